@@ -0,0 +1,324 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::PathBuf;
+
+use sha2::{Digest, Sha256};
+
+use super::chat_service::ChatError;
+use super::config::Config;
+use super::messages::Attachment;
+
+/// Content addressed attachment storage in a local directory. Each blob is written once, named
+/// by the sha256 of its content, with its metadata in a `<sha256>.json` file alongside it.
+pub struct BlobStore {
+    dir: PathBuf,
+    max_size: u64,
+    quota: u64,
+    attachments: HashMap<String, Attachment>,
+    /// Users who have uploaded each blob - each of them is charged for it once
+    uploaders: HashMap<String, BTreeSet<u64>>,
+    usage: HashMap<u64, u64>,
+}
+
+impl Default for BlobStore {
+    fn default() -> Self {
+        BlobStore::new(&Config::default())
+    }
+}
+
+impl BlobStore {
+    pub fn new(config: &Config) -> Self {
+        BlobStore {
+            dir: config.blob_dir.clone(),
+            max_size: config.max_attachment_size,
+            quota: config.attachment_quota,
+            attachments: HashMap::new(),
+            uploaders: HashMap::new(),
+            usage: HashMap::new(),
+        }
+    }
+
+    /// Stores an upload, or finds the existing copy if the same content was uploaded before
+    pub fn put(
+        &mut self,
+        user_id: u64,
+        mime_type: &str,
+        data: &[u8],
+    ) -> Result<Attachment, ChatError> {
+        let size = data.len() as u64;
+        if size > self.max_size {
+            return Err(ChatError::AttachmentTooLarge {
+                size,
+                max: self.max_size,
+            });
+        }
+        let sha256 = format!("{:x}", Sha256::digest(data));
+        let charged = self
+            .uploaders
+            .get(&sha256)
+            .is_some_and(|users| users.contains(&user_id));
+        if !charged && self.usage(user_id) + size > self.quota {
+            return Err(ChatError::AttachmentQuotaExceeded {
+                user_id,
+                quota: self.quota,
+            });
+        }
+
+        let attachment = match self.get(&sha256) {
+            Some(attachment) => attachment,
+            None => {
+                let attachment = Attachment {
+                    sha256: sha256.clone(),
+                    mime_type: mime_type.to_owned(),
+                    size,
+                };
+                self.write(&attachment, data)
+                    .map_err(|e| ChatError::Storage(format!("{}", e)))?;
+                self.attachments.insert(sha256.clone(), attachment.clone());
+                attachment
+            }
+        };
+        if !charged {
+            self.uploaders.entry(sha256).or_default().insert(user_id);
+            *self.usage.entry(user_id).or_insert(0) += size;
+        }
+        Ok(attachment)
+    }
+
+    /// Looks up an attachment's metadata, reading it from disk if it was stored by an earlier run
+    pub fn get(&mut self, sha256: &str) -> Option<Attachment> {
+        let attachment = self.find(sha256)?;
+        self.attachments
+            .entry(sha256.to_owned())
+            .or_insert_with(|| attachment.clone());
+        Some(attachment)
+    }
+
+    /// Like `get`, but metadata read from disk isn't kept for next time
+    pub fn find(&self, sha256: &str) -> Option<Attachment> {
+        if !is_sha256(sha256) {
+            return None;
+        }
+        if let Some(attachment) = self.attachments.get(sha256) {
+            return Some(attachment.clone());
+        }
+        let json = fs::read(self.dir.join(format!("{}.json", sha256))).ok()?;
+        serde_json::from_slice::<Attachment>(&json).ok()
+    }
+
+    pub fn read(&self, attachment: &Attachment) -> Result<Vec<u8>, ChatError> {
+        fs::read(self.dir.join(&attachment.sha256))
+            .map_err(|e| ChatError::Storage(format!("{}", e)))
+    }
+
+    /// Bytes of attachments the user has uploaded
+    pub fn usage(&self, user_id: u64) -> u64 {
+        self.usage.get(&user_id).cloned().unwrap_or(0)
+    }
+
+    /// Whether the user has uploaded the blob since the server started
+    pub fn is_uploader(&self, sha256: &str, user_id: u64) -> bool {
+        self.uploaders
+            .get(sha256)
+            .is_some_and(|users| users.contains(&user_id))
+    }
+
+    /// Attachments the user has uploaded since the server started
+    pub fn uploaded_by(&self, user_id: u64) -> Vec<Attachment> {
+        let mut uploaded = self
+            .uploaders
+            .iter()
+            .filter(|(_, users)| users.contains(&user_id))
+            .filter_map(|(sha256, _)| self.attachments.get(sha256).cloned())
+            .collect::<Vec<_>>();
+        uploaded.sort_by(|a, b| a.sha256.cmp(&b.sha256));
+        uploaded
+    }
+
+    /// Stops charging the user for their uploads, returning the blobs nobody else uploaded
+    pub fn forget_uploader(&mut self, user_id: u64) -> Vec<String> {
+        self.usage.remove(&user_id);
+        let mut orphaned = Vec::new();
+        for (sha256, users) in self.uploaders.iter_mut() {
+            if users.remove(&user_id) && users.is_empty() {
+                orphaned.push(sha256.clone());
+            }
+        }
+        for sha256 in &orphaned {
+            self.uploaders.remove(sha256);
+        }
+        orphaned
+    }
+
+    /// Deletes a blob and its metadata
+    pub fn remove(&mut self, sha256: &str) -> Result<(), ChatError> {
+        if !is_sha256(sha256) {
+            return Err(ChatError::AttachmentNotFound(sha256.to_owned()));
+        }
+        self.attachments.remove(sha256);
+        for path in &[
+            self.dir.join(sha256),
+            self.dir.join(format!("{}.json", sha256)),
+        ] {
+            match fs::remove_file(path) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(ChatError::Storage(format!("{}", e))),
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&self, attachment: &Attachment, data: &[u8]) -> std::io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        // write then rename, so a blob is never seen half written
+        let tmp = self.dir.join(format!(".{}.tmp", attachment.sha256));
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, self.dir.join(&attachment.sha256))?;
+        fs::write(
+            self.dir.join(format!("{}.json", attachment.sha256)),
+            serde_json::to_vec(attachment)?,
+        )
+    }
+}
+
+/// Also keeps ids from being used to reach outside the blob directory
+fn is_sha256(id: &str) -> bool {
+    id.len() == 64
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+/// The part of a body a `Range` header asks for
+#[derive(Debug, PartialEq)]
+pub enum ByteRange {
+    Full,
+    /// First and last byte, inclusive
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+/// Resolves a `Range` header against a body of `len` bytes. Only a single `bytes=` range is
+/// supported - anything else is ignored and the whole body is sent, as RFC 7233 allows.
+pub fn parse_range(header: &str, len: u64) -> ByteRange {
+    let spec = match header.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return ByteRange::Full,
+    };
+    let (start, end) = match spec.find('-') {
+        Some(dash) => (&spec[..dash], &spec[dash + 1..]),
+        None => return ByteRange::Full,
+    };
+    match (start.parse::<u64>(), end.parse::<u64>()) {
+        // the last `end` bytes
+        (Err(_), Ok(suffix)) if start.is_empty() => {
+            if suffix == 0 || len == 0 {
+                ByteRange::Unsatisfiable
+            } else {
+                ByteRange::Partial(len - suffix.min(len), len - 1)
+            }
+        }
+        (Ok(start), Err(_)) if end.is_empty() => {
+            if start < len {
+                ByteRange::Partial(start, len - 1)
+            } else {
+                ByteRange::Unsatisfiable
+            }
+        }
+        (Ok(start), Ok(end)) if start <= end => {
+            if start < len {
+                ByteRange::Partial(start, end.min(len - 1))
+            } else {
+                ByteRange::Unsatisfiable
+            }
+        }
+        _ => ByteRange::Full,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn store(name: &str, quota: u64) -> BlobStore {
+        let config = Config {
+            blob_dir: std::env::temp_dir().join(format!(
+                "chat-mio-{}-{}",
+                name,
+                std::process::id()
+            )),
+            max_attachment_size: 8,
+            attachment_quota: quota,
+            ..Config::default()
+        };
+        let _ = fs::remove_dir_all(&config.blob_dir);
+        BlobStore::new(&config)
+    }
+
+    #[test]
+    fn test_put_and_read() {
+        let mut store = store("put", 100);
+        let attachment = store.put(1, "image/png", b"\x89PNG\x00\xff").unwrap();
+        assert_eq!(attachment.size, 6);
+        assert_eq!(attachment.sha256.len(), 64);
+        assert_eq!(store.read(&attachment).unwrap(), b"\x89PNG\x00\xff");
+
+        // metadata survives a restart
+        let mut reopened = BlobStore::new(&Config {
+            blob_dir: store.dir.clone(),
+            ..Config::default()
+        });
+        assert_eq!(reopened.get(&attachment.sha256), Some(attachment));
+        assert_eq!(reopened.get("../../etc/passwd"), None);
+
+        assert_eq!(
+            store.put(1, "text/plain", b"123456789"),
+            Err(ChatError::AttachmentTooLarge { size: 9, max: 8 })
+        );
+        fs::remove_dir_all(&store.dir).unwrap();
+    }
+
+    #[test]
+    fn test_quota() {
+        let mut store = store("quota", 10);
+        store.put(1, "text/plain", b"12345678").unwrap();
+        // the same content again is free
+        store.put(1, "text/plain", b"12345678").unwrap();
+        assert_eq!(store.usage(1), 8);
+        assert_eq!(
+            store.put(1, "text/plain", b"abc"),
+            Err(ChatError::AttachmentQuotaExceeded {
+                user_id: 1,
+                quota: 10
+            })
+        );
+        // other users are charged for their own uploads
+        store.put(2, "text/plain", b"12345678").unwrap();
+        assert_eq!(store.usage(2), 8);
+
+        // a blob is only orphaned once everyone who uploaded it is forgotten
+        let shared = store.uploaded_by(2);
+        assert_eq!(shared.len(), 1);
+        assert!(store.forget_uploader(1).is_empty());
+        assert_eq!(store.forget_uploader(2), vec![shared[0].sha256.clone()]);
+        assert_eq!(store.usage(2), 0);
+        store.remove(&shared[0].sha256).unwrap();
+        assert_eq!(store.get(&shared[0].sha256), None);
+        fs::remove_dir_all(&store.dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-3", 10), ByteRange::Partial(0, 3));
+        assert_eq!(parse_range("bytes=5-", 10), ByteRange::Partial(5, 9));
+        assert_eq!(parse_range("bytes=-4", 10), ByteRange::Partial(6, 9));
+        assert_eq!(parse_range("bytes=-40", 10), ByteRange::Partial(0, 9));
+        assert_eq!(parse_range("bytes=8-40", 10), ByteRange::Partial(8, 9));
+        assert_eq!(parse_range("bytes=10-", 10), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-1,4-5", 10), ByteRange::Full);
+        assert_eq!(parse_range("lines=1-2", 10), ByteRange::Full);
+        assert_eq!(parse_range("bytes=3-1", 10), ByteRange::Full);
+    }
+}
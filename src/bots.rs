@@ -0,0 +1,219 @@
+use super::messages::Message;
+
+/// A slash command sent in a chat, such as `/echo hello`
+pub struct Command<'a> {
+    pub chat_id: u64,
+    /// The command's name, without the slash
+    pub name: &'a str,
+    /// Everything after the name, trimmed
+    pub args: &'a str,
+    pub message: &'a Message,
+    /// Every registered command with its description, for bots that list them
+    pub commands: &'a [(&'a str, &'a str)],
+}
+
+impl<'a> Command<'a> {
+    /// Reads a command from the start of a message - a slash, then a name made of letters,
+    /// digits, `-` and `_`, then the end of the text or whitespace
+    fn parse(text: &'a str) -> Option<(&'a str, &'a str)> {
+        let rest = text.strip_prefix('/')?;
+        let end = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '-' || c == '_'))
+            .unwrap_or(rest.len());
+        let (name, args) = rest.split_at(end);
+        if name.is_empty() || args.starts_with(|c: char| !c.is_whitespace()) {
+            return None;
+        }
+        Some((name, args.trim()))
+    }
+}
+
+/// A plugin that answers slash commands, and can watch every message sent. Replies are posted
+/// into the same chat as the message that prompted them.
+pub trait Bot {
+    /// Name of the command the bot answers, without the slash
+    fn command(&self) -> &str;
+
+    /// One line about what the command does, shown by `/help`
+    fn description(&self) -> &str;
+
+    /// Answers the bot's command, or stays quiet by returning None
+    fn handle(&self, command: &Command<'_>) -> Option<String>;
+
+    /// Sees every user message sent, commands included
+    fn observe(&self, _chat_id: u64, _message: &Message) -> Option<String> {
+        None
+    }
+}
+
+/// Repeats the text after the command
+pub struct EchoBot;
+
+impl Bot for EchoBot {
+    fn command(&self) -> &str {
+        "echo"
+    }
+
+    fn description(&self) -> &str {
+        "Repeats the text after the command"
+    }
+
+    fn handle(&self, command: &Command<'_>) -> Option<String> {
+        if command.args.is_empty() {
+            Some("Usage: /echo <text>".to_owned())
+        } else {
+            Some(command.args.to_owned())
+        }
+    }
+}
+
+/// Lists the commands bots answer
+pub struct HelpBot;
+
+impl Bot for HelpBot {
+    fn command(&self) -> &str {
+        "help"
+    }
+
+    fn description(&self) -> &str {
+        "Lists the commands bots answer"
+    }
+
+    fn handle(&self, command: &Command<'_>) -> Option<String> {
+        let lines = command
+            .commands
+            .iter()
+            .map(|(name, description)| format!("/{} - {}", name, description))
+            .collect::<Vec<_>>();
+        Some(lines.join("\n"))
+    }
+}
+
+/// The registered bots, which start out as the built-in `/echo` and `/help`
+pub struct Bots {
+    bots: Vec<Box<dyn Bot>>,
+}
+
+impl Default for Bots {
+    fn default() -> Self {
+        Bots {
+            bots: vec![Box::new(EchoBot), Box::new(HelpBot)],
+        }
+    }
+}
+
+impl Bots {
+    /// Returns false if another bot already answers the same command
+    pub fn register(&mut self, bot: Box<dyn Bot>) -> bool {
+        if self.bots.iter().any(|b| b.command() == bot.command()) {
+            return false;
+        }
+        self.bots.push(bot);
+        true
+    }
+
+    /// Replies to a message sent in a chat, each with the command of the bot that gave it - the
+    /// answer to the command the message starts with, if a bot answers it, then what observing
+    /// bots say
+    pub fn respond(&self, chat_id: u64, message: &Message) -> Vec<(String, String)> {
+        let mut replies = Vec::new();
+        if let Some((name, args)) = Command::parse(&message.message) {
+            let commands = self
+                .bots
+                .iter()
+                .map(|bot| (bot.command(), bot.description()))
+                .collect::<Vec<_>>();
+            let command = Command {
+                chat_id,
+                name,
+                args,
+                message,
+                commands: &commands,
+            };
+            // commands no bot answers are left alone, as they may just be text
+            let bot = self.bots.iter().find(|bot| bot.command() == name);
+            if let Some(reply) = bot.and_then(|bot| bot.handle(&command)) {
+                replies.push((name.to_owned(), reply));
+            }
+        }
+        for bot in &self.bots {
+            if let Some(reply) = bot.observe(chat_id, message) {
+                replies.push((bot.command().to_owned(), reply));
+            }
+        }
+        replies
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn message(text: &str) -> Message {
+        Message {
+            message: text.to_owned(),
+            ..Message::default()
+        }
+    }
+
+    /// Counts the messages it sees, and answers `/count`
+    struct CountBot(std::cell::Cell<usize>);
+
+    impl Bot for CountBot {
+        fn command(&self) -> &str {
+            "count"
+        }
+
+        fn description(&self) -> &str {
+            "Says how many messages it has seen"
+        }
+
+        fn handle(&self, _: &Command<'_>) -> Option<String> {
+            Some(self.0.get().to_string())
+        }
+
+        fn observe(&self, _: u64, _: &Message) -> Option<String> {
+            self.0.set(self.0.get() + 1);
+            None
+        }
+    }
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(
+            Command::parse("/echo  hi there "),
+            Some(("echo", "hi there"))
+        );
+        assert_eq!(Command::parse("/help"), Some(("help", "")));
+        assert_eq!(Command::parse("/"), None);
+        assert_eq!(Command::parse("/usr/bin"), None);
+        assert_eq!(Command::parse("say /echo"), None);
+    }
+
+    #[test]
+    fn test_respond() {
+        let mut bots = Bots::default();
+        let reply = |bots: &Bots, text| bots.respond(1, &message(text));
+        assert_eq!(
+            reply(&bots, "/echo hello"),
+            vec![("echo".to_owned(), "hello".to_owned())]
+        );
+        assert!(reply(&bots, "just chatting").is_empty());
+        assert!(reply(&bots, "/nope").is_empty());
+
+        assert!(bots.register(Box::new(CountBot(Default::default()))));
+        assert!(!bots.register(Box::new(EchoBot)));
+        reply(&bots, "one");
+        assert_eq!(reply(&bots, "/count")[0].1, "1");
+        let help = reply(&bots, "/help").remove(0).1;
+        assert_eq!(
+            help.lines().collect::<Vec<_>>(),
+            vec![
+                "/echo - Repeats the text after the command",
+                "/help - Lists the commands bots answer",
+                "/count - Says how many messages it has seen",
+            ]
+        );
+    }
+}
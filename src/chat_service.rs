@@ -1,13 +1,42 @@
-use std::collections::BinaryHeap;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::error::Error;
-use std::fs::File;
-use std::io::BufReader;
+use std::fmt;
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use super::messages::{Chat, Message};
+use serde::{Deserialize, Serialize};
 
-use lazy_static::lazy_static;
+use super::attachments::BlobStore;
+use super::bots::{Bot, Bots};
+use super::config::Config;
+use super::contacts::Contacts;
+use super::mentions;
+use super::messages::{
+    self, Attachment, AuditAction, AuditRecord, Chat, ChatEvent, ChatEventKind, ChatExport,
+    ChatSettings, ChatSettingsUpdate, ChatSummary, ChatUpdate, DeliveryState, Draft, MentionResult,
+    Message, MessageKind, MessageRevision, MessageSentData, Pin, PinnedMessage, ScheduledMessage,
+    SearchResult, UserExport, UserPresence, BOT_USER_ID, ERASED_USER_ID, SERVER_ID_PREFIX,
+};
+use super::moderation::{Filters, Flag, FlagReview, MessageFilter, ReviewAction};
+use super::presence::Presence;
+use super::quotas::{Quota, Usage, UsageReport};
+use super::retention::RetentionPolicy;
+use super::search::{self, Query, SearchIndex};
+use super::store::ChatStore;
+use super::timer_wheel::TimerWheel;
+use super::webhooks::{DeadLetter, Subscription, SubscriptionRequest, Webhooks};
+
+/// How long typing and presence events stay in a chat's event stream, in milliseconds
+const EPHEMERAL_EVENT_TTL: u64 = 60_000;
+/// Most distinct reactions a single message can collect
+pub const MAX_DISTINCT_REACTIONS: usize = 20;
+/// Most messages that can be pinned in a chat at once
+pub const MAX_PINNED_MESSAGES: usize = 10;
+/// Longest chat title accepted, in bytes
+const MAX_TITLE_LEN: usize = 128;
+/// Longest chat description accepted, in bytes
+const MAX_DESCRIPTION_LEN: usize = 1024;
+/// Longest reaction accepted, in bytes - enough for emoji built from several code points
+const MAX_REACTION_LEN: usize = 32;
 
 pub(crate) fn timestamp() -> u64 {
     SystemTime::now()
@@ -16,156 +45,3240 @@ pub(crate) fn timestamp() -> u64 {
         .as_millis() as u64
 }
 
-lazy_static! {
-    static ref USERS: HashMap<u64, Vec<u64>> = {
-        let file = File::open("contacts.json").expect("unable to open contacts.json");
-        let reader = BufReader::new(file);
-        let m: HashMap<_, _> = serde_json::from_reader(reader).unwrap();
-        m
-    };
+/// Where a chat is filed: under its participants, which keeps to one chat per pair, or under
+/// its own id once a participant has been erased
+fn chat_key(chat: &Chat) -> (u64, u64) {
+    match chat.participant_ids {
+        [a, b] if a != ERASED_USER_ID && b != ERASED_USER_ID => (a, b),
+        _ => (ERASED_USER_ID, chat.id),
+    }
+}
+
+/// Fails unless a client may give a message this id
+fn check_client_id(message_id: &str) -> Result<(), ChatError> {
+    if message_id.is_empty() || message_id.starts_with(SERVER_ID_PREFIX) {
+        return Err(ChatError::InvalidMessageId(message_id.to_owned()));
+    }
+    Ok(())
+}
+
+/// Errors returned by chat operations that callers may want to tell apart
+#[derive(Debug, PartialEq, Eq)]
+pub enum ChatError {
+    ChatNotFound(u64),
+    /// A chat with the same id or participants already exists
+    ChatExists(u64),
+    /// The user has no contact list
+    UserNotFound(u64),
+    /// Chats can only be created between users who have each other as contacts
+    NotContacts {
+        user_id: u64,
+        contact_id: u64,
+    },
+    /// The user has been blocked by the other participant, so can't start a chat with them or
+    /// change anything in the chat they already share
+    Blocked {
+        user_id: u64,
+        blocked_by: u64,
+    },
+    CannotBlockSelf(u64),
+    MessageNotFound(String),
+    /// Messages need an id to be found by, and ids starting with `SERVER_ID_PREFIX` are kept
+    /// for messages the server writes
+    InvalidMessageId(String),
+    /// Another message in the chat already has this id
+    MessageExists(String),
+    /// Only the original sender may change a message
+    NotSender {
+        user_id: u64,
+        message_id: String,
+    },
+    MessageDeleted(String),
+    NotParticipant {
+        user_id: u64,
+        chat_id: u64,
+    },
+    /// A reply names a parent message that isn't in the same chat
+    ParentNotFound(String),
+    InvalidReaction(String),
+    AttachmentNotFound(String),
+    AttachmentTooLarge {
+        size: u64,
+        max: u64,
+    },
+    AttachmentQuotaExceeded {
+        user_id: u64,
+        quota: u64,
+    },
+    /// Reading or writing attachment blobs failed
+    Storage(String),
+    /// Retention limits must be greater than zero
+    InvalidRetention(RetentionPolicy),
+    /// The message already has `MAX_DISTINCT_REACTIONS` different reactions
+    TooManyReactions(String),
+    /// A title or description is too long, or an avatar isn't an image
+    InvalidChatMetadata(String),
+    /// The chat already has `MAX_PINNED_MESSAGES` pinned messages
+    TooManyPins(u64),
+    /// The sender already has a scheduled message with this id
+    ScheduledMessageExists(String),
+    /// Only participants of a chat can be mentioned in it
+    InvalidMention {
+        user_id: u64,
+        chat_id: u64,
+    },
+    /// A webhook subscription has a bad url, event or secret
+    InvalidWebhook(String),
+    /// A message filter refused the message, saying why
+    MessageRejected(String),
+    FlagNotFound(u64),
+    QuotaExceeded {
+        user_id: u64,
+        quota: Quota,
+    },
+}
+
+impl fmt::Display for ChatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChatError::ChatNotFound(chat_id) => {
+                write!(f, "Unable to find chat with id {}", chat_id)
+            }
+            ChatError::ChatExists(chat_id) => write!(f, "chat {} already exists", chat_id),
+            ChatError::UserNotFound(user_id) => write!(f, "Unable to find user {}", user_id),
+            ChatError::NotContacts {
+                user_id,
+                contact_id,
+            } => write!(
+                f,
+                "user {} does not have user {} in their contact list.",
+                user_id, contact_id
+            ),
+            ChatError::Blocked {
+                user_id,
+                blocked_by,
+            } => write!(
+                f,
+                "user {} has been blocked by user {}",
+                user_id, blocked_by
+            ),
+            ChatError::CannotBlockSelf(user_id) => {
+                write!(f, "user {} cannot block themselves", user_id)
+            }
+            ChatError::MessageNotFound(message_id) => {
+                write!(f, "Unable to find message with id {}", message_id)
+            }
+            ChatError::InvalidMessageId(message_id) => {
+                write!(f, "invalid message id {:?}", message_id)
+            }
+            ChatError::MessageExists(message_id) => {
+                write!(f, "message {} already exists", message_id)
+            }
+            ChatError::NotSender {
+                user_id,
+                message_id,
+            } => write!(
+                f,
+                "user {} is not the sender of message {}",
+                user_id, message_id
+            ),
+            ChatError::MessageDeleted(message_id) => {
+                write!(f, "message {} has been deleted", message_id)
+            }
+            ChatError::ParentNotFound(message_id) => write!(
+                f,
+                "unable to reply to message {}, it is not in this chat",
+                message_id
+            ),
+            ChatError::InvalidReaction(emoji) => write!(f, "invalid reaction {:?}", emoji),
+            ChatError::TooManyReactions(message_id) => write!(
+                f,
+                "message {} already has {} different reactions",
+                message_id, MAX_DISTINCT_REACTIONS
+            ),
+            ChatError::InvalidChatMetadata(reason) => {
+                write!(f, "invalid chat metadata: {}", reason)
+            }
+            ChatError::TooManyPins(chat_id) => write!(
+                f,
+                "chat {} already has {} pinned messages",
+                chat_id, MAX_PINNED_MESSAGES
+            ),
+            ChatError::InvalidMention { user_id, chat_id } => write!(
+                f,
+                "user {} can't be mentioned as they aren't in chat {}",
+                user_id, chat_id
+            ),
+            ChatError::InvalidWebhook(reason) => write!(f, "invalid webhook: {}", reason),
+            ChatError::MessageRejected(reason) => write!(f, "message rejected: {}", reason),
+            ChatError::FlagNotFound(flag_id) => write!(f, "Unable to find flag {}", flag_id),
+            ChatError::QuotaExceeded { user_id, quota } => {
+                write!(f, "user {} has used their {}", user_id, quota)
+            }
+            ChatError::ScheduledMessageExists(message_id) => {
+                write!(f, "message {} is already scheduled", message_id)
+            }
+            ChatError::AttachmentNotFound(sha256) => {
+                write!(f, "Unable to find attachment {}", sha256)
+            }
+            ChatError::AttachmentTooLarge { size, max } => write!(
+                f,
+                "attachment of {} bytes is larger than the {} byte limit",
+                size, max
+            ),
+            ChatError::AttachmentQuotaExceeded { user_id, quota } => write!(
+                f,
+                "user {} has used their {} byte attachment quota",
+                user_id, quota
+            ),
+            ChatError::Storage(e) => write!(f, "storage error: {}", e),
+            ChatError::InvalidRetention(policy) => {
+                write!(f, "invalid retention policy {:?}", policy)
+            }
+            ChatError::NotParticipant { user_id, chat_id } => {
+                write!(
+                    f,
+                    "user {} is not a participant in chat {}",
+                    user_id, chat_id
+                )
+            }
+        }
+    }
 }
 
+impl Error for ChatError {}
+
+/// A chat and everything in it. This is what the data directory keeps of each chat.
+#[derive(Serialize, Deserialize)]
 pub struct ChatRoom {
     chat: Chat,
-    log: BinaryHeap<Message>,
+    /// Messages ordered by timestamp
+    #[serde(deserialize_with = "messages::deserialize_stored_log")]
+    log: Vec<Message>,
+    events: Vec<ChatEvent>,
+    #[serde(rename = "nextSeq")]
+    next_seq: u64,
+    /// Timestamp given to the latest message, which the next one has to come after
+    #[serde(rename = "lastTimestamp")]
+    last_timestamp: u64,
+    /// Timestamp of the latest message each participant has read
+    #[serde(rename = "readCursors")]
+    read_cursors: HashMap<u64, u64>,
+    retention: RetentionPolicy,
+    /// Pinned messages, oldest pin first
+    pins: Vec<Pin>,
+    /// Each participant's unsent text
+    drafts: HashMap<u64, Draft>,
 }
 
 impl ChatRoom {
     pub fn new(chat: Chat) -> Self {
         ChatRoom {
             chat,
-            log: BinaryHeap::new(),
+            log: Vec::new(),
+            events: Vec::new(),
+            next_seq: 1,
+            last_timestamp: 0,
+            read_cursors: HashMap::new(),
+            retention: RetentionPolicy::default(),
+            pins: Vec::new(),
+            drafts: HashMap::new(),
+        }
+    }
+
+    fn check_participant(&self, user_id: u64) -> Result<(), ChatError> {
+        if self.chat.participant_ids.contains(&user_id) {
+            Ok(())
+        } else {
+            Err(ChatError::NotParticipant {
+                user_id,
+                chat_id: self.chat.id,
+            })
+        }
+    }
+
+    /// Users mentioned in the text, who all have to be participants
+    fn mentions(&self, text: &str) -> Result<Vec<u64>, ChatError> {
+        let mentions = mentions::parse(text);
+        match mentions
+            .iter()
+            .find(|id| !self.chat.participant_ids.contains(id))
+        {
+            Some(user_id) => Err(ChatError::InvalidMention {
+                user_id: *user_id,
+                chat_id: self.chat.id,
+            }),
+            None => Ok(mentions),
+        }
+    }
+
+    /// Fails if the log already has a message with this id
+    fn check_unused_id(&self, message_id: &str) -> Result<(), ChatError> {
+        if self.log.iter().any(|m| m.id == message_id) {
+            return Err(ChatError::MessageExists(message_id.to_owned()));
+        }
+        Ok(())
+    }
+
+    /// Messages from other participants that the user hasn't read yet
+    fn unread_count(&self, user_id: u64) -> usize {
+        let read_up_to = self.read_cursors.get(&user_id).cloned().unwrap_or(0);
+        self.log
+            .iter()
+            .filter(|m| m.source_user_id != user_id && !m.is_deleted() && !m.is_system())
+            .filter(|m| m.timestamp > read_up_to)
+            .count()
+    }
+
+    /// Stamps a message with the server's clock, a millisecond after the last one if the clock
+    /// hasn't moved on since. The log's order and read cursors never depend on a client's
+    /// clock, and no two messages share a timestamp.
+    fn next_timestamp(&mut self) -> u64 {
+        self.last_timestamp = timestamp().max(self.last_timestamp + 1);
+        self.last_timestamp
+    }
+
+    /// Inserts a message after any others with the same or an earlier timestamp
+    fn insert_message(&mut self, message: Message) {
+        let pos = self
+            .log
+            .iter()
+            .rposition(|m| m.timestamp <= message.timestamp)
+            .map_or(0, |i| i + 1);
+        self.log.insert(pos, message);
+    }
+
+    fn push_event(&mut self, kind: ChatEventKind) {
+        let now = timestamp();
+        self.events
+            .retain(|e| !e.kind.is_ephemeral() || now - e.timestamp < EPHEMERAL_EVENT_TTL);
+        self.events.push(ChatEvent {
+            seq: self.next_seq,
+            timestamp: now,
+            kind,
+        });
+        self.next_seq += 1;
+    }
+
+    /// Removes messages, along with every earlier event about them, returning the messages
+    fn purge(&mut self, message_ids: &HashSet<String>) -> Vec<Message> {
+        let (removed, kept) = std::mem::take(&mut self.log)
+            .into_iter()
+            .partition::<Vec<_>, _>(|m| message_ids.contains(&m.id));
+        self.log = kept;
+        // deleted replies were taken off their parent's count already
+        for message in removed.iter().filter(|m| !m.is_deleted()) {
+            if let Some(parent_id) = &message.reply_to {
+                self.uncount_reply(parent_id);
+            }
+        }
+        self.events.retain(|e| match e.kind.message_id() {
+            Some(message_id) => !message_ids.contains(message_id),
+            None => true,
+        });
+        self.pins
+            .retain(|pin| !message_ids.contains(&pin.message_id));
+        removed
+    }
+
+    /// Takes a reply that has gone off its parent's reply count
+    fn uncount_reply(&mut self, parent_id: &str) {
+        if let Ok(parent) = self.message_mut(parent_id) {
+            parent.reply_count = parent.reply_count.saturating_sub(1);
+        }
+    }
+
+    /// Writes a message from the server to the log, on behalf of the user whose action it
+    /// describes
+    fn push_system_message(&mut self, user_id: u64, kind: MessageKind) {
+        let message = Message {
+            id: format!("{}system-{}", SERVER_ID_PREFIX, self.next_seq),
+            timestamp: self.next_timestamp(),
+            source_user_id: user_id,
+            destination_user_id: self
+                .chat
+                .participant_ids
+                .iter()
+                .cloned()
+                .find(|id| *id != user_id)
+                .unwrap_or(user_id),
+            message: kind.describe(),
+            kind,
+            ..Message::default()
+        };
+        self.insert_message(message.clone());
+        self.push_event(ChatEventKind::MessageSent { message });
+    }
+
+    /// Posts a bot's reply to a message, threaded under it when the message has an id
+    fn push_bot_reply(&mut self, trigger: &Message, bot: String, text: String) {
+        let reply_to = Some(trigger.id.clone()).filter(|id| !id.is_empty());
+        if let Some(parent_id) = &reply_to {
+            if let Some(parent) = self.log.iter_mut().find(|m| m.id == *parent_id) {
+                parent.reply_count += 1;
+            }
+        }
+        let message = Message {
+            id: format!("{}bot-{}", SERVER_ID_PREFIX, self.next_seq),
+            source_user_id: BOT_USER_ID,
+            destination_user_id: trigger.source_user_id,
+            timestamp: self.next_timestamp(),
+            message: text,
+            reply_to,
+            kind: MessageKind::BotReply { bot },
+            ..Message::default()
+        };
+        self.insert_message(message.clone());
+        self.push_event(ChatEventKind::MessageSent { message });
+    }
+
+    /// Unpins a message, telling participants if it was pinned
+    fn unpin(&mut self, message_id: &str, user_id: u64) -> bool {
+        let before = self.pins.len();
+        self.pins.retain(|pin| pin.message_id != message_id);
+        let unpinned = self.pins.len() < before;
+        if unpinned {
+            self.push_event(ChatEventKind::MessageUnpinned {
+                message_id: message_id.to_owned(),
+                user_id,
+            });
+        }
+        unpinned
+    }
+
+    /// Empties out a message, leaving a tombstone in the log in its place, and tells
+    /// participants it was deleted
+    fn tombstone(&mut self, message_id: &str, user_id: u64) -> Result<Message, ChatError> {
+        let message = self.message_mut(message_id)?;
+        message.message.clear();
+        message.history.clear();
+        message.reactions.clear();
+        message.mentions.clear();
+        message.edited_at = None;
+        message.deleted_at = Some(timestamp());
+        let message = message.clone();
+        if let Some(parent_id) = &message.reply_to {
+            self.uncount_reply(parent_id);
+        }
+        self.unpin(message_id, user_id);
+        self.push_event(ChatEventKind::MessageDeleted {
+            message: message.clone(),
+        });
+        Ok(message)
+    }
+
+    fn message_mut(&mut self, message_id: &str) -> Result<&mut Message, ChatError> {
+        match self.log.iter_mut().find(|m| m.id == message_id) {
+            Some(message) => Ok(message),
+            None => Err(ChatError::MessageNotFound(message_id.to_owned())),
+        }
+    }
+
+    /// Finds a message the given user sent, which they are still allowed to change
+    fn own_message_mut(
+        &mut self,
+        message_id: &str,
+        user_id: u64,
+    ) -> Result<&mut Message, ChatError> {
+        let message = self.message_mut(message_id)?;
+        // system messages belong to the server, whoever's action they describe
+        if message.source_user_id != user_id || message.is_system() {
+            return Err(ChatError::NotSender {
+                user_id,
+                message_id: message_id.to_owned(),
+            });
         }
+        if message.is_deleted() {
+            return Err(ChatError::MessageDeleted(message_id.to_owned()));
+        }
+        Ok(message)
     }
 }
 
+/// Which of a user's chats `ChatService::get_user_chats` lists
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ChatListFilter {
+    /// List archived chats instead of the rest
+    pub archived: bool,
+    /// Only list pinned chats
+    pub pinned_only: bool,
+}
+
 #[derive(Default)]
 pub struct ChatService {
     chats: HashMap<(u64, u64), ChatRoom>,
     chat_keys: HashMap<u64, (u64, u64)>,
+    /// Each user's chats by id, with how they've organized them
+    user_chats: HashMap<u64, BTreeMap<u64, ChatSettings>>,
+    index: SearchIndex,
+    blobs: BlobStore,
+    presence: Presence,
+    /// Limits every chat is held to, whatever its own policy
+    retention: RetentionPolicy,
+    contacts: Contacts,
+    /// Exports and erasures of user data, oldest first
+    audit: Vec<AuditRecord>,
+    /// Messages waiting for their `sendAt` time, sent by `send_scheduled`
+    scheduled: TimerWheel<ScheduledMessage>,
+    next_scheduled_id: u64,
+    webhooks: Webhooks,
+    bots: Bots,
+    /// Checks every message sent or edited
+    filters: Filters,
+    /// Messages the filters flagged, waiting for an admin to review them
+    flags: Vec<Flag>,
+    next_flag_id: u64,
+    /// What each user has created, held to the configured quotas
+    usage: Usage,
+    /// Where chats are saved, if anywhere
+    store: Option<ChatStore>,
+    /// Chats changed since they were last saved
+    unsaved: BTreeSet<u64>,
 }
 
 impl ChatService {
-    /// Adds a new chat - user a and b must have each other in their contact lists
-    pub fn add_chat(&mut self, chat: Chat) -> Result<(), Box<dyn Error>> {
+    /// Fails if the blocked words file or the saved chats can't be read
+    pub fn new(config: &Config) -> std::io::Result<Self> {
+        let mut service = ChatService {
+            blobs: BlobStore::new(config),
+            retention: config.retention,
+            filters: Filters::from_config(config)?,
+            usage: Usage::new(config.quotas),
+            store: config.data_dir.clone().map(ChatStore::new),
+            ..ChatService::default()
+        };
+        let saved = match &service.store {
+            Some(store) => store.load::<ChatRoom>()?,
+            None => Vec::new(),
+        };
+        for chat in saved {
+            service.restore(chat);
+        }
+        Ok(service)
+    }
+
+    /// Puts back a chat saved by an earlier run
+    fn restore(&mut self, chat: ChatRoom) {
+        let chat_id = chat.chat.id;
+        for message in &chat.log {
+            if message.kind.is_text() && !message.is_deleted() {
+                self.index
+                    .index((chat_id, message.id.clone()), &message.message);
+            }
+            self.usage
+                .add_bytes(message.source_user_id, message.stored_bytes());
+        }
+        for user_id in chat.chat.participant_ids {
+            if user_id != ERASED_USER_ID {
+                self.user_chats
+                    .entry(user_id)
+                    .or_default()
+                    .insert(chat_id, ChatSettings::default());
+            }
+        }
+        if chat.chat.created_by != ERASED_USER_ID {
+            self.usage.add_chat(chat.chat.created_by);
+        }
+        let key = chat_key(&chat.chat);
+        self.chat_keys.insert(chat_id, key);
+        self.chats.insert(key, chat);
+    }
+
+    /// Writes the chats changed since the last save to the data directory, if there is one.
+    /// Chats that fail to save are tried again next time.
+    pub fn save(&mut self) {
+        let unsaved = std::mem::take(&mut self.unsaved);
+        let store = match &self.store {
+            Some(store) => store,
+            None => return,
+        };
+        for chat_id in unsaved {
+            let chat = match self.chat_room(chat_id) {
+                Ok(chat) => chat,
+                Err(_) => continue,
+            };
+            if let Err(e) = store.save(chat_id, chat) {
+                eprintln!("unable to save chat {}: {}", chat_id, e);
+                self.unsaved.insert(chat_id);
+            }
+        }
+    }
+
+    /// Adds a new chat - user a and b must have each other in their contact lists, and neither
+    /// may have blocked the other
+    pub fn add_chat(&mut self, mut chat: Chat) -> Result<(), ChatError> {
         let user_a = chat.participant_ids[0];
         let user_b = chat.participant_ids[1];
 
-        if self.chats.contains_key(&(user_a, user_b)) {
-            return Err("Chat already exists".into());
+        if self.chats.contains_key(&(user_a, user_b)) || self.chat_keys.contains_key(&chat.id) {
+            return Err(ChatError::ChatExists(chat.id));
         }
-        let a = USERS.get(&user_a);
-        let b = USERS.get(&user_b);
-        if let (Some(a), Some(b)) = (a, b) {
-            if a.contains(&user_b) && b.contains(&user_a) {
-                self.chat_keys.insert(chat.id, (user_a, user_b));
-                let chatroom = ChatRoom::new(chat);
-                self.chats.insert((user_a, user_b), chatroom);
-                return Ok(());
-            } else {
-                if !a.contains(&user_b) {
-                    return Err(format!(
-                        "user {} does not have user {} in their contact list.",
-                        user_a, user_b
-                    )
-                    .into());
-                }
-                if !b.contains(&user_a) {
-                    return Err(format!(
-                        "user {} does not have user {} in their contact list.",
-                        user_b, user_a
-                    )
-                    .into());
-                }
+        let a = match self.contacts.get(user_a) {
+            Some(a) => a,
+            None => return Err(ChatError::UserNotFound(user_a)),
+        };
+        let b = match self.contacts.get(user_b) {
+            Some(b) => b,
+            None => return Err(ChatError::UserNotFound(user_b)),
+        };
+        if !a.contains(&user_b) {
+            return Err(ChatError::NotContacts {
+                user_id: user_a,
+                contact_id: user_b,
+            });
+        }
+        if !b.contains(&user_a) {
+            return Err(ChatError::NotContacts {
+                user_id: user_b,
+                contact_id: user_a,
+            });
+        }
+        for (user_id, other_id) in [(user_a, user_b), (user_b, user_a)] {
+            if self.contacts.is_blocked(other_id, user_id) {
+                return Err(ChatError::Blocked {
+                    user_id,
+                    blocked_by: other_id,
+                });
             }
         }
-        Err("unable to create chat".into())
+        if chat.created_by == 0 {
+            chat.created_by = user_a;
+        } else if !chat.participant_ids.contains(&chat.created_by) {
+            return Err(ChatError::NotParticipant {
+                user_id: chat.created_by,
+                chat_id: chat.id,
+            });
+        }
+        self.validate_metadata(&chat, chat.created_by)?;
+        self.usage
+            .check_chat(chat.created_by)
+            .map_err(|quota| ChatError::QuotaExceeded {
+                user_id: chat.created_by,
+                quota,
+            })?;
+        chat.created_at = timestamp();
+        self.chat_keys.insert(chat.id, (user_a, user_b));
+        for user_id in [user_a, user_b] {
+            self.user_chats
+                .entry(user_id)
+                .or_default()
+                .insert(chat.id, ChatSettings::default());
+        }
+        let created = MessageKind::ChatCreated {
+            title: chat.title.clone(),
+        };
+        let created_by = chat.created_by;
+        self.webhooks
+            .publish("chatCreated", &chat, &chat.participant_ids, timestamp());
+        let mut chatroom = ChatRoom::new(chat);
+        chatroom.push_system_message(created_by, created);
+        self.unsaved.insert(chatroom.chat.id);
+        self.chats.insert((user_a, user_b), chatroom);
+        self.usage.add_chat(created_by);
+        Ok(())
+    }
+
+    pub fn send_message(&mut self, chat_id: u64, message: Message) -> Result<(), ChatError> {
+        check_client_id(&message.id)?;
+        self.post_message(chat_id, message)
     }
 
-    pub fn send_message(&mut self, chat_id: u64, message: Message) -> Result<(), Box<dyn Error>> {
+    /// Adds a message to a chat, once it has passed the checks every message goes through
+    fn post_message(&mut self, chat_id: u64, mut message: Message) -> Result<(), ChatError> {
+        self.chat_room(chat_id)?
+            .check_participant(message.source_user_id)?;
+        self.check_not_blocked(chat_id, message.source_user_id)?;
+        for attachment in &message.attachments {
+            self.check_can_see(message.source_user_id, &attachment.sha256)?;
+        }
         let key = match self.chat_keys.get(&chat_id) {
             Some(key) => key,
-            None => return Err(format!("Unable to find chat with id {}", chat_id).into()),
+            None => return Err(ChatError::ChatNotFound(chat_id)),
         };
-        match self.chats.get_mut(&key) {
+        match self.chats.get_mut(key) {
             Some(chat) => {
                 println!(
                     "adding message to log for chat id {} users {:?}",
                     chat_id, key
                 );
-                chat.log.push(message);
+                self.unsaved.insert(chat_id);
+                chat.check_unused_id(&message.id)?;
+                let flagged = self
+                    .filters
+                    .apply(&mut message.message)
+                    .map_err(ChatError::MessageRejected)?;
+                let sender = message.source_user_id;
+                let now = timestamp();
+                let bytes = message.message.len() as u64;
+                self.usage
+                    .check_message(sender, bytes, self.blobs.usage(sender), now)
+                    .map_err(|quota| ChatError::QuotaExceeded {
+                        user_id: sender,
+                        quota,
+                    })?;
+                message.mentions = chat.mentions(&message.message)?;
+                if let Some(parent_id) = &message.reply_to {
+                    if !chat.log.iter().any(|m| m.id == *parent_id) {
+                        return Err(ChatError::ParentNotFound(parent_id.clone()));
+                    }
+                }
+                for attachment in message.attachments.iter_mut() {
+                    match self.blobs.get(&attachment.sha256) {
+                        Some(stored) => *attachment = stored,
+                        None => {
+                            return Err(ChatError::AttachmentNotFound(attachment.sha256.clone()))
+                        }
+                    }
+                }
+                self.presence
+                    .stop_typing(chat_id, message.source_user_id, timestamp());
+                chat.drafts.remove(&message.source_user_id);
+                // timing, edit, delivery and reply state is owned by the server, not the client
+                message.timestamp = chat.next_timestamp();
+                message.reply_count = 0;
+                message.edited_at = None;
+                message.history.clear();
+                message.deleted_at = None;
+                message.expires_at = message.ttl.map(|ttl| now.saturating_add(ttl));
+                message.delivery = chat
+                    .chat
+                    .participant_ids
+                    .iter()
+                    .filter(|id| **id != message.source_user_id)
+                    .map(|id| (*id, DeliveryState::Sent))
+                    .collect();
+                // a new message brings the chat back out of the recipient's archive
+                for user_id in message.delivery.keys() {
+                    let settings = self
+                        .user_chats
+                        .get_mut(user_id)
+                        .and_then(|chats| chats.get_mut(&chat_id));
+                    if let Some(settings) = settings {
+                        settings.archived = false;
+                    }
+                }
+                // nothing is changed until every check has passed
+                if let Some(parent_id) = &message.reply_to {
+                    if let Ok(parent) = chat.message_mut(parent_id) {
+                        parent.reply_count += 1;
+                    }
+                }
+                self.index
+                    .index((chat_id, message.id.clone()), &message.message);
+                chat.insert_message(message.clone());
+                self.usage.add_message(sender, bytes, now);
+                let data = MessageSentData {
+                    chat_id,
+                    message: &message,
+                };
+                self.webhooks
+                    .publish("messageSent", &data, &chat.chat.participant_ids, now);
+                let replies = self.bots.respond(chat_id, &message);
+                chat.push_event(ChatEventKind::MessageSent {
+                    message: message.clone(),
+                });
+                for (bot, reply) in replies {
+                    chat.push_bot_reply(&message, bot, reply);
+                }
+                self.flag(chat_id, &message.id, flagged);
             }
-            None => return Err(format!("Unable to find chat for {:?}", key).into()),
+            None => return Err(ChatError::ChatNotFound(chat_id)),
         }
         Ok(())
     }
 
+    /// Holds a message back until its `sendAt` time. The sender, filters, reply parent and
+    /// attachments are checked now so mistakes are reported straight away, and checked again
+    /// when it is sent. Messages without an id are given one, as the id is needed to cancel them.
+    pub fn schedule_message(
+        &mut self,
+        chat_id: u64,
+        mut message: Message,
+    ) -> Result<ScheduledMessage, ChatError> {
+        self.check_not_blocked(chat_id, message.source_user_id)?;
+        let chat = self.chat_room(chat_id)?;
+        chat.check_participant(message.source_user_id)?;
+        self.filters
+            .apply(&mut message.message.clone())
+            .map_err(ChatError::MessageRejected)?;
+        let sender = message.source_user_id;
+        let bytes = message.message.len() as u64;
+        self.usage
+            .check_message(sender, bytes, self.blobs.usage(sender), timestamp())
+            .map_err(|quota| ChatError::QuotaExceeded {
+                user_id: sender,
+                quota,
+            })?;
+        chat.mentions(&message.message)?;
+        if let Some(parent_id) = &message.reply_to {
+            if !chat.log.iter().any(|m| m.id == *parent_id) {
+                return Err(ChatError::ParentNotFound(parent_id.clone()));
+            }
+        }
+        for attachment in &message.attachments {
+            self.check_can_see(sender, &attachment.sha256)?;
+            if self.blobs.find(&attachment.sha256).is_none() {
+                return Err(ChatError::AttachmentNotFound(attachment.sha256.clone()));
+            }
+        }
+        if !message.id.is_empty() {
+            check_client_id(&message.id)?;
+            chat.check_unused_id(&message.id)?;
+        } else {
+            self.next_scheduled_id += 1;
+            message.id = format!("{}scheduled-{}", SERVER_ID_PREFIX, self.next_scheduled_id);
+        }
+        let exists = self.scheduled.iter().any(|t| {
+            t.value.message.source_user_id == message.source_user_id
+                && t.value.message.id == message.id
+        });
+        if exists {
+            return Err(ChatError::ScheduledMessageExists(message.id));
+        }
+        let send_at = *message.send_at.get_or_insert_with(timestamp);
+        let scheduled = ScheduledMessage { chat_id, message };
+        self.scheduled.insert(send_at, scheduled.clone());
+        Ok(scheduled)
+    }
+
+    /// Sends the scheduled messages that are due, returning how many were sent. A message
+    /// that can no longer be sent, say because the sender has since been blocked, is dropped,
+    /// and a `scheduledMessageFailed` event in the chat tells the sender why.
+    pub fn send_scheduled(&mut self, now: u64) -> usize {
+        let mut sent = 0;
+        for timer in self.scheduled.expire(now) {
+            let ScheduledMessage { chat_id, message } = timer.value;
+            let message_id = message.id.clone();
+            let user_id = message.source_user_id;
+            let reason = match self.post_message(chat_id, message) {
+                Ok(()) => {
+                    sent += 1;
+                    continue;
+                }
+                Err(e) => e.to_string(),
+            };
+            match self.chat_room_mut(chat_id) {
+                Ok(chat) => chat.push_event(ChatEventKind::ScheduledMessageFailed {
+                    message_id,
+                    user_id,
+                    reason,
+                }),
+                Err(_) => eprintln!(
+                    "unable to send scheduled message {} in chat {}: {}",
+                    message_id, chat_id, reason
+                ),
+            }
+        }
+        sent
+    }
+
+    /// When the next scheduled message is due
+    pub fn next_scheduled(&self) -> Option<u64> {
+        self.scheduled.next_deadline()
+    }
+
+    /// The user's messages waiting to be sent, soonest first
+    pub fn get_scheduled(&self, user_id: u64) -> Vec<ScheduledMessage> {
+        let mut scheduled = self
+            .scheduled
+            .iter()
+            .filter(|t| t.value.message.source_user_id == user_id)
+            .map(|t| t.value.clone())
+            .collect::<Vec<_>>();
+        scheduled.sort_by(|a, b| {
+            (a.message.send_at, &a.message.id).cmp(&(b.message.send_at, &b.message.id))
+        });
+        scheduled
+    }
+
+    /// Stops one of the user's scheduled messages from being sent
+    pub fn cancel_scheduled(
+        &mut self,
+        user_id: u64,
+        message_id: &str,
+    ) -> Result<ScheduledMessage, ChatError> {
+        self.scheduled
+            .remove(|s| s.message.source_user_id == user_id && s.message.id == message_id)
+            .map(|t| t.value)
+            .ok_or_else(|| ChatError::MessageNotFound(message_id.to_owned()))
+    }
+
+    /// Saves the user's draft for a chat, replacing any earlier one. It is cleared when they
+    /// next send a message there.
+    pub fn set_draft(
+        &mut self,
+        chat_id: u64,
+        user_id: u64,
+        mut draft: Draft,
+    ) -> Result<Draft, ChatError> {
+        let chat = self.chat_room_mut(chat_id)?;
+        chat.check_participant(user_id)?;
+        draft.updated_at = timestamp();
+        chat.drafts.insert(user_id, draft.clone());
+        Ok(draft)
+    }
+
+    pub fn get_draft(&self, chat_id: u64, user_id: u64) -> Result<Option<&Draft>, ChatError> {
+        let chat = self.chat_room(chat_id)?;
+        chat.check_participant(user_id)?;
+        Ok(chat.drafts.get(&user_id))
+    }
+
+    /// Returns false if the user had no draft for the chat
+    pub fn delete_draft(&mut self, chat_id: u64, user_id: u64) -> Result<bool, ChatError> {
+        let chat = self.chat_room_mut(chat_id)?;
+        chat.check_participant(user_id)?;
+        Ok(chat.drafts.remove(&user_id).is_some())
+    }
+
+    /// Messages in the user's chats that mention them, newest first
+    pub fn get_mentions(&self, user_id: u64, limit: usize) -> Vec<MentionResult> {
+        let mut mentions = self
+            .user_chats
+            .get(&user_id)
+            .into_iter()
+            .flat_map(|chats| chats.keys())
+            .filter_map(|chat_id| self.chat_room(*chat_id).ok())
+            .flat_map(|chat| {
+                chat.log
+                    .iter()
+                    .filter(|m| m.mentions.contains(&user_id) && !m.is_deleted())
+                    .map(move |m| MentionResult {
+                        chat_id: chat.chat.id,
+                        message: m.clone(),
+                    })
+            })
+            .collect::<Vec<_>>();
+        mentions.sort_by_key(|m| std::cmp::Reverse(m.message.timestamp));
+        mentions.truncate(limit);
+        mentions
+    }
+
+    /// Replaces the text of a message, keeping the previous text in its history
+    pub fn edit_message(
+        &mut self,
+        chat_id: u64,
+        message_id: &str,
+        user_id: u64,
+        mut text: String,
+    ) -> Result<Message, ChatError> {
+        self.check_not_blocked(chat_id, user_id)?;
+        let flagged = self
+            .filters
+            .apply(&mut text)
+            .map_err(ChatError::MessageRejected)?;
+        // the text it replaces is kept in the history, so still counts
+        let bytes = text.len() as u64;
+        self.usage
+            .check_bytes(user_id, bytes, self.blobs.usage(user_id))
+            .map_err(|quota| ChatError::QuotaExceeded { user_id, quota })?;
+        let chat = self.chat_room_mut(chat_id)?;
+        let mentions = chat.mentions(&text)?;
+        let message = chat.own_message_mut(message_id, user_id)?;
+        let now = timestamp();
+        message.mentions = mentions;
+        let previous = MessageRevision {
+            message: std::mem::replace(&mut message.message, text),
+            timestamp: message.edited_at.unwrap_or(message.timestamp),
+        };
+        message.history.push(previous);
+        message.edited_at = Some(now);
+        let message = message.clone();
+        chat.push_event(ChatEventKind::MessageEdited {
+            message: message.clone(),
+        });
+        self.index
+            .index((chat_id, message.id.clone()), &message.message);
+        self.flag(chat_id, message_id, flagged);
+        self.usage.add_bytes(user_id, bytes);
+        Ok(message)
+    }
+
+    /// Soft-deletes a message, leaving a tombstone in the log in its place
+    pub fn delete_message(
+        &mut self,
+        chat_id: u64,
+        message_id: &str,
+        user_id: u64,
+    ) -> Result<Message, ChatError> {
+        self.check_not_blocked(chat_id, user_id)?;
+        let chat = self.chat_room_mut(chat_id)?;
+        let released = chat.own_message_mut(message_id, user_id)?.stored_bytes();
+        let message = chat.tombstone(message_id, user_id)?;
+        chat.push_system_message(
+            user_id,
+            MessageKind::MessageDeleted {
+                message_id: message_id.to_owned(),
+            },
+        );
+        self.index.remove(&(chat_id, message.id.clone()));
+        self.usage.release_bytes(user_id, released);
+        self.flags
+            .retain(|flag| (flag.chat_id, flag.message_id.as_str()) != (chat_id, message_id));
+        Ok(message)
+    }
+
+    /// Adds a filter to the end of the chain every message goes through
+    pub fn add_filter(&mut self, filter: Box<dyn MessageFilter>) {
+        self.filters.add(filter);
+    }
+
+    /// Queues a message for review, if the filters gave any reasons to
+    fn flag(&mut self, chat_id: u64, message_id: &str, reasons: Vec<String>) {
+        if reasons.is_empty() {
+            return;
+        }
+        self.next_flag_id += 1;
+        self.flags.push(Flag {
+            id: self.next_flag_id,
+            chat_id,
+            message_id: message_id.to_owned(),
+            reasons,
+            flagged_at: timestamp(),
+        });
+    }
+
+    /// Flagged messages waiting for review, oldest first. Flags on messages that have since
+    /// been deleted or expired are left out.
+    pub fn get_flagged(&self) -> Vec<FlagReview<'_>> {
+        self.flags
+            .iter()
+            .filter_map(|flag| {
+                let chat = self.chat_room(flag.chat_id).ok()?;
+                let message = chat.log.iter().find(|m| m.id == flag.message_id)?;
+                if message.is_deleted() {
+                    return None;
+                }
+                Some(FlagReview { flag, message })
+            })
+            .collect()
+    }
+
+    /// Settles a flag, deleting the message if it's removed. The flag is dropped either way.
+    pub fn resolve_flag(&mut self, flag_id: u64, action: ReviewAction) -> Result<Flag, ChatError> {
+        let index = self
+            .flags
+            .iter()
+            .position(|flag| flag.id == flag_id)
+            .ok_or(ChatError::FlagNotFound(flag_id))?;
+        let flag = self.flags.remove(index);
+        if action == ReviewAction::Remove {
+            // a message that has already gone needs no removing
+            if let Ok(chat) = self.chat_room_mut(flag.chat_id) {
+                let sender = match chat.message_mut(&flag.message_id) {
+                    Ok(message) if !message.is_deleted() => {
+                        Some((message.source_user_id, message.stored_bytes()))
+                    }
+                    _ => None,
+                };
+                if let Some((sender, released)) = sender {
+                    chat.tombstone(&flag.message_id, sender)?;
+                    chat.push_system_message(
+                        sender,
+                        MessageKind::MessageRemoved {
+                            message_id: flag.message_id.clone(),
+                        },
+                    );
+                    self.index.remove(&(flag.chat_id, flag.message_id.clone()));
+                    self.usage.release_bytes(sender, released);
+                }
+            }
+        }
+        // other flags on the same message are settled along with it
+        let message = (flag.chat_id, &flag.message_id);
+        self.flags
+            .retain(|other| (other.chat_id, &other.message_id) != message);
+        Ok(flag)
+    }
+
     pub fn get_messages(&self, chat_id: u64) -> Result<Vec<Message>, Box<dyn Error>> {
         println!("GET MESSAGES");
         let key = match self.chat_keys.get(&chat_id) {
             Some(key) => key,
             None => return Err("Unable to find key".into()),
         };
-        let chat = match self.chats.get(&key) {
+        let chat = match self.chats.get(key) {
             Some(chat) => chat,
             None => return Err("Unable to find chatroom".into()),
         };
-        let current_log = chat.log.clone();
-        println!("chat log : {:?}", chat.log);
-        Ok(current_log.into_sorted_vec())
+        Ok(chat.log.clone())
     }
 
-    pub fn get_user_chats(&self, user_id: u64) -> Vec<&Chat> {
-        self.chats
-            .iter()
-            .filter(|((a, b), _)| *a == user_id || *b == user_id)
-            .map(|(_, v)| &v.chat)
-            .collect::<Vec<_>>()
+    /// Adds the user's reaction to a message. Reacting twice with the same emoji has no effect.
+    pub fn add_reaction(
+        &mut self,
+        chat_id: u64,
+        message_id: &str,
+        user_id: u64,
+        emoji: &str,
+    ) -> Result<Message, ChatError> {
+        if emoji.is_empty()
+            || emoji.len() > MAX_REACTION_LEN
+            || emoji.chars().any(|c| c.is_whitespace() || c.is_control())
+        {
+            return Err(ChatError::InvalidReaction(emoji.to_owned()));
+        }
+        self.check_not_blocked(chat_id, user_id)?;
+        let chat = self.chat_room_mut(chat_id)?;
+        chat.check_participant(user_id)?;
+        let message = chat.message_mut(message_id)?;
+        if message.is_deleted() {
+            return Err(ChatError::MessageDeleted(message_id.to_owned()));
+        }
+        if !message.reactions.contains_key(emoji)
+            && message.reactions.len() >= MAX_DISTINCT_REACTIONS
+        {
+            return Err(ChatError::TooManyReactions(message_id.to_owned()));
+        }
+        let added = message
+            .reactions
+            .entry(emoji.to_owned())
+            .or_default()
+            .insert(user_id);
+        let message = message.clone();
+        if added {
+            chat.push_event(ChatEventKind::ReactionAdded {
+                message_id: message_id.to_owned(),
+                user_id,
+                emoji: emoji.to_owned(),
+            });
+        }
+        Ok(message)
     }
-}
 
-#[cfg(test)]
-mod tests {
+    /// Removes the user's reaction from a message, if they had made it
+    pub fn remove_reaction(
+        &mut self,
+        chat_id: u64,
+        message_id: &str,
+        user_id: u64,
+        emoji: &str,
+    ) -> Result<Message, ChatError> {
+        self.check_not_blocked(chat_id, user_id)?;
+        let chat = self.chat_room_mut(chat_id)?;
+        chat.check_participant(user_id)?;
+        let message = chat.message_mut(message_id)?;
+        let removed = match message.reactions.get_mut(emoji) {
+            Some(user_ids) => {
+                let removed = user_ids.remove(&user_id);
+                if user_ids.is_empty() {
+                    message.reactions.remove(emoji);
+                }
+                removed
+            }
+            None => false,
+        };
+        let message = message.clone();
+        if removed {
+            chat.push_event(ChatEventKind::ReactionRemoved {
+                message_id: message_id.to_owned(),
+                user_id,
+                emoji: emoji.to_owned(),
+            });
+        }
+        Ok(message)
+    }
 
-    use super::*;
+    /// Counts a new connection from the user, telling their chats if they just came online
+    pub fn user_connected(&mut self, user_id: u64) {
+        if self.presence.connected(user_id, timestamp()) {
+            self.broadcast_presence(user_id);
+        }
+    }
 
-    fn msg(src: u64, dst: u64) -> Message {
-        let ts = timestamp();
-        Message {
-            id: String::new(),
-            timestamp: ts,
-            source_user_id: src,
-            destination_user_id: dst,
-            message: format!("{} to {} at {}", src, dst, ts),
+    /// Forgets a connection from the user, telling their chats if they just went offline
+    pub fn user_disconnected(&mut self, user_id: u64) {
+        if self.presence.disconnected(user_id, timestamp()) {
+            self.broadcast_presence(user_id);
         }
     }
 
-    #[test]
-    fn test_chat_service() {
-        let mut service = ChatService::default();
+    /// Records activity from the user
+    pub fn user_seen(&mut self, user_id: u64) {
+        self.presence.seen(user_id, timestamp());
+    }
 
-        // adding message to log for chat id 11872 users (58534, 74827)
-        let chat = Chat {
-            id: 11872,
-            participant_ids: [58534, 74827],
+    pub fn get_presence(&self, user_id: u64) -> UserPresence {
+        UserPresence {
+            user_id,
+            status: self.presence.status(user_id),
+            last_seen: self.presence.last_seen(user_id),
+            typing_in: self.presence.typing_in(user_id, timestamp()),
+        }
+    }
+
+    /// Shows or clears the user's typing indicator in a chat
+    pub fn set_typing(
+        &mut self,
+        chat_id: u64,
+        user_id: u64,
+        typing: bool,
+    ) -> Result<(), ChatError> {
+        self.check_not_blocked(chat_id, user_id)?;
+        let chat = self.chat_room_mut(chat_id)?;
+        chat.check_participant(user_id)?;
+        let now = timestamp();
+        let (notify, expires_at) = if typing {
+            let (expires_at, notify) = self.presence.start_typing(chat_id, user_id, now);
+            (notify, Some(expires_at))
+        } else {
+            (self.presence.stop_typing(chat_id, user_id, now), None)
         };
+        if notify {
+            self.chat_room_mut(chat_id)?
+                .push_event(ChatEventKind::Typing {
+                    user_id,
+                    typing,
+                    expires_at,
+                });
+        }
+        Ok(())
+    }
 
-        service.add_chat(chat).unwrap();
+    fn broadcast_presence(&mut self, user_id: u64) {
+        let status = self.presence.status(user_id);
+        let last_seen = self.presence.last_seen(user_id);
+        for chat in self.chats.values_mut() {
+            if chat.chat.participant_ids.contains(&user_id) {
+                chat.push_event(ChatEventKind::Presence {
+                    user_id,
+                    status,
+                    last_seen,
+                });
+            }
+        }
+    }
 
-        for i in 0..10 {
-            service.send_message(11872, msg(58534, 74827)).unwrap();
+    /// Changes a chat's title, description or avatar, noting each change in the log
+    pub fn update_chat(&mut self, chat_id: u64, update: ChatUpdate) -> Result<Chat, ChatError> {
+        let user_id = update.user_id;
+        self.check_not_blocked(chat_id, user_id)?;
+        let chat = self.chat_room(chat_id)?;
+        chat.check_participant(user_id)?;
+        let mut updated = chat.chat.clone();
+        if let Some(title) = update.title {
+            updated.title = title;
         }
+        if let Some(description) = update.description {
+            updated.description = description;
+        }
+        if let Some(avatar) = update.avatar {
+            updated.avatar = avatar;
+        }
+        self.validate_metadata(&updated, user_id)?;
 
-        let messages = service.get_messages(11872).unwrap();
-        assert_eq!(messages.len(), 10);
+        let chat = self.chat_room_mut(chat_id)?;
+        let mut changes = Vec::new();
+        if updated.title != chat.chat.title {
+            changes.push(MessageKind::TitleChanged {
+                title: updated.title.clone(),
+            });
+        }
+        if updated.description != chat.chat.description {
+            changes.push(MessageKind::DescriptionChanged {
+                description: updated.description.clone(),
+            });
+        }
+        if updated.avatar != chat.chat.avatar {
+            changes.push(MessageKind::AvatarChanged {
+                avatar: updated.avatar.clone(),
+            });
+        }
+        chat.chat = updated;
+        for change in changes {
+            chat.push_system_message(user_id, change);
+        }
+        Ok(chat.chat.clone())
+    }
 
-        let mut high_mark = 0;
-        for msg in messages {
-            if high_mark == 0 {
-                high_mark = msg.timestamp;
+    /// Checks a chat's metadata as set by `user_id`, who must be able to see the avatar
+    fn validate_metadata(&self, chat: &Chat, user_id: u64) -> Result<(), ChatError> {
+        if chat.title.as_ref().is_some_and(|t| t.len() > MAX_TITLE_LEN) {
+            return Err(ChatError::InvalidChatMetadata(format!(
+                "title is longer than {} bytes",
+                MAX_TITLE_LEN
+            )));
+        }
+        if chat
+            .description
+            .as_ref()
+            .is_some_and(|d| d.len() > MAX_DESCRIPTION_LEN)
+        {
+            return Err(ChatError::InvalidChatMetadata(format!(
+                "description is longer than {} bytes",
+                MAX_DESCRIPTION_LEN
+            )));
+        }
+        if let Some(avatar) = &chat.avatar {
+            self.check_can_see(user_id, avatar)?;
+            match self.blobs.find(avatar) {
+                Some(attachment) if attachment.mime_type.starts_with("image/") => {}
+                Some(attachment) => {
+                    return Err(ChatError::InvalidChatMetadata(format!(
+                        "avatar is {}, not an image",
+                        attachment.mime_type
+                    )))
+                }
+                None => return Err(ChatError::AttachmentNotFound(avatar.clone())),
+            }
+        }
+        Ok(())
+    }
+
+    /// Pins a message to the top of the chat. Pinning a message twice has no effect.
+    pub fn pin_message(
+        &mut self,
+        chat_id: u64,
+        message_id: &str,
+        user_id: u64,
+    ) -> Result<Pin, ChatError> {
+        self.check_not_blocked(chat_id, user_id)?;
+        let chat = self.chat_room_mut(chat_id)?;
+        chat.check_participant(user_id)?;
+        if chat.message_mut(message_id)?.is_deleted() {
+            return Err(ChatError::MessageDeleted(message_id.to_owned()));
+        }
+        if let Some(pin) = chat.pins.iter().find(|pin| pin.message_id == message_id) {
+            return Ok(pin.clone());
+        }
+        if chat.pins.len() >= MAX_PINNED_MESSAGES {
+            return Err(ChatError::TooManyPins(chat_id));
+        }
+        let pin = Pin {
+            message_id: message_id.to_owned(),
+            pinned_by: user_id,
+            pinned_at: timestamp(),
+        };
+        chat.pins.push(pin.clone());
+        chat.push_event(ChatEventKind::MessagePinned {
+            message_id: message_id.to_owned(),
+            user_id,
+        });
+        Ok(pin)
+    }
+
+    /// Unpins a message, failing if it wasn't pinned
+    pub fn unpin_message(
+        &mut self,
+        chat_id: u64,
+        message_id: &str,
+        user_id: u64,
+    ) -> Result<(), ChatError> {
+        self.check_not_blocked(chat_id, user_id)?;
+        let chat = self.chat_room_mut(chat_id)?;
+        chat.check_participant(user_id)?;
+        if chat.unpin(message_id, user_id) {
+            Ok(())
+        } else {
+            Err(ChatError::MessageNotFound(message_id.to_owned()))
+        }
+    }
+
+    /// Lists a chat's pinned messages, oldest pin first
+    pub fn get_pins(&self, chat_id: u64) -> Result<Vec<PinnedMessage<'_>>, ChatError> {
+        let chat = self.chat_room(chat_id)?;
+        Ok(chat
+            .pins
+            .iter()
+            .filter_map(|pin| {
+                let message = chat.log.iter().find(|m| m.id == pin.message_id)?;
+                Some(PinnedMessage { pin, message })
+            })
+            .collect())
+    }
+
+    /// Stores an uploaded file so messages can refer to it, charging it to the user's
+    /// attachment and storage quotas
+    pub fn upload_attachment(
+        &mut self,
+        user_id: u64,
+        mime_type: &str,
+        data: &[u8],
+    ) -> Result<Attachment, ChatError> {
+        self.usage
+            .check_bytes(user_id, data.len() as u64, self.blobs.usage(user_id))
+            .map_err(|quota| ChatError::QuotaExceeded { user_id, quota })?;
+        self.blobs.put(user_id, mime_type, data)
+    }
+
+    /// How much of each quota the user has used
+    pub fn get_usage(&self, user_id: u64) -> UsageReport {
+        self.usage
+            .report(user_id, self.blobs.usage(user_id), timestamp())
+    }
+
+    /// Reads an attachment's metadata and content for a user who may see it - one who uploaded
+    /// it, or who takes part in a chat it is attached to or is the avatar of
+    pub fn get_attachment(
+        &mut self,
+        user_id: u64,
+        sha256: &str,
+    ) -> Result<(Attachment, Vec<u8>), ChatError> {
+        self.check_can_see(user_id, sha256)?;
+        let attachment = match self.blobs.get(sha256) {
+            Some(attachment) => attachment,
+            None => return Err(ChatError::AttachmentNotFound(sha256.to_owned())),
+        };
+        let data = self.blobs.read(&attachment)?;
+        Ok((attachment, data))
+    }
+
+    /// Users may see the blobs they uploaded, and those attached to or set as the avatar of a
+    /// chat they take part in. Anyone else isn't told whether a blob exists, and can't attach
+    /// it either, as that would let them see it.
+    fn check_can_see(&self, user_id: u64, sha256: &str) -> Result<(), ChatError> {
+        let visible = self.blobs.is_uploader(sha256, user_id)
+            || self.chats.values().any(|chat| {
+                chat.chat.participant_ids.contains(&user_id)
+                    && (chat.chat.avatar.as_deref() == Some(sha256)
+                        || chat
+                            .log
+                            .iter()
+                            .any(|m| m.attachments.iter().any(|a| a.sha256 == sha256)))
+            });
+        if !visible {
+            return Err(ChatError::AttachmentNotFound(sha256.to_owned()));
+        }
+        Ok(())
+    }
+
+    /// Lists a message followed by every reply under it, directly or to another reply
+    pub fn get_thread(&self, chat_id: u64, message_id: &str) -> Result<Vec<Message>, ChatError> {
+        let chat = self.chat_room(chat_id)?;
+        let root = match chat.log.iter().find(|m| m.id == message_id) {
+            Some(root) => root,
+            None => return Err(ChatError::MessageNotFound(message_id.to_owned())),
+        };
+        let mut ids = HashSet::new();
+        ids.insert(root.id.as_str());
+        // replies usually follow their parent in the log, so this rarely takes more than one pass
+        loop {
+            let found = chat
+                .log
+                .iter()
+                .filter(|m| !ids.contains(m.id.as_str()))
+                .filter(|m| {
+                    m.reply_to
+                        .as_ref()
+                        .is_some_and(|p| ids.contains(p.as_str()))
+                })
+                .map(|m| m.id.as_str())
+                .collect::<Vec<_>>();
+            if found.is_empty() {
+                break;
             }
+            ids.extend(found);
+        }
+        let mut thread = vec![root.clone()];
+        thread.extend(
+            chat.log
+                .iter()
+                .filter(|m| m.id != root.id && ids.contains(m.id.as_str()))
+                .cloned(),
+        );
+        Ok(thread)
+    }
+
+    /// Lists the events of a chat with a sequence number greater than `since`
+    pub fn get_events(&self, chat_id: u64, since: u64) -> Result<Vec<ChatEvent>, ChatError> {
+        let chat = self.chat_room(chat_id)?;
+        Ok(chat
+            .events
+            .iter()
+            .filter(|event| event.seq > since)
+            .cloned()
+            .collect())
+    }
+
+    /// Lists a user's chats - pinned chats first in pin order, then the rest by latest message
+    pub fn get_user_chats(&self, user_id: u64, filter: ChatListFilter) -> Vec<ChatSummary<'_>> {
+        let mut chats = self
+            .user_chats
+            .get(&user_id)
+            .into_iter()
+            .flatten()
+            .filter(|(_, settings)| settings.archived == filter.archived)
+            .filter(|(_, settings)| !filter.pinned_only || settings.pin_order.is_some())
+            .filter_map(|(chat_id, settings)| Some((self.chat_room(*chat_id).ok()?, settings)))
+            .collect::<Vec<_>>();
+        chats.sort_by_key(|(chat, settings)| {
+            let latest = chat.log.last().map_or(0, |m| m.timestamp);
+            (
+                settings.pin_order.is_none(),
+                settings.pin_order,
+                std::cmp::Reverse(latest),
+                chat.chat.id,
+            )
+        });
+        chats
+            .into_iter()
+            .map(|(chat, settings)| ChatSummary {
+                chat: &chat.chat,
+                settings,
+                unread_count: chat.unread_count(user_id),
+                read_cursors: &chat.read_cursors,
+            })
+            .collect()
+    }
 
-            assert!(high_mark >= msg.timestamp);
+    /// Mutes, archives or pins a chat for one of its participants
+    pub fn update_chat_settings(
+        &mut self,
+        chat_id: u64,
+        update: ChatSettingsUpdate,
+    ) -> Result<ChatSettings, ChatError> {
+        self.chat_room(chat_id)?.check_participant(update.user_id)?;
+        let settings = self
+            .user_chats
+            .entry(update.user_id)
+            .or_default()
+            .entry(chat_id)
+            .or_default();
+        if let Some(muted_until) = update.muted_until {
+            settings.muted_until = muted_until;
+        }
+        if let Some(archived) = update.archived {
+            settings.archived = archived;
         }
+        if let Some(pin_order) = update.pin_order {
+            settings.pin_order = pin_order;
+        }
+        Ok(settings.clone())
+    }
+
+    /// Marks messages up to and including `message_id` as read by the user, or the whole chat
+    /// when no message is given. Read cursors only ever move forward.
+    pub fn mark_read(
+        &mut self,
+        chat_id: u64,
+        user_id: u64,
+        message_id: Option<&str>,
+    ) -> Result<u64, ChatError> {
+        let chat = self.chat_room_mut(chat_id)?;
+        chat.check_participant(user_id)?;
+        let read_up_to = match message_id {
+            Some(message_id) => match chat.log.iter().find(|m| m.id == message_id) {
+                Some(message) => message.timestamp,
+                None => return Err(ChatError::MessageNotFound(message_id.to_owned())),
+            },
+            None => chat.log.last().map_or(0, |m| m.timestamp),
+        };
+        let cursor = chat.read_cursors.entry(user_id).or_insert(0);
+        if read_up_to > *cursor {
+            *cursor = read_up_to;
+            for message in chat
+                .log
+                .iter_mut()
+                .take_while(|m| m.timestamp <= read_up_to)
+            {
+                if let Some(state) = message.delivery.get_mut(&user_id) {
+                    *state = DeliveryState::Read;
+                }
+            }
+            chat.push_event(ChatEventKind::MessagesRead {
+                user_id,
+                read_up_to,
+            });
+        }
+        Ok(*chat.read_cursors.get(&user_id).unwrap())
+    }
+
+    /// Records that a message reached one of its recipients
+    pub fn ack_delivery(
+        &mut self,
+        chat_id: u64,
+        message_id: &str,
+        user_id: u64,
+    ) -> Result<Message, ChatError> {
+        let chat = self.chat_room_mut(chat_id)?;
+        chat.check_participant(user_id)?;
+        let message = chat.message_mut(message_id)?;
+        let advanced = match message.delivery.get_mut(&user_id) {
+            Some(state) if *state < DeliveryState::Delivered => {
+                *state = DeliveryState::Delivered;
+                true
+            }
+            _ => false,
+        };
+        let message = message.clone();
+        if advanced {
+            chat.push_event(ChatEventKind::MessageDelivered {
+                message_id: message_id.to_owned(),
+                user_id,
+            });
+        }
+        Ok(message)
+    }
+
+    /// Searches the messages of every chat the user takes part in, best match first.
+    /// See `search::Query` for the query syntax.
+    pub fn search(&self, user_id: u64, query: &str, limit: usize) -> Vec<SearchResult> {
+        let query = Query::parse(query);
+        if query.is_empty() {
+            return Vec::new();
+        }
+        let visible = |(chat_id, _): &(u64, String)| match self.chat_room(*chat_id) {
+            Ok(chat) => chat.chat.participant_ids.contains(&user_id),
+            Err(_) => false,
+        };
+        let mut results = Vec::new();
+        for hit in self.index.search(&query, visible) {
+            let (chat_id, message_id) = &hit.key;
+            let message = self
+                .chat_room(*chat_id)
+                .ok()
+                .and_then(|chat| chat.log.iter().find(|m| m.id == *message_id));
+            if let Some(message) = message {
+                results.push(SearchResult {
+                    chat_id: *chat_id,
+                    score: hit.score,
+                    context: search::snippet(&message.message, &hit.positions),
+                    message: message.clone(),
+                });
+            }
+        }
+        // equally good matches are shown newest first
+        results.sort_by(|a, b| {
+            b.score
+                .partial_cmp(&a.score)
+                .unwrap()
+                .then(b.message.timestamp.cmp(&a.message.timestamp))
+        });
+        results.truncate(limit);
+        results
+    }
+
+    /// Timestamp of the latest message each participant has read
+    pub fn get_read_cursors(&self, chat_id: u64) -> Result<HashMap<u64, u64>, ChatError> {
+        Ok(self.chat_room(chat_id)?.read_cursors.clone())
+    }
+
+    /// Sets a chat's own retention policy. The global policy still applies where it is stricter.
+    pub fn set_retention(
+        &mut self,
+        chat_id: u64,
+        user_id: u64,
+        policy: RetentionPolicy,
+    ) -> Result<RetentionPolicy, ChatError> {
+        if !policy.is_valid() {
+            return Err(ChatError::InvalidRetention(policy));
+        }
+        let chat = self.chat_room_mut(chat_id)?;
+        chat.check_participant(user_id)?;
+        chat.retention = policy;
+        Ok(policy)
+    }
+
+    pub fn get_retention(&self, chat_id: u64) -> Result<RetentionPolicy, ChatError> {
+        Ok(self.chat_room(chat_id)?.retention)
+    }
+
+    /// Removes every message that has outlived its chat's retention policy or its own time to
+    /// live, returning how many went
+    pub fn sweep_expired(&mut self, now: u64) -> usize {
+        let mut removed = 0;
+        for chat in self.chats.values_mut() {
+            let policy = self.retention.stricter(&chat.retention);
+            let expired = policy
+                .expired(&chat.log, now)
+                .into_iter()
+                .map(str::to_owned)
+                .collect::<HashSet<_>>();
+            if expired.is_empty() {
+                continue;
+            }
+            for message_id in &expired {
+                self.index.remove(&(chat.chat.id, message_id.clone()));
+            }
+            self.flags
+                .retain(|flag| flag.chat_id != chat.chat.id || !expired.contains(&flag.message_id));
+            for message in chat.purge(&expired) {
+                self.usage
+                    .release_bytes(message.source_user_id, message.stored_bytes());
+            }
+            let mut message_ids = expired.into_iter().collect::<Vec<_>>();
+            message_ids.sort();
+            removed += message_ids.len();
+            chat.push_event(ChatEventKind::MessagesExpired { message_ids });
+            self.unsaved.insert(chat.chat.id);
+        }
+        removed
+    }
+
+    /// Collects everything held about a user - their contacts, the chats they take part in with
+    /// every message in them, and their uploads. Each export is recorded in the audit log.
+    pub fn export_user(&mut self, user_id: u64) -> UserExport<'_> {
+        let chats = self
+            .chats
+            .values()
+            .filter(|chat| chat.chat.participant_ids.contains(&user_id))
+            .collect::<Vec<_>>();
+        self.audit.push(AuditRecord {
+            id: self.audit.len() as u64 + 1,
+            timestamp: timestamp(),
+            action: AuditAction::Export,
+            user_id,
+            chats: chats.len(),
+            messages: chats.iter().map(|chat| chat.log.len()).sum(),
+            contacts: self.contacts.get(user_id).map_or(0, |list| list.len()),
+        });
+
+        let settings = self.user_chats.get(&user_id);
+        let mut chats = chats
+            .into_iter()
+            .map(|chat| ChatExport {
+                chat: &chat.chat,
+                settings: settings.and_then(|settings| settings.get(&chat.chat.id)),
+                read_up_to: chat.read_cursors.get(&user_id).cloned(),
+                draft: chat.drafts.get(&user_id),
+                messages: &chat.log,
+            })
+            .collect::<Vec<_>>();
+        chats.sort_by_key(|chat| chat.chat.id);
+        let mut scheduled = self
+            .scheduled
+            .iter()
+            .map(|t| &t.value)
+            .filter(|s| s.message.source_user_id == user_id)
+            .collect::<Vec<_>>();
+        scheduled.sort_by_key(|s| s.message.send_at);
+        UserExport {
+            user_id,
+            exported_at: timestamp(),
+            contacts: self
+                .contacts
+                .get(user_id)
+                .map_or(&[], |list| list.as_slice()),
+            blocked: self.contacts.blocked(user_id),
+            chats,
+            scheduled,
+            attachments: self.blobs.uploaded_by(user_id),
+        }
+    }
+
+    /// Erases a user's data. Messages they sent become anonymous tombstones so the other
+    /// participant's side of each conversation survives, and they are dropped from delivery
+    /// states, reactions, read cursors, events, contact lists, presence, attachment uploads, and
+    /// the participants and creators of their chats. Scheduled messages in their chats are cancelled. The erasure is
+    /// recorded in the audit log.
+    pub fn erase_user(&mut self, user_id: u64) -> AuditRecord {
+        let now = timestamp();
+        let mut chats = 0;
+        let mut messages = 0;
+        let mut refiled = Vec::new();
+        for (key, chat) in self.chats.iter_mut() {
+            if !chat.chat.participant_ids.contains(&user_id) {
+                continue;
+            }
+            chats += 1;
+            refiled.push(*key);
+            if chat.chat.created_by == user_id {
+                chat.chat.created_by = ERASED_USER_ID;
+            }
+            for participant_id in chat.chat.participant_ids.iter_mut() {
+                if *participant_id == user_id {
+                    *participant_id = ERASED_USER_ID;
+                }
+            }
+            let mut erased = HashSet::new();
+            let mut parent_ids = Vec::new();
+            for message in chat.log.iter_mut() {
+                let was_deleted = message.is_deleted();
+                if message.erase_user(user_id, now) {
+                    erased.insert(message.id.clone());
+                    if !was_deleted {
+                        parent_ids.extend(message.reply_to.clone());
+                    }
+                }
+            }
+            // bot replies can repeat what the user wrote, so go with the messages they answer
+            let mut replies = HashSet::new();
+            for message in chat.log.iter_mut() {
+                let answers_erased = message
+                    .reply_to
+                    .as_ref()
+                    .is_some_and(|parent_id| erased.contains(parent_id));
+                if answers_erased && matches!(message.kind, MessageKind::BotReply { .. }) {
+                    message.erase(now);
+                    replies.insert(message.id.clone());
+                }
+            }
+            erased.extend(replies);
+            for parent_id in &parent_ids {
+                chat.uncount_reply(parent_id);
+            }
+            for message_id in &erased {
+                self.index.remove(&(chat.chat.id, message_id.clone()));
+            }
+            self.flags
+                .retain(|flag| flag.chat_id != chat.chat.id || !erased.contains(&flag.message_id));
+            chat.read_cursors.remove(&user_id);
+            chat.drafts.remove(&user_id);
+            chat.pins.retain(|pin| !erased.contains(&pin.message_id));
+            for pin in chat.pins.iter_mut().filter(|pin| pin.pinned_by == user_id) {
+                pin.pinned_by = ERASED_USER_ID;
+            }
+            chat.events.retain(|e| {
+                e.kind.user_id() != Some(user_id)
+                    && !e
+                        .kind
+                        .message_id()
+                        .is_some_and(|message_id| erased.contains(message_id))
+            });
+            for event in chat.events.iter_mut() {
+                if let Some(message) = event.kind.message_mut() {
+                    message.erase_user(user_id, now);
+                }
+            }
+            messages += erased.len();
+        }
+        // their chats were filed under their id, and are saved again without it
+        for key in refiled {
+            if let Some(chat) = self.chats.remove(&key) {
+                let key = chat_key(&chat.chat);
+                self.chat_keys.insert(chat.chat.id, key);
+                self.unsaved.insert(chat.chat.id);
+                self.chats.insert(key, chat);
+            }
+        }
+        // messages waiting to be sent in their chats are dropped rather than sent later, though
+        // only those they wrote are counted as theirs
+        let user_chats = self.user_chats.remove(&user_id).unwrap_or_default();
+        messages += self
+            .scheduled
+            .iter()
+            .filter(|t| t.value.message.source_user_id == user_id)
+            .count();
+        self.scheduled
+            .retain(|s| !user_chats.contains_key(&s.chat_id));
+        let contacts = self.contacts.erase(user_id);
+        self.webhooks.forget_user(user_id);
+        self.presence.forget(user_id);
+        self.usage.forget(user_id);
+        for sha256 in self.blobs.forget_uploader(user_id) {
+            let referenced = self.chats.values().any(|chat| {
+                chat.log
+                    .iter()
+                    .any(|m| m.attachments.iter().any(|a| a.sha256 == sha256))
+            });
+            if !referenced {
+                if let Err(e) = self.blobs.remove(&sha256) {
+                    eprintln!("unable to remove attachment {}: {}", sha256, e);
+                }
+            }
+        }
+
+        let record = AuditRecord {
+            id: self.audit.len() as u64 + 1,
+            timestamp: now,
+            action: AuditAction::Erase,
+            user_id,
+            chats,
+            messages,
+            contacts,
+        };
+        self.audit.push(record.clone());
+        record
+    }
+
+    pub fn get_audit_log(&self) -> &[AuditRecord] {
+        &self.audit
+    }
+
+    /// Adds a bot to answer its command in every chat. Returns false if another bot already
+    /// answers the same command.
+    pub fn register_bot(&mut self, bot: Box<dyn Bot>) -> bool {
+        self.bots.register(bot)
+    }
+
+    pub fn subscribe_webhook(
+        &mut self,
+        request: SubscriptionRequest,
+    ) -> Result<Subscription, ChatError> {
+        self.webhooks
+            .subscribe(request, timestamp())
+            .map_err(ChatError::InvalidWebhook)
+    }
+
+    /// Returns false if there was no such subscription
+    pub fn unsubscribe_webhook(&mut self, subscription_id: u64) -> bool {
+        self.webhooks.unsubscribe(subscription_id)
+    }
+
+    pub fn get_webhooks(&self) -> Vec<&Subscription> {
+        self.webhooks.subscriptions()
+    }
+
+    /// Webhook deliveries that failed every attempt
+    pub fn get_dead_letters(&self) -> &[DeadLetter] {
+        self.webhooks.dead_letters()
+    }
+
+    /// Returns false if the delivery isn't on the dead-letter list
+    pub fn redeliver_webhook(&mut self, delivery_id: u64) -> bool {
+        self.webhooks.redeliver(delivery_id, timestamp())
+    }
+
+    pub(crate) fn webhooks_mut(&mut self) -> &mut Webhooks {
+        &mut self.webhooks
+    }
+
+    /// Stops the target from starting chats with the user, and makes chats they already share
+    /// read-only for the target. Returns false if they were already blocked.
+    pub fn block_user(&mut self, user_id: u64, target_id: u64) -> Result<bool, ChatError> {
+        if user_id == target_id {
+            return Err(ChatError::CannotBlockSelf(user_id));
+        }
+        Ok(self.contacts.block(user_id, target_id))
+    }
+
+    /// Lifts a block, returning false if there wasn't one
+    pub fn unblock_user(&mut self, user_id: u64, target_id: u64) -> bool {
+        self.contacts.unblock(user_id, target_id)
+    }
+
+    /// Users the user has blocked
+    pub fn get_blocked(&self, user_id: u64) -> Vec<u64> {
+        self.contacts.blocked(user_id)
+    }
+
+    /// Fails if the other participant of the chat has blocked the user
+    fn check_not_blocked(&self, chat_id: u64, user_id: u64) -> Result<(), ChatError> {
+        let chat = self.chat_room(chat_id)?;
+        for other_id in chat
+            .chat
+            .participant_ids
+            .iter()
+            .filter(|id| **id != user_id)
+        {
+            if self.contacts.is_blocked(*other_id, user_id) {
+                return Err(ChatError::Blocked {
+                    user_id,
+                    blocked_by: *other_id,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn chat_room(&self, chat_id: u64) -> Result<&ChatRoom, ChatError> {
+        self.chat_keys
+            .get(&chat_id)
+            .and_then(|key| self.chats.get(key))
+            .ok_or(ChatError::ChatNotFound(chat_id))
+    }
+
+    /// The chat, to be saved again since it may be about to change
+    fn chat_room_mut(&mut self, chat_id: u64) -> Result<&mut ChatRoom, ChatError> {
+        let chats = &mut self.chats;
+        let chat = self
+            .chat_keys
+            .get(&chat_id)
+            .and_then(move |key| chats.get_mut(key))
+            .ok_or(ChatError::ChatNotFound(chat_id))?;
+        self.unsaved.insert(chat_id);
+        Ok(chat)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::messages::PresenceStatus;
+
+    /// A message with an id no other test message has
+    fn msg(src: u64, dst: u64) -> Message {
+        static NEXT_ID: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(1);
+        let ts = timestamp();
+        Message {
+            id: format!(
+                "msg-{}",
+                NEXT_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+            ),
+            timestamp: ts,
+            source_user_id: src,
+            destination_user_id: dst,
+            message: format!("{} to {} at {}", src, dst, ts),
+            ..Message::default()
+        }
+    }
+
+    /// The chat most tests use, between users 58534 and 74827
+    fn test_chat() -> Chat {
+        Chat {
+            id: 11872,
+            participant_ids: [58534, 74827],
+            ..Chat::default()
+        }
+    }
+
+    fn service_with_chat() -> ChatService {
+        let mut service = ChatService::default();
+        service.add_chat(test_chat()).unwrap();
+        service
+    }
+
+    /// Sends text from 58534 to 74827 in the test chat
+    fn send_text(service: &mut ChatService, id: &str, text: &str) -> Result<(), ChatError> {
+        let mut message = msg(58534, 74827);
+        message.id = id.to_owned();
+        message.message = text.to_owned();
+        service.send_message(11872, message)
+    }
+
+    #[test]
+    fn test_chat_service() {
+        // adding message to log for chat id 11872 users (58534, 74827)
+        let mut service = service_with_chat();
+
+        for _ in 0..10 {
+            service.send_message(11872, msg(58534, 74827)).unwrap();
+        }
+
+        let messages = service.get_messages(11872).unwrap();
+        assert_eq!(messages.len(), 11);
+        assert_eq!(messages[0].kind, MessageKind::ChatCreated { title: None });
+
+        let mut high_mark = 0;
+        for msg in messages {
+            assert!(msg.timestamp >= high_mark);
+            high_mark = msg.timestamp;
+        }
+    }
+
+    #[test]
+    fn test_message_ids() {
+        let mut service = service_with_chat();
+        service.send_message(11872, msg(58534, 74827)).unwrap();
+        let messages = service.get_messages(11872).unwrap();
+
+        // every message needs an id of its own
+        let mut unnamed = msg(58534, 74827);
+        unnamed.id.clear();
+        assert_eq!(
+            service.send_message(11872, unnamed),
+            Err(ChatError::InvalidMessageId(String::new()))
+        );
+        let mut duplicate = msg(74827, 58534);
+        duplicate.id = messages[1].id.clone();
+        assert_eq!(
+            service.send_message(11872, duplicate),
+            Err(ChatError::MessageExists(messages[1].id.clone()))
+        );
+        // the server's own ids are kept apart from those clients choose
+        assert!(messages[0].id.starts_with(SERVER_ID_PREFIX));
+        let mut reserved = msg(58534, 74827);
+        reserved.id = format!("{}system-99", SERVER_ID_PREFIX);
+        assert_eq!(
+            service.send_message(11872, reserved.clone()),
+            Err(ChatError::InvalidMessageId(reserved.id))
+        );
+        assert_eq!(service.get_messages(11872).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_edit_and_delete_message() {
+        let mut service = service_with_chat();
+
+        let mut message = msg(58534, 74827);
+        message.id = "m1".to_owned();
+        let original = message.message.clone();
+        service.send_message(11872, message).unwrap();
+
+        assert_eq!(
+            service.edit_message(11872, "m1", 74827, "not mine".to_owned()),
+            Err(ChatError::NotSender {
+                user_id: 74827,
+                message_id: "m1".to_owned()
+            })
+        );
+        assert_eq!(
+            service.edit_message(11872, "m2", 58534, "missing".to_owned()),
+            Err(ChatError::MessageNotFound("m2".to_owned()))
+        );
+
+        let edited = service
+            .edit_message(11872, "m1", 58534, "edited".to_owned())
+            .unwrap();
+        assert_eq!(edited.message, "edited");
+        assert!(edited.edited_at.is_some());
+        assert_eq!(edited.history.len(), 1);
+        assert_eq!(edited.history[0].message, original);
+
+        let deleted = service.delete_message(11872, "m1", 58534).unwrap();
+        assert!(deleted.is_deleted());
+        assert!(deleted.message.is_empty() && deleted.history.is_empty());
+        let messages = service.get_messages(11872).unwrap();
+        assert_eq!(messages[1], deleted);
+        // the deletion is noted in the log after the tombstone
+        assert_eq!(
+            messages[2].kind,
+            MessageKind::MessageDeleted {
+                message_id: "m1".to_owned()
+            }
+        );
+        assert_eq!(
+            service.edit_message(11872, "m1", 58534, "again".to_owned()),
+            Err(ChatError::MessageDeleted("m1".to_owned()))
+        );
+
+        // created, sent, edited, deleted and the deletion notice
+        let events = service.get_events(11872, 0).unwrap();
+        assert_eq!(events.len(), 5);
+        assert_eq!(service.get_events(11872, 4).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_saved_chats() {
+        let dir = std::env::temp_dir().join(format!("chat-data-test-{}", std::process::id()));
+        let config = Config {
+            data_dir: Some(dir.clone()),
+            ..Config::default()
+        };
+        let mut service = ChatService::new(&config).unwrap();
+        service.add_chat(test_chat()).unwrap();
+        send_text(&mut service, "m1", "first draft").unwrap();
+        send_text(&mut service, "m2", "never mind").unwrap();
+        service
+            .edit_message(11872, "m1", 58534, "final text".to_owned())
+            .unwrap();
+        service.delete_message(11872, "m2", 58534).unwrap();
+        service.add_reaction(11872, "m1", 74827, "👍").unwrap();
+        service.mark_read(11872, 74827, None).unwrap();
+        service.save();
+        let json = |service: &ChatService| {
+            let messages = service.get_messages(11872).unwrap();
+            let events = service.get_events(11872, 0).unwrap();
+            serde_json::to_value((messages, events)).unwrap()
+        };
+        let before = json(&service);
+
+        // edits, their history and deletions are all still there after a restart
+        let mut service = ChatService::new(&config).unwrap();
+        assert_eq!(json(&service), before);
+        let messages = service.get_messages(11872).unwrap();
+        assert_eq!(messages[0].kind, MessageKind::ChatCreated { title: None });
+        assert_eq!(messages[1].history[0].message, "first draft");
+        assert!(messages[2].is_deleted());
+        assert_eq!(service.chat_room(11872).unwrap().unread_count(74827), 0);
+        assert_eq!(service.search(74827, "final", 10).len(), 1);
+        assert!(service.search(74827, "never", 10).is_empty());
+        send_text(&mut service, "m3", "later").unwrap();
+        let messages = service.get_messages(11872).unwrap();
+        assert!(messages[4].timestamp > messages[3].timestamp);
+        assert_eq!(
+            service.get_events(11872, 0).unwrap().last().unwrap().seq,
+            before[1].as_array().unwrap().len() as u64 + 1
+        );
+
+        // an erased user is gone from what was saved too
+        service.erase_user(58534);
+        service.save();
+        let saved = std::fs::read_to_string(dir.join("11872.json")).unwrap();
+        assert!(!saved.contains("first draft") && !saved.contains("final text"));
+        let service = ChatService::new(&config).unwrap();
+        let chat = service.chat_room(11872).unwrap();
+        assert_eq!(chat.chat.participant_ids, [ERASED_USER_ID, 74827]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_cursors_and_unread_counts() {
+        let mut service = service_with_chat();
+
+        // messages are stamped by the server, whatever time the client says they were sent
+        let mut stamped = Vec::new();
+        for (i, ts) in [u64::MAX, 0, 30].iter().enumerate() {
+            let mut message = msg(58534, 74827);
+            message.id = format!("m{}", i);
+            message.timestamp = *ts;
+            service.send_message(11872, message).unwrap();
+            stamped.push(
+                service
+                    .get_messages(11872)
+                    .unwrap()
+                    .last()
+                    .unwrap()
+                    .timestamp,
+            );
+        }
+        assert!(stamped.windows(2).all(|pair| pair[0] < pair[1]));
+
+        let unread = |service: &ChatService, user_id| {
+            service.get_user_chats(user_id, ChatListFilter::default())[0].unread_count
+        };
+        assert_eq!(unread(&service, 74827), 3);
+        // a sender's own messages are never unread
+        assert_eq!(unread(&service, 58534), 0);
+
+        assert_eq!(service.mark_read(11872, 74827, Some("m1")), Ok(stamped[1]));
+        assert_eq!(unread(&service, 74827), 1);
+        // cursors don't move backwards
+        assert_eq!(service.mark_read(11872, 74827, Some("m0")), Ok(stamped[1]));
+        assert_eq!(service.mark_read(11872, 74827, None), Ok(stamped[2]));
+        assert_eq!(unread(&service, 74827), 0);
+        assert_eq!(service.get_read_cursors(11872).unwrap()[&74827], stamped[2]);
+        // so later messages are still unread after a client sent one from the far future
+        service.send_message(11872, msg(58534, 74827)).unwrap();
+        assert_eq!(unread(&service, 74827), 1);
+
+        assert_eq!(
+            service.mark_read(11872, 1, None),
+            Err(ChatError::NotParticipant {
+                user_id: 1,
+                chat_id: 11872
+            })
+        );
+    }
+
+    #[test]
+    fn test_delivery_states() {
+        let mut service = service_with_chat();
+
+        for i in 0..2 {
+            let mut message = msg(58534, 74827);
+            message.id = format!("m{}", i);
+            service.send_message(11872, message).unwrap();
+        }
+        // the first message is the notice that the chat was created
+        let state = |service: &ChatService, i: usize| {
+            service.get_messages(11872).unwrap()[i + 1].delivery[&74827]
+        };
+        assert_eq!(state(&service, 0), DeliveryState::Sent);
+        // senders don't track delivery to themselves
+        assert!(!service.get_messages(11872).unwrap()[1]
+            .delivery
+            .contains_key(&58534));
+
+        service.ack_delivery(11872, "m1", 74827).unwrap();
+        assert_eq!(state(&service, 0), DeliveryState::Sent);
+        assert_eq!(state(&service, 1), DeliveryState::Delivered);
+
+        service.mark_read(11872, 74827, Some("m0")).unwrap();
+        assert_eq!(state(&service, 0), DeliveryState::Read);
+        // a late ack doesn't move a read message backwards
+        service.ack_delivery(11872, "m0", 74827).unwrap();
+        assert_eq!(state(&service, 0), DeliveryState::Read);
+        assert_eq!(state(&service, 1), DeliveryState::Delivered);
+    }
+
+    #[test]
+    fn test_search_respects_participants() {
+        let mut service = ChatService::default();
+        service
+            .add_chat(Chat {
+                id: 1,
+                participant_ids: [58534, 74827],
+                ..Chat::default()
+            })
+            .unwrap();
+        service
+            .add_chat(Chat {
+                id: 2,
+                participant_ids: [58534, 68694],
+                ..Chat::default()
+            })
+            .unwrap();
+
+        let mut message = msg(58534, 74827);
+        message.id = "m1".to_owned();
+        message.message = "see you at the station".to_owned();
+        service.send_message(1, message).unwrap();
+        let mut message = msg(58534, 68694);
+        message.id = "m2".to_owned();
+        message.message = "the station is closed".to_owned();
+        service.send_message(2, message).unwrap();
+
+        assert_eq!(service.search(58534, "station", 10).len(), 2);
+        let results = service.search(74827, "station", 10);
+        assert_eq!(results.len(), 1);
+        assert_eq!(
+            (results[0].chat_id, results[0].message.id.as_str()),
+            (1, "m1")
+        );
+        assert_eq!(results[0].context, "see you at the station");
+
+        service.delete_message(1, "m1", 58534).unwrap();
+        assert!(service.search(74827, "station", 10).is_empty());
+    }
+
+    #[test]
+    fn test_replies_and_threads() {
+        let mut service = service_with_chat();
+
+        let send = |service: &mut ChatService, id: &str, reply_to: Option<&str>| {
+            let mut message = msg(58534, 74827);
+            message.id = id.to_owned();
+            message.reply_to = reply_to.map(str::to_owned);
+            service.send_message(11872, message)
+        };
+        send(&mut service, "root", None).unwrap();
+        send(&mut service, "other", None).unwrap();
+        send(&mut service, "r1", Some("root")).unwrap();
+        send(&mut service, "r2", Some("r1")).unwrap();
+        send(&mut service, "r3", Some("root")).unwrap();
+        let err = send(&mut service, "r4", Some("missing")).unwrap_err();
+        assert_eq!(err, ChatError::ParentNotFound("missing".to_owned()));
+
+        let messages = service.get_messages(11872).unwrap();
+        assert_eq!(messages.iter().filter(|m| !m.is_system()).count(), 5);
+        let root = messages.iter().find(|m| m.id == "root").unwrap();
+        assert_eq!(root.reply_count, 2);
+
+        let thread = service.get_thread(11872, "root").unwrap();
+        let ids = thread.iter().map(|m| m.id.as_str()).collect::<Vec<_>>();
+        assert_eq!(ids, vec!["root", "r1", "r2", "r3"]);
+        assert_eq!(service.get_thread(11872, "r1").unwrap().len(), 2);
+
+        // a reply that is turned away isn't counted, and a deleted one stops counting
+        let mut rejected = msg(58534, 74827);
+        rejected.reply_to = Some("root".to_owned());
+        rejected.attachments = vec![Attachment {
+            sha256: "0".repeat(64),
+            ..Attachment::default()
+        }];
+        assert!(matches!(
+            service.send_message(11872, rejected),
+            Err(ChatError::AttachmentNotFound(_))
+        ));
+        service.delete_message(11872, "r3", 58534).unwrap();
+        let reply_count = |service: &ChatService| {
+            let messages = service.get_messages(11872).unwrap();
+            messages
+                .iter()
+                .find(|m| m.id == "root")
+                .unwrap()
+                .reply_count
+        };
+        assert_eq!(reply_count(&service), 1);
+    }
+
+    #[test]
+    fn test_reactions() {
+        let mut service = service_with_chat();
+        let mut message = msg(58534, 74827);
+        message.id = "m1".to_owned();
+        service.send_message(11872, message).unwrap();
+
+        service.add_reaction(11872, "m1", 58534, "👍").unwrap();
+        service.add_reaction(11872, "m1", 74827, "👍").unwrap();
+        let message = service.add_reaction(11872, "m1", 74827, "👍").unwrap();
+        assert_eq!(message.reactions["👍"].len(), 2);
+        let json = serde_json::to_value(&message).unwrap();
+        assert_eq!(json["reactions"][0]["count"], 2);
+
+        let message = service.remove_reaction(11872, "m1", 58534, "👍").unwrap();
+        assert_eq!(message.reactions["👍"].len(), 1);
+        let message = service.remove_reaction(11872, "m1", 74827, "👍").unwrap();
+        assert!(message.reactions.is_empty());
+
+        assert_eq!(
+            service.add_reaction(11872, "m1", 58534, "two words"),
+            Err(ChatError::InvalidReaction("two words".to_owned()))
+        );
+        for i in 0..MAX_DISTINCT_REACTIONS {
+            service
+                .add_reaction(11872, "m1", 58534, &format!(":{}:", i))
+                .unwrap();
+        }
+        assert_eq!(
+            service.add_reaction(11872, "m1", 58534, "👍"),
+            Err(ChatError::TooManyReactions("m1".to_owned()))
+        );
+        // existing reactions can still be joined
+        service.add_reaction(11872, "m1", 74827, ":0:").unwrap();
+        // 2 adds and 2 removes, then the 20 distinct reactions and the join
+        assert_eq!(service.get_events(11872, 2).unwrap().len(), 25);
+    }
+
+    #[test]
+    fn test_typing_and_presence_events() {
+        let mut service = service_with_chat();
+
+        service.user_connected(58534);
+        service.user_connected(58534);
+        service.set_typing(11872, 58534, true).unwrap();
+        service.set_typing(11872, 58534, true).unwrap();
+        assert_eq!(service.get_presence(58534).typing_in, vec![11872]);
+
+        // sending a message clears the indicator
+        service.send_message(11872, msg(58534, 74827)).unwrap();
+        assert!(service.get_presence(58534).typing_in.is_empty());
+
+        service.user_disconnected(58534);
+        service.user_disconnected(58534);
+        assert_eq!(service.get_presence(58534).status, PresenceStatus::Offline);
+        let types = service
+            .get_events(11872, 0)
+            .unwrap()
+            .into_iter()
+            .map(|e| serde_json::to_value(&e).unwrap()["type"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            types,
+            vec![
+                "messageSent",
+                "presence",
+                "typing",
+                "messageSent",
+                "presence"
+            ]
+        );
+
+        assert!(service.set_typing(11872, 1, true).is_err());
+    }
+
+    #[test]
+    fn test_retention_sweep() {
+        let mut service = ChatService::new(&Config {
+            retention: RetentionPolicy {
+                max_age: Some(60_000),
+                max_count: None,
+            },
+            ..Config::default()
+        })
+        .unwrap();
+        service.add_chat(test_chat()).unwrap();
+        let ids = |service: &ChatService| {
+            service
+                .get_messages(11872)
+                .unwrap()
+                .into_iter()
+                .filter(|m| !m.is_system())
+                .map(|m| m.id)
+                .collect::<Vec<_>>()
+        };
+        let now = timestamp();
+        let mut old = msg(58534, 74827);
+        old.id = "old".to_owned();
+        service.send_message(11872, old).unwrap();
+        // only the server stamps messages, so this one is made old by hand
+        let chat = service.chat_room_mut(11872).unwrap();
+        chat.log.last_mut().unwrap().timestamp = now - 120_000;
+        let mut reply = msg(74827, 58534);
+        reply.id = "reply".to_owned();
+        reply.reply_to = Some("old".to_owned());
+        service.send_message(11872, reply).unwrap();
+        let mut disappearing = msg(58534, 74827);
+        disappearing.id = "disappearing".to_owned();
+        disappearing.message = "gone soon".to_owned();
+        disappearing.ttl = Some(5_000);
+        service.send_message(11872, disappearing).unwrap();
+
+        // the global max age removes the old message straight away
+        assert_eq!(service.sweep_expired(now), 1);
+        assert_eq!(ids(&service), vec!["reply", "disappearing"]);
+        assert!(service
+            .get_messages(11872)
+            .unwrap()
+            .iter()
+            .any(|m| m.id == "disappearing" && m.expires_at.is_some()));
+
+        // the disappearing message goes once its time is up, search included
+        assert_eq!(service.search(58534, "gone", 10).len(), 1);
+        assert_eq!(service.sweep_expired(now + 10_000), 1);
+        assert_eq!(ids(&service), vec!["reply"]);
+        assert!(service.search(58534, "gone", 10).is_empty());
+
+        // a chat's own policy can be stricter than the global one
+        let policy = RetentionPolicy {
+            max_age: None,
+            max_count: Some(1),
+        };
+        service.set_retention(11872, 58534, policy).unwrap();
+        let mut last = msg(58534, 74827);
+        last.id = "last".to_owned();
+        service.send_message(11872, last).unwrap();
+        // the notice that the chat was created doesn't count towards the limit
+        assert_eq!(service.sweep_expired(now + 10_000), 1);
+        assert_eq!(ids(&service), vec!["last"]);
+        let kept = service
+            .get_messages(11872)
+            .unwrap()
+            .into_iter()
+            .map(|m| m.id)
+            .collect::<Vec<_>>();
+        assert_eq!(kept.len(), 2);
+
+        // events about removed messages are dropped, leaving the expiry notices in their place
+        let events = service.get_events(11872, 0).unwrap();
+        assert!(events.iter().all(|e| e
+            .kind
+            .message_id()
+            .is_none_or(|message_id| kept.iter().any(|id| id == message_id))));
+        assert_eq!(
+            events
+                .iter()
+                .filter(|e| matches!(e.kind, ChatEventKind::MessagesExpired { .. }))
+                .count(),
+            3
+        );
+
+        // age counts from when the server got a message, whatever time the client gave
+        service
+            .set_retention(11872, 58534, RetentionPolicy::default())
+            .unwrap();
+        for (id, ts) in [("future", u64::MAX), ("epoch", 0)] {
+            let mut message = msg(58534, 74827);
+            message.id = id.to_owned();
+            message.timestamp = ts;
+            service.send_message(11872, message).unwrap();
+        }
+        assert_eq!(service.sweep_expired(timestamp()), 0);
+        service.sweep_expired(timestamp() + 120_000);
+        assert!(ids(&service).is_empty());
+
+        // a time to live too long to add up just never runs out
+        let mut forever = msg(58534, 74827);
+        forever.ttl = Some(u64::MAX);
+        service.send_message(11872, forever).unwrap();
+        let messages = service.get_messages(11872).unwrap();
+        assert_eq!(messages.last().unwrap().expires_at, Some(u64::MAX));
+
+        let invalid = RetentionPolicy {
+            max_age: Some(0),
+            max_count: None,
+        };
+        assert_eq!(
+            service.set_retention(11872, 58534, invalid),
+            Err(ChatError::InvalidRetention(invalid))
+        );
+    }
+
+    #[test]
+    fn test_export_and_erase_user() {
+        let mut service = ChatService::default();
+        for (id, other) in [(11872, 74827), (11873, 68694)] {
+            let chat = Chat {
+                id,
+                participant_ids: [58534, other],
+                ..Chat::default()
+            };
+            service.add_chat(chat).unwrap();
+        }
+        let mut sent = msg(58534, 74827);
+        sent.id = "sent".to_owned();
+        sent.message = "my secret plans".to_owned();
+        service.send_message(11872, sent).unwrap();
+        let mut received = msg(74827, 58534);
+        received.id = "received".to_owned();
+        service.send_message(11872, received).unwrap();
+        service
+            .add_reaction(11872, "received", 58534, "👍")
+            .unwrap();
+        service.mark_read(11872, 58534, None).unwrap();
+
+        let export = serde_json::to_value(service.export_user(58534)).unwrap();
+        assert_eq!(export["chats"].as_array().unwrap().len(), 2);
+        assert_eq!(
+            export["chats"][0]["messages"][1]["message"],
+            "my secret plans"
+        );
+        assert!(export["contacts"]
+            .as_array()
+            .unwrap()
+            .contains(&74827.into()));
+
+        let record = service.erase_user(58534);
+        // their sent message and the notices for both chats they created
+        assert_eq!((record.chats, record.messages), (2, 3));
+        assert!(record.contacts > 0);
+
+        let chats = service.get_user_chats(74827, ChatListFilter::default());
+        assert_eq!(chats[0].chat.created_by, ERASED_USER_ID);
+        assert_eq!(chats[0].chat.participant_ids, [ERASED_USER_ID, 74827]);
+        assert!(service
+            .chat_room(11872)
+            .unwrap()
+            .check_participant(58534)
+            .is_err());
+        let messages = service.get_messages(11872).unwrap();
+        assert_eq!(messages[1].source_user_id, ERASED_USER_ID);
+        assert_eq!(messages[1].message, "");
+        assert!(messages[1].is_deleted());
+        assert_eq!(messages[2].destination_user_id, ERASED_USER_ID);
+        assert!(messages[2].reactions.is_empty());
+        assert!(service.search(74827, "secret", 10).is_empty());
+        assert!(service.get_read_cursors(11872).unwrap().is_empty());
+        for event in service.get_events(11872, 0).unwrap() {
+            assert_ne!(event.kind.user_id(), Some(58534));
+            if let ChatEventKind::MessageSent { message } = event.kind {
+                assert_ne!(message.source_user_id, 58534);
+                assert_ne!(message.destination_user_id, 58534);
+                assert!(message.delivery.is_empty());
+            }
+        }
+
+        // they can no longer be added to chats, and the audit log keeps both requests
+        let chat = Chat {
+            id: 11874,
+            ..test_chat()
+        };
+        assert!(service.add_chat(chat).is_err());
+        let actions = service
+            .get_audit_log()
+            .iter()
+            .map(|r| r.action)
+            .collect::<Vec<_>>();
+        assert_eq!(actions, vec![AuditAction::Export, AuditAction::Erase]);
+
+        // nothing the other participant can get at still names them
+        fn names(value: &serde_json::Value, user_id: u64) -> bool {
+            match value {
+                serde_json::Value::Number(n) => n.as_u64() == Some(user_id),
+                serde_json::Value::Array(values) => values.iter().any(|v| names(v, user_id)),
+                serde_json::Value::Object(fields) => fields
+                    .iter()
+                    .any(|(k, v)| *k == user_id.to_string() || names(v, user_id)),
+                _ => false,
+            }
+        }
+        let export = serde_json::to_value(service.export_user(74827)).unwrap();
+        assert!(!names(&export, 58534));
+    }
+
+    #[test]
+    fn test_blocking() {
+        let mut service = service_with_chat();
+        let mut message = msg(74827, 58534);
+        message.id = "before".to_owned();
+        service.send_message(11872, message).unwrap();
+        service.add_reaction(11872, "before", 74827, "👍").unwrap();
+        // only participants can post, whoever has blocked whom
+        let mut outsider = msg(68694, 58534);
+        outsider.id = "outsider".to_owned();
+        assert_eq!(
+            service.send_message(11872, outsider),
+            Err(ChatError::NotParticipant {
+                user_id: 68694,
+                chat_id: 11872
+            })
+        );
+
+        assert!(service.block_user(58534, 74827).unwrap());
+        assert_eq!(
+            service.block_user(58534, 58534),
+            Err(ChatError::CannotBlockSelf(58534))
+        );
+        let blocked = || ChatError::Blocked {
+            user_id: 74827,
+            blocked_by: 58534,
+        };
+        // the existing chat is read-only for the blocked user, but not for the blocker
+        assert_eq!(
+            service.send_message(11872, msg(74827, 58534)),
+            Err(ChatError::Blocked {
+                user_id: 74827,
+                blocked_by: 58534,
+            })
+        );
+        assert_eq!(
+            service.add_reaction(11872, "before", 74827, "👍"),
+            Err(blocked())
+        );
+        assert_eq!(
+            service.remove_reaction(11872, "before", 74827, "👍"),
+            Err(blocked())
+        );
+        assert_eq!(
+            service.delete_message(11872, "before", 74827),
+            Err(blocked())
+        );
+        assert_eq!(service.set_typing(11872, 74827, true), Err(blocked()));
+        service.send_message(11872, msg(58534, 74827)).unwrap();
+        service.set_typing(11872, 58534, true).unwrap();
+
+        // and no new chat can be started in either direction
+        let chat = Chat {
+            id: 11873,
+            participant_ids: [74827, 58534],
+            ..Chat::default()
+        };
+        assert_eq!(
+            service.add_chat(chat),
+            Err(ChatError::Blocked {
+                user_id: 74827,
+                blocked_by: 58534,
+            })
+        );
+
+        assert!(service.unblock_user(58534, 74827));
+        assert!(service.get_blocked(58534).is_empty());
+        service.send_message(11872, msg(74827, 58534)).unwrap();
+    }
+
+    #[test]
+    fn test_add_chat_errors() {
+        let mut service = ChatService::default();
+        let chat = |id, participant_ids| Chat {
+            id,
+            participant_ids,
+            ..Chat::default()
+        };
+        service.add_chat(chat(11872, [58534, 74827])).unwrap();
+        assert_eq!(
+            service.add_chat(chat(11872, [58534, 68694])),
+            Err(ChatError::ChatExists(11872))
+        );
+        assert_eq!(
+            service.add_chat(chat(11873, [58534, 1])),
+            Err(ChatError::UserNotFound(1))
+        );
+        assert_eq!(
+            service.add_chat(chat(11873, [58534, 25583])),
+            Err(ChatError::NotContacts {
+                user_id: 58534,
+                contact_id: 25583,
+            })
+        );
+    }
+
+    #[test]
+    fn test_chat_settings() {
+        let mut service = ChatService::default();
+        for (id, other) in [(11872, 74827), (11873, 68694)] {
+            let chat = Chat {
+                id,
+                participant_ids: [58534, other],
+                ..Chat::default()
+            };
+            service.add_chat(chat).unwrap();
+        }
+        let list = |service: &ChatService, archived, pinned_only| {
+            let filter = ChatListFilter {
+                archived,
+                pinned_only,
+            };
+            service
+                .get_user_chats(58534, filter)
+                .iter()
+                .map(|summary| summary.chat.id)
+                .collect::<Vec<_>>()
+        };
+        let update = |user_id, archived, pin_order| ChatSettingsUpdate {
+            user_id,
+            muted_until: None,
+            archived,
+            pin_order,
+        };
+
+        // the chat with the latest message comes first until one is pinned
+        service.send_message(11872, msg(74827, 58534)).unwrap();
+        assert_eq!(list(&service, false, false), vec![11872, 11873]);
+        service
+            .update_chat_settings(11873, update(58534, None, Some(Some(1))))
+            .unwrap();
+        assert_eq!(list(&service, false, false), vec![11873, 11872]);
+        assert_eq!(list(&service, false, true), vec![11873]);
+
+        // archived chats are listed separately, and only for the user who archived them
+        service
+            .update_chat_settings(11872, update(58534, Some(true), None))
+            .unwrap();
+        assert_eq!(list(&service, false, false), vec![11873]);
+        assert_eq!(list(&service, true, false), vec![11872]);
+        assert_eq!(
+            service
+                .get_user_chats(74827, ChatListFilter::default())
+                .len(),
+            1
+        );
+
+        // a new message unarchives the chat, muted or not
+        service.send_message(11872, msg(74827, 58534)).unwrap();
+        assert!(list(&service, true, false).is_empty());
+        service
+            .update_chat_settings(11872, update(58534, Some(true), None))
+            .unwrap();
+        let mute = ChatSettingsUpdate {
+            muted_until: Some(Some(timestamp() + 60_000)),
+            ..update(58534, None, None)
+        };
+        let settings = service.update_chat_settings(11872, mute).unwrap();
+        assert!(settings.archived && settings.is_muted(timestamp()));
+        service.send_message(11872, msg(74827, 58534)).unwrap();
+        assert!(list(&service, true, false).is_empty());
+
+        assert_eq!(
+            service.update_chat_settings(11872, update(68694, Some(true), None)),
+            Err(ChatError::NotParticipant {
+                user_id: 68694,
+                chat_id: 11872,
+            })
+        );
+    }
+
+    #[test]
+    fn test_pins() {
+        let mut service = service_with_chat();
+        for i in 0..=MAX_PINNED_MESSAGES {
+            let mut message = msg(58534, 74827);
+            message.id = format!("m{}", i);
+            service.send_message(11872, message).unwrap();
+        }
+
+        let pin = service.pin_message(11872, "m0", 74827).unwrap();
+        assert_eq!(pin.pinned_by, 74827);
+        // pinning again keeps the original pin
+        assert_eq!(service.pin_message(11872, "m0", 58534), Ok(pin));
+        for i in 1..MAX_PINNED_MESSAGES {
+            service
+                .pin_message(11872, &format!("m{}", i), 58534)
+                .unwrap();
+        }
+        let last = format!("m{}", MAX_PINNED_MESSAGES);
+        assert_eq!(
+            service.pin_message(11872, &last, 58534),
+            Err(ChatError::TooManyPins(11872))
+        );
+        assert_eq!(
+            service.pin_message(11872, "missing", 58534),
+            Err(ChatError::MessageNotFound("missing".to_owned()))
+        );
+
+        // deleting a pinned message unpins it, making room for another
+        service.delete_message(11872, "m1", 58534).unwrap();
+        service.pin_message(11872, &last, 58534).unwrap();
+        service.unpin_message(11872, "m0", 58534).unwrap();
+        assert!(service.unpin_message(11872, "m0", 58534).is_err());
+        let pins = service.get_pins(11872).unwrap();
+        assert_eq!(pins.len(), MAX_PINNED_MESSAGES - 1);
+        assert_eq!(pins[0].message.id, "m2");
+        assert_eq!(pins.last().unwrap().message.id, last);
+
+        let types = service
+            .get_events(11872, 0)
+            .unwrap()
+            .into_iter()
+            .map(|e| serde_json::to_value(&e).unwrap()["type"].clone())
+            .filter(|t| t.as_str().unwrap().contains("inned"))
+            .collect::<Vec<_>>();
+        assert_eq!(types.iter().filter(|t| *t == "messagePinned").count(), 11);
+        assert_eq!(types.iter().filter(|t| *t == "messageUnpinned").count(), 2);
+    }
+
+    #[test]
+    fn test_chat_metadata() {
+        let mut service = ChatService::default();
+        let chat = Chat {
+            title: Some("Lunch".to_owned()),
+            created_by: 74827,
+            ..test_chat()
+        };
+        service.add_chat(chat).unwrap();
+        let listed = &service.get_user_chats(58534, ChatListFilter::default())[0];
+        assert_eq!(listed.chat.title.as_deref(), Some("Lunch"));
+        assert_eq!(listed.chat.created_by, 74827);
+        assert!(listed.chat.created_at > 0);
+
+        let update = ChatUpdate {
+            user_id: 58534,
+            title: Some(Some("Dinner".to_owned())),
+            description: Some(Some("Friday".to_owned())),
+            avatar: None,
+        };
+        let chat = service.update_chat(11872, update).unwrap();
+        assert_eq!(chat.title.as_deref(), Some("Dinner"));
+        assert_eq!(chat.description.as_deref(), Some("Friday"));
+
+        // each change is noted in the log, and can't be edited by the user it names
+        let messages = service.get_messages(11872).unwrap();
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[0].message, "created the chat \"Lunch\"");
+        assert_eq!(messages[0].source_user_id, 74827);
+        let messages = &messages[1..];
+        assert!(messages
+            .iter()
+            .all(|m| m.is_system() && m.source_user_id == 58534));
+        assert_eq!(
+            messages[0].kind,
+            MessageKind::TitleChanged {
+                title: Some("Dinner".to_owned())
+            }
+        );
+        assert_eq!(messages[0].message, "changed the title to \"Dinner\"");
+        assert!(matches!(
+            service.edit_message(11872, &messages[0].id, 58534, "hi".to_owned()),
+            Err(ChatError::NotSender { .. })
+        ));
+
+        let update = |title: String, avatar: Option<Option<String>>| ChatUpdate {
+            user_id: 58534,
+            title: Some(Some(title)),
+            description: None,
+            avatar,
+        };
+        assert!(matches!(
+            service.update_chat(11872, update("x".repeat(MAX_TITLE_LEN + 1), None)),
+            Err(ChatError::InvalidChatMetadata(_))
+        ));
+        let missing = "0".repeat(64);
+        assert_eq!(
+            service.update_chat(
+                11872,
+                update("Dinner".to_owned(), Some(Some(missing.clone())))
+            ),
+            Err(ChatError::AttachmentNotFound(missing))
+        );
+        // a rejected update changes nothing
+        assert_eq!(service.get_messages(11872).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_scheduled_messages() {
+        let mut service = service_with_chat();
+        let now = timestamp();
+        let schedule = |service: &mut ChatService, id: &str, send_at: u64| {
+            let mut message = msg(58534, 74827);
+            message.id = id.to_owned();
+            message.send_at = Some(send_at);
+            service.schedule_message(11872, message)
+        };
+        schedule(&mut service, "later", now + 60_000).unwrap();
+        schedule(&mut service, "soon", now + 1_000).unwrap();
+        let generated = schedule(&mut service, "", now + 2_000).unwrap();
+        let generated = generated.message.id;
+        assert_eq!(generated, format!("{}scheduled-1", SERVER_ID_PREFIX));
+        assert_eq!(
+            schedule(&mut service, "soon", now + 5_000),
+            Err(ChatError::ScheduledMessageExists("soon".to_owned()))
+        );
+        // ids are checked against the chat too
+        let sent = msg(58534, 74827);
+        service.send_message(11872, sent.clone()).unwrap();
+        assert_eq!(
+            schedule(&mut service, &sent.id, now + 5_000),
+            Err(ChatError::MessageExists(sent.id))
+        );
+        assert_eq!(
+            schedule(&mut service, &generated, now + 5_000),
+            Err(ChatError::InvalidMessageId(generated.clone()))
+        );
+        let mut stranger = msg(1, 74827);
+        stranger.send_at = Some(now + 1_000);
+        assert!(matches!(
+            service.schedule_message(11872, stranger),
+            Err(ChatError::NotParticipant { .. })
+        ));
+
+        // nothing reaches the chat until it is due
+        let ids = |scheduled: Vec<ScheduledMessage>| {
+            scheduled
+                .into_iter()
+                .map(|s| s.message.id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            ids(service.get_scheduled(58534)),
+            vec!["soon", generated.as_str(), "later"]
+        );
+        assert!(service.get_scheduled(74827).is_empty());
+        assert_eq!(service.next_scheduled(), Some(now + 1_000));
+        assert_eq!(service.send_scheduled(now), 0);
+        assert_eq!(service.get_messages(11872).unwrap().len(), 2);
+
+        assert_eq!(
+            service.cancel_scheduled(58534, &generated).unwrap().chat_id,
+            11872
+        );
+        assert_eq!(
+            service.cancel_scheduled(74827, "later"),
+            Err(ChatError::MessageNotFound("later".to_owned()))
+        );
+        assert_eq!(service.send_scheduled(now + 5_000), 1);
+        let messages = service.get_messages(11872).unwrap();
+        let sent = messages.last().unwrap();
+        assert_eq!(sent.id, "soon");
+        assert!(sent.timestamp >= now);
+        assert_eq!(ids(service.get_scheduled(58534)), vec!["later"]);
+        assert_eq!(service.next_scheduled(), Some(now + 60_000));
+
+        // a message that can't be sent when it's due is dropped, and the sender told why
+        schedule(&mut service, "blocked", now + 10_000).unwrap();
+        service.block_user(74827, 58534).unwrap();
+        assert_eq!(service.send_scheduled(now + 10_000), 0);
+        let events = service.get_events(11872, 0).unwrap();
+        match &events.last().unwrap().kind {
+            ChatEventKind::ScheduledMessageFailed {
+                message_id,
+                user_id,
+                reason,
+            } => {
+                assert_eq!((message_id.as_str(), *user_id), ("blocked", 58534));
+                assert_eq!(reason, "user 58534 has been blocked by user 74827");
+            }
+            kind => panic!("unexpected event {:?}", kind),
+        }
+        service.unblock_user(74827, 58534);
+
+        // erasing either participant cancels what's left, counted for the user who wrote it
+        assert_eq!(service.erase_user(74827).messages, 0);
+        assert_eq!(service.next_scheduled(), None);
+    }
+
+    #[test]
+    fn test_drafts() {
+        let mut service = service_with_chat();
+        let draft = |text: &str| Draft {
+            message: text.to_owned(),
+            reply_to: None,
+            updated_at: 0,
+        };
+
+        assert_eq!(service.get_draft(11872, 58534), Ok(None));
+        service.set_draft(11872, 58534, draft("hel")).unwrap();
+        let saved = service.set_draft(11872, 58534, draft("hello")).unwrap();
+        assert!(saved.updated_at > 0);
+        assert_eq!(service.get_draft(11872, 58534), Ok(Some(&saved)));
+        assert!(matches!(
+            service.set_draft(11872, 1, draft("hi")),
+            Err(ChatError::NotParticipant { .. })
+        ));
+
+        // sending clears the sender's draft only
+        service.set_draft(11872, 74827, draft("reply")).unwrap();
+        service.send_message(11872, msg(58534, 74827)).unwrap();
+        assert_eq!(service.get_draft(11872, 58534), Ok(None));
+        assert_eq!(service.delete_draft(11872, 74827), Ok(true));
+        assert_eq!(service.delete_draft(11872, 74827), Ok(false));
+    }
+
+    #[test]
+    fn test_mentions() {
+        let mut service = ChatService::default();
+        for (id, other) in [(11872, 74827), (11873, 68694)] {
+            let chat = Chat {
+                id,
+                participant_ids: [58534, other],
+                ..Chat::default()
+            };
+            service.add_chat(chat).unwrap();
+        }
+        send_text(&mut service, "m1", "hi @74827").unwrap();
+        let mut message = msg(58534, 68694);
+        message.id = "m2".to_owned();
+        message.message = "@68694 ask @58534".to_owned();
+        service.send_message(11873, message).unwrap();
+        assert_eq!(
+            send_text(&mut service, "m3", "@68694 is not here"),
+            Err(ChatError::InvalidMention {
+                user_id: 68694,
+                chat_id: 11872
+            })
+        );
+        let events = service.get_events(11872, 0).unwrap();
+        match &events.last().unwrap().kind {
+            ChatEventKind::MessageSent { message } => assert_eq!(message.mentions, vec![74827]),
+            kind => panic!("unexpected event {:?}", kind),
+        }
+
+        let ids = |mentions: Vec<MentionResult>| {
+            mentions
+                .into_iter()
+                .map(|m| (m.chat_id, m.message.id))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            ids(service.get_mentions(74827, 10)),
+            vec![(11872, "m1".to_owned())]
+        );
+        assert_eq!(ids(service.get_mentions(58534, 10)).len(), 1);
+
+        // edits change who is mentioned, and deleted messages leave the feed
+        service
+            .edit_message(11872, "m1", 58534, "hi all".to_owned())
+            .unwrap();
+        assert!(service.get_mentions(74827, 10).is_empty());
+        service.delete_message(11873, "m2", 58534).unwrap();
+        assert!(service.get_mentions(68694, 10).is_empty());
+    }
+
+    #[test]
+    fn test_bots() {
+        struct ShoutBot;
+
+        impl Bot for ShoutBot {
+            fn command(&self) -> &str {
+                "shout"
+            }
+
+            fn description(&self) -> &str {
+                "Shouts"
+            }
+
+            fn handle(&self, command: &crate::bots::Command<'_>) -> Option<String> {
+                Some(command.args.to_uppercase())
+            }
+        }
+
+        let mut service = service_with_chat();
+        assert!(service.register_bot(Box::new(ShoutBot)));
+        assert!(!service.register_bot(Box::new(ShoutBot)));
+        send_text(&mut service, "m1", "/echo hello").unwrap();
+        send_text(&mut service, "m2", "/shout hello").unwrap();
+        send_text(&mut service, "m3", "no command here").unwrap();
+
+        let messages = service.get_messages(11872).unwrap();
+        let replies = messages
+            .iter()
+            .filter(|m| matches!(m.kind, MessageKind::BotReply { .. }))
+            .map(|m| (m.reply_to.as_deref(), m.message.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(replies, vec![(Some("m1"), "hello"), (Some("m2"), "HELLO")]);
+        let command = messages.iter().find(|m| m.id == "m1").unwrap();
+        assert_eq!(command.reply_count, 1);
+        // replies reach clients as messages from the bot, to whoever sent the command
+        let reply = messages.iter().find(|m| m.reply_to.is_some()).unwrap();
+        assert!(reply.id.starts_with(SERVER_ID_PREFIX));
+        assert_eq!(reply.source_user_id, BOT_USER_ID);
+        assert_eq!(reply.destination_user_id, 58534);
+        assert!(matches!(
+            service.edit_message(11872, &reply.id, 58534, "mine".to_owned()),
+            Err(ChatError::NotSender { .. })
+        ));
+        assert_eq!(
+            service.get_user_chats(74827, ChatListFilter::default())[0].unread_count,
+            3
+        );
+
+        // replies repeating what an erased user wrote go with the user's messages
+        service.erase_user(58534);
+        let messages = service.get_messages(11872).unwrap();
+        assert!(messages
+            .iter()
+            .filter(|m| matches!(m.kind, MessageKind::BotReply { .. }))
+            .all(|m| m.message.is_empty() && m.is_deleted()));
+    }
+
+    #[test]
+    fn test_moderation() {
+        use crate::moderation::{FilterAction, Links, MaxLength, WordList};
+
+        let mut service = service_with_chat();
+        service.add_filter(Box::new(MaxLength(20)));
+        service.add_filter(Box::new(WordList::new(
+            vec!["heck".to_owned()],
+            FilterAction::Redact,
+        )));
+        service.add_filter(Box::new(Links(FilterAction::Flag)));
+        assert_eq!(
+            send_text(&mut service, "m1", "far too long to be let through"),
+            Err(ChatError::MessageRejected(
+                "message is longer than 20 characters".to_owned()
+            ))
+        );
+        send_text(&mut service, "m2", "what the heck").unwrap();
+        send_text(&mut service, "m3", "see www.x.com").unwrap();
+        send_text(&mut service, "m4", "and www.y.com").unwrap();
+        service
+            .edit_message(11872, "m2", 58534, "heck, www.x.com".to_owned())
+            .unwrap();
+
+        let messages = service.get_messages(11872).unwrap();
+        assert!(!messages.iter().any(|m| m.id == "m1"));
+        assert_eq!(messages[1].message, "****, www.x.com");
+        let flagged = service
+            .get_flagged()
+            .iter()
+            .map(|review| (review.flag.id, review.message.id.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(flagged, vec![(1, "m3"), (2, "m4"), (3, "m2")]);
+        assert_eq!(
+            service.get_flagged()[0].flag.reasons,
+            vec!["contains a link"]
+        );
+
+        service.resolve_flag(1, ReviewAction::Approve).unwrap();
+        let removed = service.resolve_flag(2, ReviewAction::Remove).unwrap();
+        assert_eq!(removed.message_id, "m4");
+        assert_eq!(
+            service.resolve_flag(2, ReviewAction::Remove),
+            Err(ChatError::FlagNotFound(2))
+        );
+        let messages = service.get_messages(11872).unwrap();
+        assert!(messages.iter().find(|m| m.id == "m4").unwrap().is_deleted());
+        assert_eq!(
+            messages.last().unwrap().kind,
+            MessageKind::MessageRemoved {
+                message_id: "m4".to_owned()
+            }
+        );
+        // flags on messages the sender deletes are dropped from the queue
+        service.delete_message(11872, "m2", 58534).unwrap();
+        assert!(service.flags.is_empty());
+        // as are those on messages that expire, or whose sender is erased
+        send_text(&mut service, "m5", "www.z.com").unwrap();
+        send_text(&mut service, "m6", "www.z.com").unwrap();
+        service
+            .set_retention(
+                11872,
+                58534,
+                RetentionPolicy {
+                    max_age: None,
+                    max_count: Some(1),
+                },
+            )
+            .unwrap();
+        service.sweep_expired(timestamp());
+        assert_eq!(service.flags.len(), 1);
+        service.erase_user(58534);
+        assert!(service.flags.is_empty());
+    }
+
+    #[test]
+    fn test_quotas() {
+        use crate::quotas::Quotas;
+
+        let mut service = ChatService {
+            usage: Usage::new(Quotas {
+                chats: Some(1),
+                messages_per_day: Some(2),
+                stored_bytes: Some(10),
+            }),
+            ..ChatService::default()
+        };
+        service.add_chat(test_chat()).unwrap();
+        let chat = Chat {
+            id: 11873,
+            participant_ids: [58534, 68694],
+            ..Chat::default()
+        };
+        assert_eq!(
+            service.add_chat(chat),
+            Err(ChatError::QuotaExceeded {
+                user_id: 58534,
+                quota: Quota::Chats(1)
+            })
+        );
+
+        send_text(&mut service, "m1", "hello").unwrap();
+        assert_eq!(
+            send_text(&mut service, "m2", "storage!"),
+            Err(ChatError::QuotaExceeded {
+                user_id: 58534,
+                quota: Quota::StoredBytes(10)
+            })
+        );
+        // the old text stays in the history, so an edit adds to what's stored
+        service
+            .edit_message(11872, "m1", 58534, "hi".to_owned())
+            .unwrap();
+        assert_eq!(service.get_usage(58534).stored_bytes.used, 7);
+        // deleting frees the message's text and history
+        service.delete_message(11872, "m1", 58534).unwrap();
+        assert_eq!(service.get_usage(58534).stored_bytes.used, 0);
+        send_text(&mut service, "m2", "storage!").unwrap();
+        assert!(matches!(
+            send_text(&mut service, "m3", "a"),
+            Err(ChatError::QuotaExceeded {
+                quota: Quota::MessagesPerDay(2),
+                ..
+            })
+        ));
+
+        let usage = service.get_usage(58534);
+        assert_eq!((usage.chats.used, usage.messages_today.used), (1, 2));
+        assert_eq!(service.get_usage(74827).chats.used, 0);
     }
 }
@@ -0,0 +1,197 @@
+use std::convert::TryFrom;
+use std::env;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use super::moderation::FilterAction;
+use super::quotas::Quotas;
+use super::rate_limit::RateLimit;
+use super::retention::RetentionPolicy;
+
+/// Server settings. `Config::default()` suits tests and local runs, `Config::from_env` lets
+/// each setting be overridden with a `CHAT_*` environment variable.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Directory attachment blobs are written to (`CHAT_BLOB_DIR`)
+    pub blob_dir: PathBuf,
+    /// Directory chats are saved to, with their messages, edit history and events, so they
+    /// survive a restart (`CHAT_DATA_DIR` - `data` unless set, empty to keep them only in
+    /// memory)
+    pub data_dir: Option<PathBuf>,
+    /// Largest single attachment accepted, in bytes (`CHAT_MAX_ATTACHMENT_SIZE`)
+    pub max_attachment_size: u64,
+    /// Total attachment bytes each user may upload (`CHAT_ATTACHMENT_QUOTA`)
+    pub attachment_quota: u64,
+    /// Limits applied to every chat on top of its own policy (`CHAT_RETENTION_MAX_AGE` in
+    /// milliseconds, `CHAT_RETENTION_MAX_COUNT` - unset or 0 for no limit)
+    pub retention: RetentionPolicy,
+    /// Milliseconds between sweeps for expired messages (`CHAT_RETENTION_SWEEP_INTERVAL`)
+    pub retention_sweep_interval: u64,
+    /// Bearer token for the `/admin` routes, which are disabled without one (`CHAT_ADMIN_TOKEN`)
+    pub admin_token: Option<String>,
+    /// Longest message text accepted, in characters (`CHAT_MAX_MESSAGE_LENGTH` - unset or 0 for
+    /// no limit)
+    pub max_message_length: Option<usize>,
+    /// File of words to catch in messages, one per line (`CHAT_BLOCKED_WORDS_FILE`)
+    pub blocked_words_file: Option<PathBuf>,
+    /// What to do with messages containing a blocked word (`CHAT_BLOCKED_WORDS_ACTION`)
+    pub blocked_words_action: FilterAction,
+    /// What to do with messages containing links, which are let through without one
+    /// (`CHAT_LINK_ACTION`)
+    pub link_action: Option<FilterAction>,
+    /// Most times a character may repeat in a row (`CHAT_MAX_REPEATED_CHARS` - unset or 0 for no
+    /// limit)
+    pub max_repeated_chars: Option<usize>,
+    /// What to do with messages repeating a character too often (`CHAT_REPEATED_CHARS_ACTION`)
+    pub repeated_chars_action: FilterAction,
+    /// How fast each address and each user may send messages: a burst of
+    /// `CHAT_MESSAGE_RATE_BURST` (0 for no limit), then one every `CHAT_MESSAGE_RATE_INTERVAL`
+    /// milliseconds
+    pub message_rate_limit: Option<RateLimit>,
+    /// What each user may create (`CHAT_MAX_CHATS`, `CHAT_MAX_MESSAGES_PER_DAY` and
+    /// `CHAT_STORAGE_QUOTA` in bytes - unset or 0 for no limit)
+    pub quotas: Quotas,
+    /// Milliseconds a connection may sit idle before it is closed (`CHAT_IDLE_TIMEOUT`)
+    pub idle_timeout: u64,
+    /// Milliseconds a client has to send a request's headers once it starts
+    /// (`CHAT_HEADER_TIMEOUT`)
+    pub header_timeout: u64,
+    /// Bytes per second a request must average once it has taken longer than the header timeout
+    /// (`CHAT_MIN_TRANSFER_RATE` - 0 for no minimum)
+    pub min_transfer_rate: u64,
+    /// Most client connections open at once (`CHAT_MAX_CONNECTIONS`). Defaults to 1024, or
+    /// fewer if the open file limit leaves no room for that many.
+    pub max_connections: usize,
+    /// Milliseconds open connections get to finish once the server is asked to shut down
+    /// (`CHAT_SHUTDOWN_GRACE_PERIOD`)
+    pub shutdown_grace_period: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            blob_dir: PathBuf::from("blobs"),
+            data_dir: None,
+            max_attachment_size: 10 * 1024 * 1024,
+            attachment_quota: 100 * 1024 * 1024,
+            retention: RetentionPolicy::default(),
+            retention_sweep_interval: 1_000,
+            admin_token: None,
+            max_message_length: None,
+            blocked_words_file: None,
+            blocked_words_action: FilterAction::Redact,
+            link_action: None,
+            max_repeated_chars: None,
+            repeated_chars_action: FilterAction::Flag,
+            message_rate_limit: Some(RateLimit {
+                burst: 20,
+                interval: 500,
+            }),
+            quotas: Quotas::default(),
+            idle_timeout: 60_000,
+            header_timeout: 10_000,
+            min_transfer_rate: 1024,
+            max_connections: default_max_connections(),
+            shutdown_grace_period: 10_000,
+        }
+    }
+}
+
+/// Open files kept back from client connections, for the listener, attachments, webhooks and
+/// the like
+const RESERVED_FILES: usize = 64;
+
+/// As many connections as the soft open file limit allows, up to 1024. Accepting more than the
+/// limit allows fails, and leaves nothing for anything else the server needs to open.
+fn default_max_connections() -> usize {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    let open_files = if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } == 0 {
+        usize::try_from(limit.rlim_cur).unwrap_or(usize::MAX)
+    } else {
+        1024
+    };
+    open_files.saturating_sub(RESERVED_FILES).clamp(1, 1024)
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        let defaults = Config::default();
+        Config {
+            blob_dir: env::var_os("CHAT_BLOB_DIR")
+                .map(PathBuf::from)
+                .unwrap_or(defaults.blob_dir),
+            data_dir: Some(
+                env::var_os("CHAT_DATA_DIR").map_or_else(|| "data".into(), PathBuf::from),
+            )
+            .filter(|dir| !dir.as_os_str().is_empty()),
+            max_attachment_size: env_or("CHAT_MAX_ATTACHMENT_SIZE", defaults.max_attachment_size),
+            attachment_quota: env_or("CHAT_ATTACHMENT_QUOTA", defaults.attachment_quota),
+            retention: RetentionPolicy {
+                max_age: Some(env_or("CHAT_RETENTION_MAX_AGE", 0)).filter(|n| *n > 0),
+                max_count: Some(env_or("CHAT_RETENTION_MAX_COUNT", 0)).filter(|n| *n > 0),
+            },
+            retention_sweep_interval: env_or(
+                "CHAT_RETENTION_SWEEP_INTERVAL",
+                defaults.retention_sweep_interval,
+            ),
+            admin_token: env::var("CHAT_ADMIN_TOKEN")
+                .ok()
+                .filter(|token| !token.is_empty()),
+            max_message_length: Some(env_or("CHAT_MAX_MESSAGE_LENGTH", 0)).filter(|n| *n > 0),
+            blocked_words_file: env::var_os("CHAT_BLOCKED_WORDS_FILE").map(PathBuf::from),
+            blocked_words_action: env_or(
+                "CHAT_BLOCKED_WORDS_ACTION",
+                defaults.blocked_words_action,
+            ),
+            // an unreadable action still turns the filter on, as the strictest one
+            link_action: env::var_os("CHAT_LINK_ACTION")
+                .map(|_| env_or("CHAT_LINK_ACTION", FilterAction::Reject)),
+            max_repeated_chars: Some(env_or("CHAT_MAX_REPEATED_CHARS", 0)).filter(|n| *n > 0),
+            repeated_chars_action: env_or(
+                "CHAT_REPEATED_CHARS_ACTION",
+                defaults.repeated_chars_action,
+            ),
+            message_rate_limit: defaults
+                .message_rate_limit
+                .map(|limit| RateLimit {
+                    burst: env_or("CHAT_MESSAGE_RATE_BURST", limit.burst),
+                    interval: env_or("CHAT_MESSAGE_RATE_INTERVAL", limit.interval),
+                })
+                .filter(|limit| limit.burst > 0),
+            quotas: Quotas {
+                chats: Some(env_or("CHAT_MAX_CHATS", 0)).filter(|n| *n > 0),
+                messages_per_day: Some(env_or("CHAT_MAX_MESSAGES_PER_DAY", 0)).filter(|n| *n > 0),
+                stored_bytes: Some(env_or("CHAT_STORAGE_QUOTA", 0)).filter(|n| *n > 0),
+            },
+            idle_timeout: env_or("CHAT_IDLE_TIMEOUT", defaults.idle_timeout),
+            header_timeout: env_or("CHAT_HEADER_TIMEOUT", defaults.header_timeout),
+            min_transfer_rate: env_or("CHAT_MIN_TRANSFER_RATE", defaults.min_transfer_rate),
+            max_connections: env_or("CHAT_MAX_CONNECTIONS", defaults.max_connections),
+            shutdown_grace_period: env_or(
+                "CHAT_SHUTDOWN_GRACE_PERIOD",
+                defaults.shutdown_grace_period,
+            ),
+        }
+    }
+
+    /// Largest request the server will buffer - an attachment plus room for the request head
+    pub fn max_request_size(&self) -> usize {
+        self.max_attachment_size as usize + 64 * 1024
+    }
+}
+
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(value) => match value.parse() {
+            Ok(value) => value,
+            Err(_) => {
+                eprintln!("ignoring invalid value {:?} for {}", value, name);
+                default
+            }
+        },
+        Err(_) => default,
+    }
+}
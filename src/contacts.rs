@@ -0,0 +1,119 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::io::BufReader;
+
+use lazy_static::lazy_static;
+
+lazy_static! {
+    static ref USERS: HashMap<u64, Vec<u64>> = {
+        let file = File::open("contacts.json").expect("unable to open contacts.json");
+        let reader = BufReader::new(file);
+        let m: HashMap<_, _> = serde_json::from_reader(reader).unwrap();
+        m
+    };
+}
+
+/// Each user's contact list and the users they have blocked. Contact lists start out as the
+/// lists in contacts.json, which stays untouched - changes such as erasing a user only last as
+/// long as the server runs.
+pub struct Contacts {
+    lists: HashMap<u64, Vec<u64>>,
+    blocked: HashMap<u64, BTreeSet<u64>>,
+}
+
+impl Default for Contacts {
+    fn default() -> Self {
+        Contacts {
+            lists: USERS.clone(),
+            blocked: HashMap::new(),
+        }
+    }
+}
+
+impl Contacts {
+    pub fn get(&self, user_id: u64) -> Option<&Vec<u64>> {
+        self.lists.get(&user_id)
+    }
+
+    /// Returns false if the user had already blocked the target
+    pub fn block(&mut self, user_id: u64, target_id: u64) -> bool {
+        self.blocked.entry(user_id).or_default().insert(target_id)
+    }
+
+    /// Returns false if the user hadn't blocked the target
+    pub fn unblock(&mut self, user_id: u64, target_id: u64) -> bool {
+        match self.blocked.get_mut(&user_id) {
+            Some(blocked) => {
+                let removed = blocked.remove(&target_id);
+                if blocked.is_empty() {
+                    self.blocked.remove(&user_id);
+                }
+                removed
+            }
+            None => false,
+        }
+    }
+
+    pub fn is_blocked(&self, user_id: u64, target_id: u64) -> bool {
+        self.blocked
+            .get(&user_id)
+            .is_some_and(|blocked| blocked.contains(&target_id))
+    }
+
+    pub fn blocked(&self, user_id: u64) -> Vec<u64> {
+        self.blocked
+            .get(&user_id)
+            .map_or_else(Vec::new, |blocked| blocked.iter().cloned().collect())
+    }
+
+    /// Removes the user's own contact list and every entry for them in other users' lists,
+    /// returning how many entries went
+    pub fn erase(&mut self, user_id: u64) -> usize {
+        let mut removed = self.lists.remove(&user_id).map_or(0, |list| list.len());
+        for list in self.lists.values_mut() {
+            let before = list.len();
+            list.retain(|contact| *contact != user_id);
+            removed += before - list.len();
+        }
+        self.blocked.remove(&user_id);
+        for blocked in self.blocked.values_mut() {
+            blocked.remove(&user_id);
+        }
+        self.blocked.retain(|_, blocked| !blocked.is_empty());
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_erase() {
+        let mut contacts = Contacts::default();
+        assert!(contacts.get(58534).unwrap().contains(&74827));
+        assert!(contacts.get(74827).unwrap().contains(&58534));
+        let own = contacts.get(58534).unwrap().len();
+        assert!(contacts.erase(58534) > own);
+        assert_eq!(contacts.get(58534), None);
+        assert!(!contacts.get(74827).unwrap().contains(&58534));
+        assert_eq!(contacts.erase(58534), 0);
+    }
+
+    #[test]
+    fn test_block() {
+        let mut contacts = Contacts::default();
+        assert!(contacts.block(1, 2));
+        assert!(!contacts.block(1, 2));
+        assert!(contacts.is_blocked(1, 2));
+        assert!(!contacts.is_blocked(2, 1));
+        contacts.block(3, 1);
+        assert_eq!(contacts.blocked(1), vec![2]);
+
+        contacts.erase(1);
+        assert!(contacts.blocked(1).is_empty());
+        assert!(!contacts.is_blocked(3, 1));
+        assert!(!contacts.unblock(1, 2));
+    }
+}
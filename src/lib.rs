@@ -1,8 +1,24 @@
+mod attachments;
+mod bots;
 mod chat_service;
+mod config;
+mod contacts;
+mod mentions;
 mod messages;
+mod moderation;
 mod parse;
+mod presence;
+mod quotas;
+mod rate_limit;
+mod retention;
 mod router;
+mod search;
 mod server;
+mod shutdown;
+mod store;
+mod timer_wheel;
+mod webhooks;
 
+pub use config::Config;
 pub use router::Router;
 pub use server::Server;
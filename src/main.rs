@@ -1,4 +1,4 @@
-use chat_mio::Server;
+use chat_mio::{Config, Server};
 use mio::net::TcpListener;
 
 fn main() {
@@ -10,13 +10,15 @@ fn main() {
         .unwrap();
 
     let listener = TcpListener::bind(&addr).unwrap();
-    let mut server = Server::new(listener).unwrap();
+    let mut server = Server::with_config(listener, Config::from_env()).unwrap();
+    server.handle_signals().unwrap();
 
     println!("Running chat server on {}. Press ctrl-c to exit...", addr);
-    loop {
+    while !server.is_finished() {
         match server.poll() {
             Ok(_) => {}
             Err(e) => println!("Error handling http request {:?}", e),
         }
     }
+    println!("Chat server stopped");
 }
@@ -0,0 +1,42 @@
+/// Users mentioned in a message's text with `@<userId>`, in the order they're first mentioned.
+/// A mention has to start a word and be all digits, so `@42,` counts but email addresses like
+/// `me@42.com` and words like `@42nd` don't.
+pub fn parse(text: &str) -> Vec<u64> {
+    let mut mentions = Vec::new();
+    let mut previous = None;
+    for (i, c) in text.char_indices() {
+        if c == '@' && !previous.is_some_and(is_word_char) {
+            let rest = &text[i + 1..];
+            let end = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            let ends_word = !rest[end..].chars().next().is_some_and(is_word_char);
+            if let Ok(user_id) = rest[..end].parse::<u64>() {
+                if ends_word && !mentions.contains(&user_id) {
+                    mentions.push(user_id);
+                }
+            }
+        }
+        previous = Some(c);
+    }
+    mentions
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("@74827 are you there? cc @68694"), vec![74827, 68694]);
+        assert_eq!(parse("(@74827), @74827!"), vec![74827]);
+        assert!(parse("mail me@74827.com or @74827th or @ or @x1").is_empty());
+        // too big to be a user id
+        assert!(parse("@99999999999999999999999").is_empty());
+    }
+}
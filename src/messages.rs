@@ -1,22 +1,306 @@
-use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 
-#[derive(Serialize, Deserialize, PartialEq, Eq, Hash)]
+use serde::de::Error as _;
+use serde::ser::SerializeSeq;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use super::retention::RetentionPolicy;
+
+/// Stands in for the sender and recipient of messages whose user has been erased
+pub const ERASED_USER_ID: u64 = 0;
+
+/// Sender of bot replies. Replies are sent to whoever sent the message that prompted them.
+pub const BOT_USER_ID: u64 = u64::MAX;
+
+/// Starts the id of every message the server writes. Clients can't use ids starting with it,
+/// so the two never collide.
+pub const SERVER_ID_PREFIX: &str = "~";
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Debug, Default)]
 pub struct Chat {
     #[serde(default)]
     pub id: u64,
     #[serde(alias = "participantIds")]
     pub participant_ids: [u64; 2],
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// sha256 of an uploaded image
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub avatar: Option<String>,
+    /// Participant who created the chat - the first participant unless given
+    #[serde(rename = "createdBy", default)]
+    pub created_by: u64,
+    /// When the server added the chat. Any value a client sends is replaced.
+    #[serde(rename = "createdAt", default)]
+    pub created_at: u64,
 }
 
-#[derive(PartialEq, Eq, Serialize, Deserialize, Clone, Debug)]
+/// Body of `PATCH /chats/:chatId` - fields left out are unchanged, and `null` clears them
+#[derive(Deserialize)]
+pub struct ChatUpdate {
+    #[serde(rename = "userId")]
+    pub user_id: u64,
+    #[serde(default, deserialize_with = "present")]
+    pub title: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    pub description: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    pub avatar: Option<Option<String>>,
+}
+
+#[derive(PartialEq, Eq, Serialize, Deserialize, Clone, Debug, Default)]
 pub struct Message {
     pub id: String,
     #[serde(rename = "sourceUserId")]
     pub source_user_id: u64,
     #[serde(rename = "destinationUserId")]
     pub destination_user_id: u64,
+    /// When the server received the message. Any value a client sends is replaced.
     pub timestamp: u64,
     pub message: String,
+    /// Time of the most recent edit, if the message has been edited
+    #[serde(rename = "editedAt", default, skip_serializing_if = "Option::is_none")]
+    pub edited_at: Option<u64>,
+    /// Previous revisions of the message text, oldest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub history: Vec<MessageRevision>,
+    /// Set when the sender has deleted the message - the text and history are cleared
+    #[serde(rename = "deletedAt", default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<u64>,
+    /// How far the message has got towards each recipient
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub delivery: BTreeMap<u64, DeliveryState>,
+    /// Id of the message in the same chat this one replies to
+    #[serde(rename = "replyTo", default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<String>,
+    /// Number of messages replying directly to this one, leaving out deleted replies
+    #[serde(rename = "replyCount", default, skip_serializing_if = "is_zero")]
+    pub reply_count: u64,
+    /// Users who reacted to the message, by reaction
+    #[serde(
+        skip_deserializing,
+        skip_serializing_if = "BTreeMap::is_empty",
+        serialize_with = "serialize_reactions"
+    )]
+    pub reactions: BTreeMap<String, BTreeSet<u64>>,
+    /// Uploaded files sent with the message - clients only need to give the sha256
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<Attachment>,
+    /// Users mentioned in the text with `@<userId>`, worked out by the server
+    #[serde(skip_deserializing, skip_serializing_if = "Vec::is_empty")]
+    pub mentions: Vec<u64>,
+    /// Whether this is text from a user or an event the server wrote to the log, such as a
+    /// title change. Only the server writes system events.
+    #[serde(skip_deserializing, skip_serializing_if = "MessageKind::is_text")]
+    pub kind: MessageKind,
+    /// Makes the message disappear this many milliseconds after it is sent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u64>,
+    /// When a disappearing message will be removed
+    #[serde(rename = "expiresAt", default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    /// Holds the message back until this time. Scheduled messages stay out of the chat until
+    /// they are sent, and keep the field afterwards.
+    #[serde(rename = "sendAt", default, skip_serializing_if = "Option::is_none")]
+    pub send_at: Option<u64>,
+}
+
+/// What a message in the log is. System events carry what changed, and their message text
+/// describes it for clients that don't know the event. A chat always has the same two
+/// participants, so there are no events for members joining or leaving.
+#[derive(PartialEq, Eq, Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(tag = "type")]
+pub enum MessageKind {
+    #[default]
+    #[serde(rename = "text")]
+    Text,
+    #[serde(rename = "chatCreated")]
+    ChatCreated { title: Option<String> },
+    #[serde(rename = "titleChanged")]
+    TitleChanged { title: Option<String> },
+    #[serde(rename = "descriptionChanged")]
+    DescriptionChanged { description: Option<String> },
+    #[serde(rename = "avatarChanged")]
+    AvatarChanged { avatar: Option<String> },
+    #[serde(rename = "messageDeleted")]
+    MessageDeleted {
+        #[serde(rename = "messageId")]
+        message_id: String,
+    },
+    /// A moderator removed a message flagged for review
+    #[serde(rename = "messageRemoved")]
+    MessageRemoved {
+        #[serde(rename = "messageId")]
+        message_id: String,
+    },
+    /// A bot's answer to a message, with the command of the bot that gave it
+    #[serde(rename = "botReply")]
+    BotReply { bot: String },
+}
+
+impl MessageKind {
+    pub fn is_text(&self) -> bool {
+        *self == MessageKind::Text
+    }
+
+    /// A notice of something done to the chat, rather than anything said in it
+    pub fn is_notice(&self) -> bool {
+        !matches!(self, MessageKind::Text | MessageKind::BotReply { .. })
+    }
+
+    /// Text for a system event, as shown to clients that don't know the event. Bot replies
+    /// carry their own text.
+    pub fn describe(&self) -> String {
+        match self {
+            MessageKind::Text | MessageKind::BotReply { .. } => String::new(),
+            MessageKind::ChatCreated { title: Some(title) } => {
+                format!("created the chat {:?}", title)
+            }
+            MessageKind::ChatCreated { title: None } => "created the chat".to_owned(),
+            MessageKind::TitleChanged { title: Some(title) } => {
+                format!("changed the title to {:?}", title)
+            }
+            MessageKind::TitleChanged { title: None } => "removed the title".to_owned(),
+            MessageKind::DescriptionChanged {
+                description: Some(_),
+            } => "changed the description".to_owned(),
+            MessageKind::DescriptionChanged { description: None } => {
+                "removed the description".to_owned()
+            }
+            MessageKind::AvatarChanged { avatar: Some(_) } => "changed the avatar".to_owned(),
+            MessageKind::AvatarChanged { avatar: None } => "removed the avatar".to_owned(),
+            MessageKind::MessageDeleted { .. } => "deleted a message".to_owned(),
+            MessageKind::MessageRemoved { .. } => "a message was removed by a moderator".to_owned(),
+        }
+    }
+}
+
+/// A message waiting for its `sendAt` time, as listed to its sender
+#[derive(PartialEq, Eq, Serialize, Clone, Debug)]
+pub struct ScheduledMessage {
+    #[serde(rename = "chatId")]
+    pub chat_id: u64,
+    #[serde(flatten)]
+    pub message: Message,
+}
+
+/// Metadata of an uploaded file, which is stored under the sha256 of its content
+#[derive(PartialEq, Eq, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Attachment {
+    pub sha256: String,
+    #[serde(rename = "mimeType", default)]
+    pub mime_type: String,
+    #[serde(default)]
+    pub size: u64,
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+/// Lists reactions as `[{"emoji": .., "count": .., "userIds": [..]}]`
+fn serialize_reactions<S>(
+    reactions: &BTreeMap<String, BTreeSet<u64>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    #[derive(Serialize)]
+    struct Reaction<'a> {
+        emoji: &'a str,
+        count: usize,
+        #[serde(rename = "userIds")]
+        user_ids: &'a BTreeSet<u64>,
+    }
+
+    let mut seq = serializer.serialize_seq(Some(reactions.len()))?;
+    for (emoji, user_ids) in reactions {
+        seq.serialize_element(&Reaction {
+            emoji,
+            count: user_ids.len(),
+            user_ids,
+        })?;
+    }
+    seq.end()
+}
+
+/// Reads reactions back from the list `serialize_reactions` writes
+fn deserialize_reactions<'de, D>(
+    deserializer: D,
+) -> Result<BTreeMap<String, BTreeSet<u64>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct Reaction {
+        emoji: String,
+        #[serde(rename = "userIds")]
+        user_ids: BTreeSet<u64>,
+    }
+
+    let reactions = Vec::<Reaction>::deserialize(deserializer)?;
+    Ok(reactions
+        .into_iter()
+        .map(|reaction| (reaction.emoji, reaction.user_ids))
+        .collect())
+}
+
+/// Reads a message as the server wrote it out, taking back the fields a client's message
+/// can't set
+fn deserialize_stored<'de, D>(deserializer: D) -> Result<Message, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct ServerOwned {
+        #[serde(default, deserialize_with = "deserialize_reactions")]
+        reactions: BTreeMap<String, BTreeSet<u64>>,
+        #[serde(default)]
+        mentions: Vec<u64>,
+        #[serde(default)]
+        kind: MessageKind,
+    }
+
+    let value = serde_json::Value::deserialize(deserializer)?;
+    let owned = ServerOwned::deserialize(&value).map_err(D::Error::custom)?;
+    let mut message = Message::deserialize(value).map_err(D::Error::custom)?;
+    message.reactions = owned.reactions;
+    message.mentions = owned.mentions;
+    message.kind = owned.kind;
+    Ok(message)
+}
+
+/// Like `deserialize_stored`, for a chat's whole log
+pub(crate) fn deserialize_stored_log<'de, D>(deserializer: D) -> Result<Vec<Message>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct Stored(#[serde(deserialize_with = "deserialize_stored")] Message);
+
+    let log = Vec::<Stored>::deserialize(deserializer)?;
+    Ok(log.into_iter().map(|Stored(message)| message).collect())
+}
+
+/// Delivery progress of a message to one recipient - states only ever move forward
+#[derive(PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, Clone, Copy, Debug)]
+pub enum DeliveryState {
+    #[serde(rename = "sent")]
+    Sent,
+    #[serde(rename = "delivered")]
+    Delivered,
+    #[serde(rename = "read")]
+    Read,
+}
+
+/// A prior version of a message's text, and when that version was written
+#[derive(PartialEq, Eq, Serialize, Deserialize, Clone, Debug)]
+pub struct MessageRevision {
+    pub message: String,
+    pub timestamp: u64,
 }
 
 impl Ord for Message {
@@ -27,7 +311,7 @@ impl Ord for Message {
 
 impl PartialOrd for Message {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.cmp(&other))
+        Some(self.cmp(other))
     }
 }
 
@@ -35,4 +319,585 @@ impl Message {
     pub fn update_timestamp(&mut self) {
         self.timestamp = super::chat_service::timestamp();
     }
+
+    pub fn is_deleted(&self) -> bool {
+        self.deleted_at.is_some()
+    }
+
+    pub fn is_system(&self) -> bool {
+        !self.kind.is_text()
+    }
+
+    /// Bytes of text charged to the sender's storage quota - the message and its edit history.
+    /// System messages and bot replies aren't charged.
+    pub fn stored_bytes(&self) -> u64 {
+        if self.is_system() {
+            return 0;
+        }
+        let history = self.history.iter().map(|r| r.message.len()).sum::<usize>();
+        (self.message.len() + history) as u64
+    }
+
+    /// Removes a user from the message. If they sent it, it becomes an anonymous tombstone and
+    /// true is returned.
+    pub fn erase_user(&mut self, user_id: u64, now: u64) -> bool {
+        self.delivery.remove(&user_id);
+        for user_ids in self.reactions.values_mut() {
+            user_ids.remove(&user_id);
+        }
+        self.reactions.retain(|_, user_ids| !user_ids.is_empty());
+        self.mentions.retain(|id| *id != user_id);
+        if self.destination_user_id == user_id {
+            self.destination_user_id = ERASED_USER_ID;
+        }
+        if self.source_user_id != user_id {
+            return false;
+        }
+        self.erase(now);
+        true
+    }
+
+    /// Empties the message out into an anonymous tombstone
+    pub fn erase(&mut self, now: u64) {
+        self.source_user_id = ERASED_USER_ID;
+        self.message.clear();
+        self.history.clear();
+        self.attachments.clear();
+        self.edited_at = None;
+        self.deleted_at = Some(self.deleted_at.unwrap_or(now));
+    }
+}
+
+impl ChatEventKind {
+    /// Ephemeral events only matter for a short while, so aren't kept in a chat's history
+    pub fn is_ephemeral(&self) -> bool {
+        matches!(
+            self,
+            ChatEventKind::Typing { .. } | ChatEventKind::Presence { .. }
+        )
+    }
+
+    /// The message an event is about, if any
+    pub fn message_id(&self) -> Option<&str> {
+        match self {
+            ChatEventKind::MessageSent { message }
+            | ChatEventKind::MessageEdited { message }
+            | ChatEventKind::MessageDeleted { message } => Some(&message.id),
+            ChatEventKind::MessageDelivered { message_id, .. }
+            | ChatEventKind::ReactionAdded { message_id, .. }
+            | ChatEventKind::ReactionRemoved { message_id, .. }
+            | ChatEventKind::MessagePinned { message_id, .. }
+            | ChatEventKind::MessageUnpinned { message_id, .. } => Some(message_id),
+            _ => None,
+        }
+    }
+
+    /// The user who did what an event describes, for events that aren't about a message
+    /// they sent
+    pub fn user_id(&self) -> Option<u64> {
+        match self {
+            ChatEventKind::MessageDelivered { user_id, .. }
+            | ChatEventKind::ReactionAdded { user_id, .. }
+            | ChatEventKind::ReactionRemoved { user_id, .. }
+            | ChatEventKind::MessagePinned { user_id, .. }
+            | ChatEventKind::MessageUnpinned { user_id, .. }
+            | ChatEventKind::Typing { user_id, .. }
+            | ChatEventKind::Presence { user_id, .. }
+            | ChatEventKind::MessagesRead { user_id, .. }
+            | ChatEventKind::ScheduledMessageFailed { user_id, .. } => Some(*user_id),
+            _ => None,
+        }
+    }
+
+    /// The message embedded in an event, if it carries a copy of one
+    pub fn message_mut(&mut self) -> Option<&mut Message> {
+        match self {
+            ChatEventKind::MessageSent { message }
+            | ChatEventKind::MessageEdited { message }
+            | ChatEventKind::MessageDeleted { message } => Some(message),
+            _ => None,
+        }
+    }
+}
+
+/// Body of `PATCH /chats/:chatId/messages/:messageId`
+#[derive(Deserialize)]
+pub struct MessageEdit {
+    #[serde(rename = "userId")]
+    pub user_id: u64,
+    pub message: String,
+}
+
+/// Body of `POST /chats/:chatId/messages/:messageId/ack`
+#[derive(Deserialize)]
+pub struct DeliveryAck {
+    #[serde(rename = "userId")]
+    pub user_id: u64,
+}
+
+/// Body of `POST /chats/:chatId/messages/:messageId/reactions`
+#[derive(Deserialize)]
+pub struct ReactionRequest {
+    #[serde(rename = "userId")]
+    pub user_id: u64,
+    pub emoji: String,
+}
+
+/// Body of `POST /chats/:chatId/typing` - `typing` defaults to true
+#[derive(Deserialize)]
+pub struct TypingRequest {
+    #[serde(rename = "userId")]
+    pub user_id: u64,
+    #[serde(default)]
+    pub typing: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PresenceStatus {
+    #[serde(rename = "online")]
+    Online,
+    #[serde(rename = "offline")]
+    Offline,
+}
+
+/// Response of `GET /users/:userId/presence`
+#[derive(Serialize)]
+pub struct UserPresence {
+    #[serde(rename = "userId")]
+    pub user_id: u64,
+    pub status: PresenceStatus,
+    #[serde(rename = "lastSeen")]
+    pub last_seen: Option<u64>,
+    /// Chats the user is typing in right now
+    #[serde(rename = "typingIn")]
+    pub typing_in: Vec<u64>,
+}
+
+/// Body of `POST /users/:userId/blocks`
+#[derive(Deserialize)]
+pub struct BlockRequest {
+    #[serde(rename = "blockedUserId")]
+    pub blocked_user_id: u64,
+}
+
+/// Body of `POST /chats/:chatId/retention`
+#[derive(Deserialize)]
+pub struct RetentionRequest {
+    #[serde(rename = "userId")]
+    pub user_id: u64,
+    #[serde(flatten)]
+    pub policy: RetentionPolicy,
+}
+
+/// Body of `POST /chats/:chatId/read` - without a message id everything in the chat is read
+#[derive(Deserialize)]
+pub struct ReadReceipt {
+    #[serde(rename = "userId")]
+    pub user_id: u64,
+    #[serde(rename = "messageId", default)]
+    pub message_id: Option<String>,
+}
+
+/// How one participant has organized a chat in their chat list
+#[derive(Serialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ChatSettings {
+    /// No notifications until this time
+    #[serde(rename = "mutedUntil", skip_serializing_if = "Option::is_none")]
+    pub muted_until: Option<u64>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub archived: bool,
+    /// Position among the user's pinned chats, lowest first
+    #[serde(rename = "pinOrder", skip_serializing_if = "Option::is_none")]
+    pub pin_order: Option<u64>,
+}
+
+impl ChatSettings {
+    pub fn is_muted(&self, now: u64) -> bool {
+        self.muted_until
+            .is_some_and(|muted_until| muted_until > now)
+    }
+}
+
+/// Body of `PATCH /chats/:chatId/settings` - fields left out are unchanged, and `null` clears
+/// `mutedUntil` or `pinOrder`
+#[derive(Deserialize)]
+pub struct ChatSettingsUpdate {
+    #[serde(rename = "userId")]
+    pub user_id: u64,
+    #[serde(rename = "mutedUntil", default, deserialize_with = "present")]
+    pub muted_until: Option<Option<u64>>,
+    #[serde(default)]
+    pub archived: Option<bool>,
+    #[serde(rename = "pinOrder", default, deserialize_with = "present")]
+    pub pin_order: Option<Option<u64>>,
+}
+
+/// Tells a field set to `null` apart from one that was left out, which stays `None`
+fn present<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+/// A chat as listed for one of its participants
+#[derive(Serialize)]
+pub struct ChatSummary<'a> {
+    #[serde(flatten)]
+    pub chat: &'a Chat,
+    #[serde(flatten)]
+    pub settings: &'a ChatSettings,
+    #[serde(rename = "unreadCount")]
+    pub unread_count: usize,
+    /// Timestamp of the latest message each participant has read
+    #[serde(rename = "readCursors")]
+    pub read_cursors: &'a HashMap<u64, u64>,
+}
+
+/// Everything held about a user, as returned by `GET /admin/users/:userId/export`
+#[derive(Serialize)]
+pub struct UserExport<'a> {
+    #[serde(rename = "userId")]
+    pub user_id: u64,
+    #[serde(rename = "exportedAt")]
+    pub exported_at: u64,
+    pub contacts: &'a [u64],
+    /// Users they have blocked
+    pub blocked: Vec<u64>,
+    pub chats: Vec<ChatExport<'a>>,
+    /// Messages they have scheduled that haven't been sent yet
+    pub scheduled: Vec<&'a ScheduledMessage>,
+    /// Files the user uploaded during this run of the server
+    pub attachments: Vec<Attachment>,
+}
+
+/// A chat the user takes part in, with every message in it
+#[derive(Serialize)]
+pub struct ChatExport<'a> {
+    #[serde(flatten)]
+    pub chat: &'a Chat,
+    #[serde(flatten)]
+    pub settings: Option<&'a ChatSettings>,
+    #[serde(rename = "readUpTo", skip_serializing_if = "Option::is_none")]
+    pub read_up_to: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub draft: Option<&'a Draft>,
+    pub messages: &'a [Message],
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuditAction {
+    #[serde(rename = "export")]
+    Export,
+    #[serde(rename = "erase")]
+    Erase,
+}
+
+/// A record of an administrator reading or erasing a user's data
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct AuditRecord {
+    pub id: u64,
+    pub timestamp: u64,
+    pub action: AuditAction,
+    #[serde(rename = "userId")]
+    pub user_id: u64,
+    /// Chats the user took part in
+    pub chats: usize,
+    /// Messages exported, or messages the user sent that were erased
+    pub messages: usize,
+    /// Contact list entries exported or erased
+    pub contacts: usize,
+}
+
+/// A message pinned to the top of a chat
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Pin {
+    #[serde(rename = "messageId")]
+    pub message_id: String,
+    #[serde(rename = "pinnedBy")]
+    pub pinned_by: u64,
+    #[serde(rename = "pinnedAt")]
+    pub pinned_at: u64,
+}
+
+/// A pin along with the message it points at, as listed by `GET /chats/:chatId/pins`
+#[derive(Serialize)]
+pub struct PinnedMessage<'a> {
+    #[serde(flatten)]
+    pub pin: &'a Pin,
+    pub message: &'a Message,
+}
+
+/// Body of `POST /chats/:chatId/messages/:messageId/pin`
+#[derive(Deserialize)]
+pub struct PinRequest {
+    #[serde(rename = "userId")]
+    pub user_id: u64,
+}
+
+/// Text a user has started writing in a chat but not sent yet, shared between their devices.
+/// Body of `PUT /chats/:chatId/draft`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Draft {
+    pub message: String,
+    /// Message the draft will reply to
+    #[serde(rename = "replyTo", default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<String>,
+    /// When the server last saved the draft. Any value a client sends is replaced.
+    #[serde(rename = "updatedAt", default)]
+    pub updated_at: u64,
+}
+
+/// A message matching a search, with an excerpt of the text around the match
+#[derive(Serialize)]
+pub struct SearchResult {
+    #[serde(rename = "chatId")]
+    pub chat_id: u64,
+    pub score: f64,
+    pub context: String,
+    pub message: Message,
+}
+
+/// A message mentioning the user, as listed by `GET /users/:userId/mentions`
+#[derive(Serialize)]
+pub struct MentionResult {
+    #[serde(rename = "chatId")]
+    pub chat_id: u64,
+    pub message: Message,
+}
+
+/// Data sent to webhooks subscribed to `messageSent`
+#[derive(Serialize)]
+pub struct MessageSentData<'a> {
+    #[serde(rename = "chatId")]
+    pub chat_id: u64,
+    pub message: &'a Message,
+}
+
+/// Something that happened in a chat, in the order it happened
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ChatEvent {
+    pub seq: u64,
+    pub timestamp: u64,
+    #[serde(flatten)]
+    pub kind: ChatEventKind,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type")]
+pub enum ChatEventKind {
+    #[serde(rename = "messageSent")]
+    MessageSent {
+        #[serde(deserialize_with = "deserialize_stored")]
+        message: Message,
+    },
+    #[serde(rename = "messageEdited")]
+    MessageEdited {
+        #[serde(deserialize_with = "deserialize_stored")]
+        message: Message,
+    },
+    #[serde(rename = "messageDeleted")]
+    MessageDeleted {
+        #[serde(deserialize_with = "deserialize_stored")]
+        message: Message,
+    },
+    /// A recipient's device acknowledged receiving a message
+    #[serde(rename = "messageDelivered")]
+    MessageDelivered {
+        #[serde(rename = "messageId")]
+        message_id: String,
+        #[serde(rename = "userId")]
+        user_id: u64,
+    },
+    #[serde(rename = "reactionAdded")]
+    ReactionAdded {
+        #[serde(rename = "messageId")]
+        message_id: String,
+        #[serde(rename = "userId")]
+        user_id: u64,
+        emoji: String,
+    },
+    #[serde(rename = "reactionRemoved")]
+    ReactionRemoved {
+        #[serde(rename = "messageId")]
+        message_id: String,
+        #[serde(rename = "userId")]
+        user_id: u64,
+        emoji: String,
+    },
+    #[serde(rename = "messagePinned")]
+    MessagePinned {
+        #[serde(rename = "messageId")]
+        message_id: String,
+        #[serde(rename = "userId")]
+        user_id: u64,
+    },
+    #[serde(rename = "messageUnpinned")]
+    MessageUnpinned {
+        #[serde(rename = "messageId")]
+        message_id: String,
+        #[serde(rename = "userId")]
+        user_id: u64,
+    },
+    /// A participant started or stopped typing - the indicator lasts until `expiresAt`
+    #[serde(rename = "typing")]
+    Typing {
+        #[serde(rename = "userId")]
+        user_id: u64,
+        typing: bool,
+        #[serde(rename = "expiresAt", skip_serializing_if = "Option::is_none")]
+        expires_at: Option<u64>,
+    },
+    /// A participant came online or went offline
+    #[serde(rename = "presence")]
+    Presence {
+        #[serde(rename = "userId")]
+        user_id: u64,
+        status: PresenceStatus,
+        #[serde(rename = "lastSeen")]
+        last_seen: Option<u64>,
+    },
+    /// Messages were removed by the chat's retention policy or their own time to live. Earlier
+    /// events about them are dropped too.
+    #[serde(rename = "messagesExpired")]
+    MessagesExpired {
+        #[serde(rename = "messageIds")]
+        message_ids: Vec<String>,
+    },
+    /// A participant's read cursor moved forward
+    #[serde(rename = "messagesRead")]
+    MessagesRead {
+        #[serde(rename = "userId")]
+        user_id: u64,
+        #[serde(rename = "readUpTo")]
+        read_up_to: u64,
+    },
+    /// One of the user's scheduled messages couldn't be sent when it was due, so was dropped
+    #[serde(rename = "scheduledMessageFailed")]
+    ScheduledMessageFailed {
+        #[serde(rename = "messageId")]
+        message_id: String,
+        #[serde(rename = "userId")]
+        user_id: u64,
+        reason: String,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_message_kinds() {
+        let kinds = vec![
+            (
+                MessageKind::ChatCreated {
+                    title: Some("Lunch".to_owned()),
+                },
+                json!({"type": "chatCreated", "title": "Lunch"}),
+                "created the chat \"Lunch\"",
+            ),
+            (
+                MessageKind::ChatCreated { title: None },
+                json!({"type": "chatCreated", "title": null}),
+                "created the chat",
+            ),
+            (
+                MessageKind::TitleChanged {
+                    title: Some("Dinner".to_owned()),
+                },
+                json!({"type": "titleChanged", "title": "Dinner"}),
+                "changed the title to \"Dinner\"",
+            ),
+            (
+                MessageKind::TitleChanged { title: None },
+                json!({"type": "titleChanged", "title": null}),
+                "removed the title",
+            ),
+            (
+                MessageKind::DescriptionChanged {
+                    description: Some("Friday".to_owned()),
+                },
+                json!({"type": "descriptionChanged", "description": "Friday"}),
+                "changed the description",
+            ),
+            (
+                MessageKind::DescriptionChanged { description: None },
+                json!({"type": "descriptionChanged", "description": null}),
+                "removed the description",
+            ),
+            (
+                MessageKind::AvatarChanged {
+                    avatar: Some("ab12".to_owned()),
+                },
+                json!({"type": "avatarChanged", "avatar": "ab12"}),
+                "changed the avatar",
+            ),
+            (
+                MessageKind::AvatarChanged { avatar: None },
+                json!({"type": "avatarChanged", "avatar": null}),
+                "removed the avatar",
+            ),
+            (
+                MessageKind::MessageDeleted {
+                    message_id: "m1".to_owned(),
+                },
+                json!({"type": "messageDeleted", "messageId": "m1"}),
+                "deleted a message",
+            ),
+            (
+                MessageKind::MessageRemoved {
+                    message_id: "m1".to_owned(),
+                },
+                json!({"type": "messageRemoved", "messageId": "m1"}),
+                "a message was removed by a moderator",
+            ),
+            (
+                MessageKind::BotReply {
+                    bot: "echo".to_owned(),
+                },
+                json!({"type": "botReply", "bot": "echo"}),
+                "",
+            ),
+        ];
+        for (kind, expected, text) in kinds {
+            assert_eq!(kind.describe(), text);
+            let message = Message {
+                kind,
+                ..Message::default()
+            };
+            assert!(message.is_system());
+            assert_eq!(message.stored_bytes(), 0);
+            assert_eq!(serde_json::to_value(&message).unwrap()["kind"], expected);
+        }
+    }
+
+    #[test]
+    fn test_text_messages() {
+        let message = Message {
+            message: "hi".to_owned(),
+            ..Message::default()
+        };
+        assert!(!message.is_system());
+        assert_eq!(message.stored_bytes(), 2);
+        assert!(serde_json::to_value(&message)
+            .unwrap()
+            .get("kind")
+            .is_none());
+
+        // clients can't pass their messages off as system events
+        let forged = json!({
+            "id": "m1",
+            "sourceUserId": 1,
+            "destinationUserId": 2,
+            "timestamp": 0,
+            "message": "created the chat",
+            "kind": {"type": "chatCreated", "title": null},
+        });
+        let forged = serde_json::from_value::<Message>(forged).unwrap();
+        assert_eq!(forged.kind, MessageKind::Text);
+    }
 }
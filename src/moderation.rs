@@ -0,0 +1,383 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use super::config::Config;
+use super::messages::Message;
+
+/// What a filter does with a message that trips it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FilterAction {
+    /// Refuse to send the message
+    Reject,
+    /// Send the message with the offending text masked out
+    Redact,
+    /// Send the message as it is, and queue it for an admin to review
+    Flag,
+}
+
+impl FromStr for FilterAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(FilterAction::Reject),
+            "redact" => Ok(FilterAction::Redact),
+            "flag" => Ok(FilterAction::Flag),
+            _ => Err(format!("unknown filter action {:?}", s)),
+        }
+    }
+}
+
+/// What a filter decided about a message's text
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Pass,
+    /// Refuse the message, saying why
+    Reject(String),
+    /// Send this text instead
+    Redact(String),
+    /// Send the message but queue it for review, saying why
+    Flag(String),
+}
+
+impl Verdict {
+    fn new<F: FnOnce() -> String>(action: FilterAction, reason: String, redact: F) -> Verdict {
+        match action {
+            FilterAction::Reject => Verdict::Reject(reason),
+            FilterAction::Redact => Verdict::Redact(redact()),
+            FilterAction::Flag => Verdict::Flag(reason),
+        }
+    }
+}
+
+/// Checks the text of messages before they are sent
+pub trait MessageFilter {
+    fn check(&self, text: &str) -> Verdict;
+}
+
+/// Rejects messages longer than a number of characters
+pub struct MaxLength(pub usize);
+
+impl MessageFilter for MaxLength {
+    fn check(&self, text: &str) -> Verdict {
+        if text.chars().count() > self.0 {
+            Verdict::Reject(format!("message is longer than {} characters", self.0))
+        } else {
+            Verdict::Pass
+        }
+    }
+}
+
+/// Catches words from a list, ignoring case. Redacting masks each one with asterisks.
+pub struct WordList {
+    words: HashSet<String>,
+    action: FilterAction,
+}
+
+impl WordList {
+    pub fn new<I: IntoIterator<Item = String>>(words: I, action: FilterAction) -> Self {
+        WordList {
+            words: words.into_iter().map(|word| word.to_lowercase()).collect(),
+            action,
+        }
+    }
+
+    /// Reads a word list with one word per line. Blank lines and lines starting with `#` are
+    /// skipped, as are lines that aren't a single word - messages are only ever checked a word
+    /// at a time, so they could never match.
+    pub fn load(path: &Path, action: FilterAction) -> io::Result<Self> {
+        let mut words = Vec::new();
+        for (i, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if !line.chars().all(char::is_alphanumeric) {
+                eprintln!(
+                    "{}:{}: skipping {:?}, as only single words can be blocked",
+                    path.display(),
+                    i + 1,
+                    line
+                );
+                continue;
+            }
+            words.push(line.to_owned());
+        }
+        Ok(WordList::new(words, action))
+    }
+
+    fn is_blocked(&self, word: &str) -> bool {
+        self.words.contains(&word.to_lowercase())
+    }
+}
+
+impl MessageFilter for WordList {
+    fn check(&self, text: &str) -> Verdict {
+        if !words(text).any(|(_, word)| self.is_blocked(word)) {
+            return Verdict::Pass;
+        }
+        Verdict::new(self.action, "contains a blocked word".to_owned(), || {
+            let mut redacted = text.to_owned();
+            // replacing from the end keeps the earlier offsets valid
+            let blocked = words(text)
+                .filter(|(_, word)| self.is_blocked(word))
+                .collect::<Vec<_>>();
+            for (start, word) in blocked.into_iter().rev() {
+                let mask = "*".repeat(word.chars().count());
+                redacted.replace_range(start..start + word.len(), &mask);
+            }
+            redacted
+        })
+    }
+}
+
+/// Runs of letters and digits in the text, with their byte offsets
+fn words(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(move |word| (word.as_ptr() as usize - text.as_ptr() as usize, word))
+}
+
+/// Catches web links. Redacting replaces each one with `[link removed]`.
+pub struct Links(pub FilterAction);
+
+impl Links {
+    fn is_link(word: &str) -> bool {
+        let word = word.to_lowercase();
+        word.starts_with("http://") || word.starts_with("https://") || word.starts_with("www.")
+    }
+}
+
+impl MessageFilter for Links {
+    fn check(&self, text: &str) -> Verdict {
+        if !text.split_whitespace().any(Links::is_link) {
+            return Verdict::Pass;
+        }
+        Verdict::new(self.0, "contains a link".to_owned(), || {
+            text.split_inclusive(char::is_whitespace)
+                .map(|piece| {
+                    let word = piece.trim_end();
+                    if Links::is_link(word) {
+                        format!("[link removed]{}", &piece[word.len()..])
+                    } else {
+                        piece.to_owned()
+                    }
+                })
+                .collect()
+        })
+    }
+}
+
+/// Catches the same character typed over and over. Redacting shortens each run to the most
+/// allowed.
+pub struct RepeatedChars {
+    pub max_run: usize,
+    pub action: FilterAction,
+}
+
+impl MessageFilter for RepeatedChars {
+    fn check(&self, text: &str) -> Verdict {
+        let mut run = 0;
+        let mut previous = None;
+        let mut redacted = String::with_capacity(text.len());
+        let mut tripped = false;
+        for c in text.chars() {
+            run = if previous == Some(c) { run + 1 } else { 1 };
+            previous = Some(c);
+            if run > self.max_run {
+                tripped = true;
+            } else {
+                redacted.push(c);
+            }
+        }
+        if !tripped {
+            return Verdict::Pass;
+        }
+        let reason = format!("repeats a character more than {} times", self.max_run);
+        Verdict::new(self.action, reason, || redacted)
+    }
+}
+
+/// The filters every message goes through, in order
+#[derive(Default)]
+pub struct Filters {
+    filters: Vec<Box<dyn MessageFilter>>,
+}
+
+impl Filters {
+    /// The filters turned on in the config. Fails if the blocked words file can't be read.
+    pub fn from_config(config: &Config) -> io::Result<Self> {
+        let mut filters = Filters::default();
+        if let Some(max_length) = config.max_message_length {
+            filters.add(Box::new(MaxLength(max_length)));
+        }
+        if let Some(path) = &config.blocked_words_file {
+            let words = WordList::load(path, config.blocked_words_action).map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!(
+                        "unable to read blocked words file {}: {}",
+                        path.display(),
+                        e
+                    ),
+                )
+            })?;
+            filters.add(Box::new(words));
+        }
+        if let Some(action) = config.link_action {
+            filters.add(Box::new(Links(action)));
+        }
+        if let Some(max_run) = config.max_repeated_chars {
+            filters.add(Box::new(RepeatedChars {
+                max_run,
+                action: config.repeated_chars_action,
+            }));
+        }
+        Ok(filters)
+    }
+
+    pub fn add(&mut self, filter: Box<dyn MessageFilter>) {
+        self.filters.push(filter);
+    }
+
+    /// Runs the text through each filter in turn, each seeing the text as redacted by the ones
+    /// before it. Returns the reasons the message was flagged, or why it was rejected.
+    pub fn apply(&self, text: &mut String) -> Result<Vec<String>, String> {
+        let mut flags = Vec::new();
+        for filter in &self.filters {
+            match filter.check(text) {
+                Verdict::Pass => {}
+                Verdict::Reject(reason) => return Err(reason),
+                Verdict::Redact(redacted) => *text = redacted,
+                Verdict::Flag(reason) => flags.push(reason),
+            }
+        }
+        Ok(flags)
+    }
+}
+
+/// A sent message that a filter flagged for review
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct Flag {
+    pub id: u64,
+    #[serde(rename = "chatId")]
+    pub chat_id: u64,
+    #[serde(rename = "messageId")]
+    pub message_id: String,
+    pub reasons: Vec<String>,
+    #[serde(rename = "flaggedAt")]
+    pub flagged_at: u64,
+}
+
+/// A flag with the message as it is now, as listed by `GET /admin/flagged`
+#[derive(Serialize)]
+pub struct FlagReview<'a> {
+    #[serde(flatten)]
+    pub flag: &'a Flag,
+    pub message: &'a Message,
+}
+
+/// Body of `POST /admin/flagged/:flagId`
+#[derive(Deserialize)]
+pub struct ReviewDecision {
+    pub action: ReviewAction,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReviewAction {
+    /// Leave the message be
+    #[serde(rename = "approve")]
+    Approve,
+    /// Delete the message
+    #[serde(rename = "remove")]
+    Remove,
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_filters() {
+        let words = WordList::new(vec!["darn".to_owned()], FilterAction::Redact);
+        assert_eq!(
+            words.check("Darn it, darnation, DARN!"),
+            Verdict::Redact("**** it, darnation, ****!".to_owned())
+        );
+        assert_eq!(words.check("fine"), Verdict::Pass);
+
+        let links = Links(FilterAction::Redact);
+        assert_eq!(
+            links.check("see https://example.com\tor WWW.example.com"),
+            Verdict::Redact("see [link removed]\tor [link removed]".to_owned())
+        );
+        assert_eq!(
+            Links(FilterAction::Reject).check("http://x"),
+            Verdict::Reject("contains a link".to_owned())
+        );
+
+        let repeated = RepeatedChars {
+            max_run: 3,
+            action: FilterAction::Redact,
+        };
+        assert_eq!(
+            repeated.check("nooooo!!!!"),
+            Verdict::Redact("nooo!!!".to_owned())
+        );
+        assert_eq!(repeated.check("good"), Verdict::Pass);
+        assert_eq!(MaxLength(3).check("ééé"), Verdict::Pass);
+        assert!(matches!(MaxLength(3).check("four"), Verdict::Reject(_)));
+    }
+
+    #[test]
+    fn test_chain() {
+        let mut filters = Filters::default();
+        filters.add(Box::new(WordList::new(
+            vec!["spam".to_owned()],
+            FilterAction::Flag,
+        )));
+        filters.add(Box::new(Links(FilterAction::Redact)));
+        filters.add(Box::new(MaxLength(22)));
+
+        let mut text = "spam at www.spam.com".to_owned();
+        assert_eq!(
+            filters.apply(&mut text),
+            Ok(vec!["contains a blocked word".to_owned()])
+        );
+        assert_eq!(text, "spam at [link removed]");
+        // the length limit sees the redacted text
+        let mut text = "go to https://example.com/a/long/path".to_owned();
+        assert_eq!(filters.apply(&mut text), Ok(vec![]));
+        let mut text = "this one is much too long".to_owned();
+        assert!(filters.apply(&mut text).is_err());
+    }
+
+    #[test]
+    fn test_load_word_list() {
+        let path = std::env::temp_dir().join(format!("words-{}.txt", std::process::id()));
+        fs::write(
+            &path,
+            "# words to mask\n\n  Heck \nflip\nwhat the\nf-word\n",
+        )
+        .unwrap();
+        let words = WordList::load(&path, FilterAction::Reject).unwrap();
+        fs::remove_file(&path).unwrap();
+        // lines that aren't a single word are skipped
+        let expected = ["heck", "flip"].iter().map(|w| w.to_string()).collect();
+        assert_eq!(words.words, expected);
+        assert!(WordList::load(&path, FilterAction::Reject).is_err());
+
+        let config = Config {
+            blocked_words_file: Some(path.clone()),
+            ..Config::default()
+        };
+        let error = Filters::from_config(&config).err().unwrap();
+        assert!(error.to_string().contains(&path.display().to_string()));
+    }
+}
@@ -1,8 +1,9 @@
 use nom::{alt, do_parse, eat_separator, named, tag, take_until};
 
 use std::error::Error;
+use std::fmt;
 
-named!(pub space<&str, &str>, eat_separator!(&" \t"[..]));
+named!(pub space<&str, &str>, eat_separator!(" \t"));
 
 named!(get<&str, http::Method>,
     do_parse!(
@@ -18,7 +19,28 @@ named!(post<&str, http::Method>,
     )
 );
 
-named!(method<&str, http::Method>, alt!(get|post));
+named!(put<&str, http::Method>,
+    do_parse!(
+        tag!("PUT") >>
+        ( http::Method::PUT )
+    )
+);
+
+named!(patch<&str, http::Method>,
+    do_parse!(
+        tag!("PATCH") >>
+        ( http::Method::PATCH )
+    )
+);
+
+named!(delete<&str, http::Method>,
+    do_parse!(
+        tag!("DELETE") >>
+        ( http::Method::DELETE )
+    )
+);
+
+named!(method<&str, http::Method>, alt!(get|post|put|patch|delete));
 
 named!(uri<&str, &str>,
     do_parse!(
@@ -62,20 +84,37 @@ named!(pub end_headers<&str, &str>,
     tag!("\r\n")
 );
 
+/// A request borrowing its body from the read buffer
+pub type Request<'a> = http::Request<&'a [u8]>;
+
+/// Returned when a buffer ends part way through a request
+#[derive(Debug)]
+pub struct Incomplete;
+
+impl fmt::Display for Incomplete {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "incomplete request")
+    }
+}
+
+impl Error for Incomplete {}
+
 ///
-/// Parse a buffer of (potentially) multiple pipelined http requests
+/// Parse every complete request at the start of a buffer, returning them along with the
+/// number of bytes they took up. Anything after that is the start of a request still arriving.
 ///
-pub fn parse_buffer(buffer: &[u8]) -> Result<Vec<http::Request<&str>>, Box<dyn Error>> {
-    let buffer_str = std::str::from_utf8(buffer)?;
+pub fn parse_pipelined(buffer: &[u8]) -> Result<(Vec<Request<'_>>, usize), Box<dyn Error>> {
     let mut requests = Vec::new();
-    let mut temp_buffer = buffer_str;
+    let mut temp_buffer = buffer;
     while !temp_buffer.is_empty() {
         let (remaining, request) = match parse_http_request(temp_buffer) {
             Ok(item) => item,
+            Err(ref e) if e.is::<Incomplete>() => break,
             Err(e) => {
                 eprintln!(
                     "error parsing: {:?}\ntemp_buffer:\n[[[{}]]]",
-                    e, temp_buffer
+                    e,
+                    String::from_utf8_lossy(temp_buffer)
                 );
                 return Err(e);
             }
@@ -83,16 +122,20 @@ pub fn parse_buffer(buffer: &[u8]) -> Result<Vec<http::Request<&str>>, Box<dyn E
         requests.push(request);
         temp_buffer = remaining;
     }
-    Ok(requests)
+    Ok((requests, buffer.len() - temp_buffer.len()))
 }
 
 ///
 /// Parse a single http request from a buffer, returning the remainder of the buffer once
 /// Content-Length is reached
 ///
-pub fn parse_http_request(buffer: &str) -> Result<(&str, http::Request<&str>), Box<dyn Error>> {
+pub fn parse_http_request(buffer: &[u8]) -> Result<(&[u8], Request<'_>), Box<dyn Error>> {
+    let head_len = match buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(end) => end + 4,
+        None => return Err(Box::new(Incomplete)),
+    };
     let mut builder = http::Request::builder();
-    let mut temp_buffer = buffer;
+    let mut temp_buffer = std::str::from_utf8(&buffer[..head_len])?;
     temp_buffer = match start_line(temp_buffer) {
         Ok((remainder, (method, uri, version))) => {
             builder.method(method).uri(uri).version(version);
@@ -103,22 +146,22 @@ pub fn parse_http_request(buffer: &str) -> Result<(&str, http::Request<&str>), B
 
     let mut len = 0;
     while let Ok((remainder, (header, value))) = header(temp_buffer) {
-        if header.trim() == "Content-Length" {
+        if header.trim().eq_ignore_ascii_case("Content-Length") {
             if let Ok(l) = value.parse::<usize>() {
                 len = l;
             }
         }
         builder.header(header, value);
         temp_buffer = remainder;
-        if let Ok((remainder, _)) = end_headers(remainder) {
-            temp_buffer = remainder;
+        if end_headers(remainder).is_ok() {
             break;
         }
     }
-    if temp_buffer.len() >= len {
-        Ok((&temp_buffer[len..], builder.body(&temp_buffer[..len])?))
+    let body = &buffer[head_len..];
+    if body.len() >= len {
+        Ok((&body[len..], builder.body(&body[..len])?))
     } else {
-        Err("incomplete request".into())
+        Err(Box::new(Incomplete))
     }
 }
 
@@ -130,8 +173,8 @@ mod tests {
     #[test]
     fn test_single_request() {
         let requests =
-            parse_buffer(
-            b"GET /something/neat/here/1 HTTP/1.1\r\nUser-Agent: Wget/1.20.1 (linux-gnu)\r\nAccept: */*\r\n Accept-Encoding: identity\r\n Host: localhost:8080\r\nConnection: Keep-Alive\r\nContent-Length: 16\r\n\r\n{'kinda':'json'}").unwrap();
+            parse_pipelined(
+            b"GET /something/neat/here/1 HTTP/1.1\r\nUser-Agent: Wget/1.20.1 (linux-gnu)\r\nAccept: */*\r\n Accept-Encoding: identity\r\n Host: localhost:8080\r\nConnection: Keep-Alive\r\nContent-Length: 16\r\n\r\n{'kinda':'json'}").unwrap().0;
 
         assert_eq!(requests.len(), 1);
     }
@@ -139,24 +182,25 @@ mod tests {
     #[test]
     fn test_pipelined_requests_with_body() {
         let requests =
-            parse_buffer(
-            b"GET /something/neat/here/1 HTTP/1.1\r\nUser-Agent: Wget/1.20.1 (linux-gnu)\r\nAccept: */*\r\n Accept-Encoding: identity\r\n Host: localhost:8080\r\nConnection: Keep-Alive\r\nContent-Length: 15\r\n\r\n{'an':'object'}GET /something/neat/here/1 HTTP/1.1\r\nUser-Agent: Wget/1.20.1 (linux-gnu)\r\nAccept: */*\r\n Accept-Encoding: identity\r\n Host: localhost:8080\r\nConnection: Keep-Alive\r\nContent-Length: 15\r\n\r\n{'an':'object'}").unwrap();
+            parse_pipelined(
+            b"GET /something/neat/here/1 HTTP/1.1\r\nUser-Agent: Wget/1.20.1 (linux-gnu)\r\nAccept: */*\r\n Accept-Encoding: identity\r\n Host: localhost:8080\r\nConnection: Keep-Alive\r\nContent-Length: 15\r\n\r\n{'an':'object'}GET /something/neat/here/1 HTTP/1.1\r\nUser-Agent: Wget/1.20.1 (linux-gnu)\r\nAccept: */*\r\n Accept-Encoding: identity\r\n Host: localhost:8080\r\nConnection: Keep-Alive\r\nContent-Length: 15\r\n\r\n{'an':'object'}").unwrap().0;
 
         assert_eq!(requests.len(), 2);
     }
 
     #[test]
     fn test_parse_pipelined_no_body() {
-        let req =
-            parse_buffer(b"GET /something/here HTTP/1.1\r\nUser-Agent: something\r\n\r\nGET /something/here HTTP/1.1\r\nUser-Agent: something\r\n\r\n")
-                .unwrap();
+        let buffer = b"GET /something/here HTTP/1.1\r\nUser-Agent: something\r\n\r\nGET /something/here HTTP/1.1\r\nUser-Agent: something\r\n\r\n";
+        let (requests, consumed) = parse_pipelined(buffer).unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(consumed, buffer.len());
     }
 
     #[test]
     fn test_bad_content_length() {
         let r =
             parse_http_request(
-            "GET /something/neat/here/1 HTTP/1.1\r\nUser-Agent: Wget/1.20.1 (linux-gnu)\r\nAccept: */*\r\n Accept-Encoding: identity\r\n Host: localhost:8080\r\nConnection: Keep-Alive\r\nContent-Length: 9000\r\n\r\nthis was a body...");
+            b"GET /something/neat/here/1 HTTP/1.1\r\nUser-Agent: Wget/1.20.1 (linux-gnu)\r\nAccept: */*\r\n Accept-Encoding: identity\r\n Host: localhost:8080\r\nConnection: Keep-Alive\r\nContent-Length: 9000\r\n\r\nthis was a body...");
         assert!(r.is_err());
     }
 
@@ -164,7 +208,7 @@ mod tests {
     fn test_parse_real() {
         let (_, req) =
             parse_http_request(
-            "GET /something/neat/here/1 HTTP/1.1\r\nUser-Agent: Wget/1.20.1 (linux-gnu)\r\nAccept: */*\r\n Accept-Encoding: identity\r\n Host: localhost:8080\r\nConnection: Keep-Alive\r\nContent-Length: 18\r\n\r\nthis was a body... ")
+            b"GET /something/neat/here/1 HTTP/1.1\r\nUser-Agent: Wget/1.20.1 (linux-gnu)\r\nAccept: */*\r\n Accept-Encoding: identity\r\n Host: localhost:8080\r\nConnection: Keep-Alive\r\nContent-Length: 18\r\n\r\nthis was a body... ")
                 .unwrap();
         assert_eq!(req.uri(), "/something/neat/here/1");
         assert_eq!(req.method(), http::Method::GET);
@@ -180,14 +224,14 @@ mod tests {
                 .parse::<usize>()
                 .unwrap()
         );
-        assert_eq!(*req.body(), "this was a body...");
+        assert_eq!(*req.body(), b"this was a body...");
         assert_eq!(ua, Some(&expected));
     }
 
     #[test]
     fn test_parse_request() {
         let (_, req) =
-            parse_http_request("GET /something/here HTTP/1.1\r\nUser-Agent: something\r\n\r\n")
+            parse_http_request(b"GET /something/here HTTP/1.1\r\nUser-Agent: something\r\n\r\n")
                 .unwrap();
         assert_eq!(req.uri(), "/something/here");
         assert_eq!(req.method(), http::Method::GET);
@@ -196,6 +240,22 @@ mod tests {
         assert_eq!(ua, Some(&expected));
     }
 
+    #[test]
+    fn test_partial_and_binary_requests() {
+        let mut buffer =
+            b"POST /attachments HTTP/1.1\r\nContent-Length: 4\r\n\r\n\xff\x00\xfe\x01".to_vec();
+        buffer.extend_from_slice(b"GET /chats HTTP/1.1\r\nContent-");
+
+        let (requests, consumed) = parse_pipelined(&buffer).unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(*requests[0].body(), b"\xff\x00\xfe\x01");
+        assert_eq!(&buffer[consumed..], b"GET /chats HTTP/1.1\r\nContent-");
+
+        let (requests, consumed) = parse_pipelined(&buffer[consumed..]).unwrap();
+        assert!(requests.is_empty());
+        assert_eq!(consumed, 0);
+    }
+
     #[test]
     fn test_header() {
         let h = header("User-Agent:Wget\r\n");
@@ -232,6 +292,12 @@ mod tests {
         assert_eq!(parsed, Ok(("", http::Method::GET)));
         let parsed = post("POST");
         assert_eq!(parsed, Ok(("", http::Method::POST)));
+        let parsed = put("PUT");
+        assert_eq!(parsed, Ok(("", http::Method::PUT)));
+        let parsed = patch("PATCH");
+        assert_eq!(parsed, Ok(("", http::Method::PATCH)));
+        let parsed = delete("DELETE");
+        assert_eq!(parsed, Ok(("", http::Method::DELETE)));
     }
 
     #[test]
@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+
+use super::messages::PresenceStatus;
+
+/// How long a typing indicator lasts unless the user keeps typing, in milliseconds
+pub const TYPING_TIMEOUT: u64 = 5_000;
+
+/// Who is connected, when each user was last seen and who is typing in which chat. None of
+/// this is persisted - it is rebuilt as clients reconnect.
+#[derive(Default)]
+pub struct Presence {
+    connections: HashMap<u64, usize>,
+    last_seen: HashMap<u64, u64>,
+    /// When each typing indicator runs out, by (chat id, user id)
+    typing: HashMap<(u64, u64), u64>,
+}
+
+impl Presence {
+    /// Counts a new connection for the user. Returns true if they have just come online.
+    pub fn connected(&mut self, user_id: u64, now: u64) -> bool {
+        self.last_seen.insert(user_id, now);
+        let connections = self.connections.entry(user_id).or_insert(0);
+        *connections += 1;
+        *connections == 1
+    }
+
+    /// Forgets one of the user's connections. Returns true if they have just gone offline.
+    pub fn disconnected(&mut self, user_id: u64, now: u64) -> bool {
+        self.last_seen.insert(user_id, now);
+        match self.connections.get_mut(&user_id) {
+            Some(connections) if *connections > 1 => {
+                *connections -= 1;
+                false
+            }
+            Some(_) => {
+                self.connections.remove(&user_id);
+                self.typing
+                    .retain(|(_, typing_user), _| *typing_user != user_id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Drops everything known about the user
+    pub fn forget(&mut self, user_id: u64) {
+        self.connections.remove(&user_id);
+        self.last_seen.remove(&user_id);
+        self.typing
+            .retain(|(_, typing_user), _| *typing_user != user_id);
+    }
+
+    pub fn seen(&mut self, user_id: u64, now: u64) {
+        self.last_seen.insert(user_id, now);
+    }
+
+    pub fn status(&self, user_id: u64) -> PresenceStatus {
+        if self.connections.contains_key(&user_id) {
+            PresenceStatus::Online
+        } else {
+            PresenceStatus::Offline
+        }
+    }
+
+    pub fn last_seen(&self, user_id: u64) -> Option<u64> {
+        self.last_seen.get(&user_id).cloned()
+    }
+
+    /// Starts or extends a typing indicator, returning when it will run out. The indicator is
+    /// only extended, and the second value true, when participants should be told about it -
+    /// when the user starts typing, or once the indicator is more than half way to running out.
+    pub fn start_typing(&mut self, chat_id: u64, user_id: u64, now: u64) -> (u64, bool) {
+        self.typing.retain(|_, expires_at| *expires_at > now);
+        self.last_seen.insert(user_id, now);
+        if let Some(expires_at) = self.typing.get(&(chat_id, user_id)) {
+            if *expires_at - now >= TYPING_TIMEOUT / 2 {
+                return (*expires_at, false);
+            }
+        }
+        let expires_at = now + TYPING_TIMEOUT;
+        self.typing.insert((chat_id, user_id), expires_at);
+        (expires_at, true)
+    }
+
+    /// Clears a typing indicator, returning true if one was showing
+    pub fn stop_typing(&mut self, chat_id: u64, user_id: u64, now: u64) -> bool {
+        match self.typing.remove(&(chat_id, user_id)) {
+            Some(expires_at) => expires_at > now,
+            None => false,
+        }
+    }
+
+    /// Chats the user is currently typing in
+    pub fn typing_in(&self, user_id: u64, now: u64) -> Vec<u64> {
+        let mut chats = self
+            .typing
+            .iter()
+            .filter(|((_, typing_user), expires_at)| *typing_user == user_id && **expires_at > now)
+            .map(|((chat_id, _), _)| *chat_id)
+            .collect::<Vec<_>>();
+        chats.sort_unstable();
+        chats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_connections() {
+        let mut presence = Presence::default();
+        assert_eq!(presence.status(1), PresenceStatus::Offline);
+        assert_eq!(presence.last_seen(1), None);
+
+        assert!(presence.connected(1, 10));
+        assert!(!presence.connected(1, 20));
+        assert_eq!(presence.status(1), PresenceStatus::Online);
+        assert!(!presence.disconnected(1, 30));
+        assert_eq!(presence.status(1), PresenceStatus::Online);
+        assert!(presence.disconnected(1, 40));
+        assert_eq!(presence.status(1), PresenceStatus::Offline);
+        assert_eq!(presence.last_seen(1), Some(40));
+        // a stray disconnect doesn't go negative
+        assert!(!presence.disconnected(1, 50));
+    }
+
+    #[test]
+    fn test_typing_expiry() {
+        let mut presence = Presence::default();
+        assert_eq!(presence.start_typing(7, 1, 0), (TYPING_TIMEOUT, true));
+        // refreshing straight away isn't worth telling anyone about
+        assert_eq!(presence.start_typing(7, 1, 1_000), (TYPING_TIMEOUT, false));
+        assert_eq!(
+            presence.start_typing(7, 1, 3_000),
+            (3_000 + TYPING_TIMEOUT, true)
+        );
+        assert_eq!(presence.typing_in(1, 4_000), vec![7]);
+        assert!(presence.typing_in(1, 3_000 + TYPING_TIMEOUT).is_empty());
+
+        presence.start_typing(8, 1, 10_000);
+        assert!(presence.stop_typing(8, 1, 11_000));
+        assert!(!presence.stop_typing(8, 1, 11_000));
+    }
+}
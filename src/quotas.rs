@@ -0,0 +1,246 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use serde::Serialize;
+
+/// Milliseconds in a day. Daily quotas reset at midnight UTC.
+pub const DAY: u64 = 24 * 60 * 60 * 1000;
+
+/// Limits on what each user may create, None for no limit
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Quotas {
+    /// Chats the user may create
+    pub chats: Option<u64>,
+    /// Messages the user may send each day
+    pub messages_per_day: Option<u64>,
+    /// Bytes of message text, edit history and attachments the user may have stored
+    pub stored_bytes: Option<u64>,
+}
+
+/// A quota a user ran into, with its limit
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Quota {
+    Chats(u64),
+    MessagesPerDay(u64),
+    StoredBytes(u64),
+}
+
+impl fmt::Display for Quota {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Quota::Chats(limit) => write!(f, "{} chat quota", limit),
+            Quota::MessagesPerDay(limit) => write!(f, "{} messages per day quota", limit),
+            Quota::StoredBytes(limit) => write!(f, "{} byte storage quota", limit),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Counters {
+    chats: u64,
+    /// The day `messages` counts, in days since the epoch
+    day: u64,
+    messages: u64,
+    /// Bytes of message text and edit history, without attachments
+    message_bytes: u64,
+}
+
+impl Counters {
+    fn messages_on(&self, day: u64) -> u64 {
+        if self.day == day {
+            self.messages
+        } else {
+            0
+        }
+    }
+}
+
+/// How much of one quota a user has used
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Allowance {
+    pub used: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u64>,
+}
+
+/// Body of `GET /users/:userId/usage`
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UsageReport {
+    #[serde(rename = "userId")]
+    pub user_id: u64,
+    pub chats: Allowance,
+    #[serde(rename = "messagesToday")]
+    pub messages_today: Allowance,
+    /// When `messagesToday` goes back to zero
+    #[serde(rename = "messagesResetAt")]
+    pub messages_reset_at: u64,
+    #[serde(rename = "storedBytes")]
+    pub stored_bytes: Allowance,
+}
+
+/// What each user has created, kept up to date as they go and checked against the quotas.
+/// Attachment bytes are counted by the blob store, so are passed in where needed.
+#[derive(Default)]
+pub struct Usage {
+    quotas: Quotas,
+    users: HashMap<u64, Counters>,
+}
+
+impl Usage {
+    pub fn new(quotas: Quotas) -> Self {
+        Usage {
+            quotas,
+            users: HashMap::new(),
+        }
+    }
+
+    fn counters(&self, user_id: u64) -> Counters {
+        self.users.get(&user_id).cloned().unwrap_or_default()
+    }
+
+    /// Checks the user may create another chat
+    pub fn check_chat(&self, user_id: u64) -> Result<(), Quota> {
+        match self.quotas.chats {
+            Some(limit) if self.counters(user_id).chats >= limit => Err(Quota::Chats(limit)),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn add_chat(&mut self, user_id: u64) {
+        self.users.entry(user_id).or_default().chats += 1;
+    }
+
+    /// Checks the user may store `bytes` more, on top of `attachment_bytes` already uploaded
+    pub fn check_bytes(
+        &self,
+        user_id: u64,
+        bytes: u64,
+        attachment_bytes: u64,
+    ) -> Result<(), Quota> {
+        let stored = self.counters(user_id).message_bytes + attachment_bytes;
+        match self.quotas.stored_bytes {
+            Some(limit) if stored + bytes > limit => Err(Quota::StoredBytes(limit)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks the user may send a message of `bytes` today
+    pub fn check_message(
+        &self,
+        user_id: u64,
+        bytes: u64,
+        attachment_bytes: u64,
+        now: u64,
+    ) -> Result<(), Quota> {
+        match self.quotas.messages_per_day {
+            Some(limit) if self.counters(user_id).messages_on(now / DAY) >= limit => {
+                Err(Quota::MessagesPerDay(limit))
+            }
+            _ => self.check_bytes(user_id, bytes, attachment_bytes),
+        }
+    }
+
+    pub fn add_message(&mut self, user_id: u64, bytes: u64, now: u64) {
+        let counters = self.users.entry(user_id).or_default();
+        counters.messages = counters.messages_on(now / DAY) + 1;
+        counters.day = now / DAY;
+        counters.message_bytes += bytes;
+    }
+
+    pub fn add_bytes(&mut self, user_id: u64, bytes: u64) {
+        self.users.entry(user_id).or_default().message_bytes += bytes;
+    }
+
+    /// Stops counting bytes the user no longer has stored
+    pub fn release_bytes(&mut self, user_id: u64, bytes: u64) {
+        if let Some(counters) = self.users.get_mut(&user_id) {
+            counters.message_bytes = counters.message_bytes.saturating_sub(bytes);
+        }
+    }
+
+    pub fn forget(&mut self, user_id: u64) {
+        self.users.remove(&user_id);
+    }
+
+    pub fn report(&self, user_id: u64, attachment_bytes: u64, now: u64) -> UsageReport {
+        let counters = self.counters(user_id);
+        UsageReport {
+            user_id,
+            chats: Allowance {
+                used: counters.chats,
+                limit: self.quotas.chats,
+            },
+            messages_today: Allowance {
+                used: counters.messages_on(now / DAY),
+                limit: self.quotas.messages_per_day,
+            },
+            messages_reset_at: (now / DAY + 1) * DAY,
+            stored_bytes: Allowance {
+                used: counters.message_bytes + attachment_bytes,
+                limit: self.quotas.stored_bytes,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_quotas() {
+        let mut usage = Usage::new(Quotas {
+            chats: Some(1),
+            messages_per_day: Some(2),
+            stored_bytes: Some(100),
+        });
+        let now = 10 * DAY + 5;
+
+        assert_eq!(usage.check_chat(1), Ok(()));
+        usage.add_chat(1);
+        assert_eq!(usage.check_chat(1), Err(Quota::Chats(1)));
+        assert_eq!(usage.check_chat(2), Ok(()));
+
+        usage.add_message(1, 30, now);
+        assert_eq!(
+            usage.check_message(1, 30, 50, now),
+            Err(Quota::StoredBytes(100))
+        );
+        usage.add_message(1, 30, now);
+        assert_eq!(
+            usage.check_message(1, 1, 0, now),
+            Err(Quota::MessagesPerDay(2))
+        );
+        // the daily count starts again at midnight, stored bytes don't
+        assert_eq!(usage.check_message(1, 1, 0, 11 * DAY), Ok(()));
+        assert_eq!(
+            usage.check_message(1, 41, 0, 11 * DAY),
+            Err(Quota::StoredBytes(100))
+        );
+        usage.release_bytes(1, 50);
+        assert_eq!(
+            usage.report(1, 15, now),
+            UsageReport {
+                user_id: 1,
+                chats: Allowance {
+                    used: 1,
+                    limit: Some(1)
+                },
+                messages_today: Allowance {
+                    used: 2,
+                    limit: Some(2)
+                },
+                messages_reset_at: 11 * DAY,
+                stored_bytes: Allowance {
+                    used: 25,
+                    limit: Some(100)
+                },
+            }
+        );
+        assert_eq!(usage.report(1, 0, 11 * DAY).messages_today.used, 0);
+
+        let unlimited = Usage::default();
+        assert_eq!(unlimited.check_message(1, u64::MAX / 2, 0, now), Ok(()));
+    }
+}
@@ -0,0 +1,116 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::hash::Hash;
+
+/// How often something may be done: `burst` times at once, then once every `interval`
+/// milliseconds as the allowance refills
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub interval: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Bucket {
+    tokens: u32,
+    /// When the last token was added
+    refilled_at: u64,
+}
+
+/// A token bucket for each key, each holding up to `burst` tokens and gaining one every
+/// `interval` milliseconds
+pub struct RateLimiter<K> {
+    limit: RateLimit,
+    buckets: HashMap<K, Bucket>,
+}
+
+impl<K: Hash + Eq> RateLimiter<K> {
+    pub fn new(limit: RateLimit) -> Self {
+        RateLimiter {
+            limit,
+            buckets: HashMap::new(),
+        }
+    }
+
+    fn refill(limit: RateLimit, bucket: &mut Bucket, now: u64) {
+        let added = now.saturating_sub(bucket.refilled_at) / limit.interval.max(1);
+        let tokens = cmp::min(u64::from(bucket.tokens) + added, u64::from(limit.burst));
+        bucket.tokens = tokens as u32;
+        bucket.refilled_at = if bucket.tokens == limit.burst {
+            now
+        } else {
+            bucket.refilled_at + added * limit.interval
+        };
+    }
+
+    /// Takes a token from the key's bucket, or returns how many milliseconds until it has one
+    pub fn check(&mut self, key: K, now: u64) -> Result<(), u64> {
+        let limit = self.limit;
+        let bucket = self.buckets.entry(key).or_insert(Bucket {
+            tokens: limit.burst,
+            refilled_at: now,
+        });
+        RateLimiter::<K>::refill(limit, bucket, now);
+        if bucket.tokens == 0 {
+            return Err((bucket.refilled_at + limit.interval).saturating_sub(now));
+        }
+        bucket.tokens -= 1;
+        Ok(())
+    }
+
+    /// How many milliseconds until the key's bucket has a token, without taking one - zero if
+    /// it has one now
+    pub fn wait(&self, key: &K, now: u64) -> u64 {
+        let mut bucket = self.buckets.get(key).cloned().unwrap_or(Bucket {
+            tokens: self.limit.burst,
+            refilled_at: now,
+        });
+        RateLimiter::<K>::refill(self.limit, &mut bucket, now);
+        if bucket.tokens > 0 {
+            return 0;
+        }
+        (bucket.refilled_at + self.limit.interval).saturating_sub(now)
+    }
+
+    /// Forgets the buckets that have filled back up, as they behave the same as new ones
+    pub fn sweep(&mut self, now: u64) {
+        let limit = self.limit;
+        self.buckets.retain(|_, bucket| {
+            RateLimiter::<K>::refill(limit, bucket, now);
+            bucket.tokens < limit.burst
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let mut limiter = RateLimiter::new(RateLimit {
+            burst: 2,
+            interval: 1_000,
+        });
+        assert_eq!(limiter.check("a", 10_000), Ok(()));
+        assert_eq!(limiter.check("a", 10_000), Ok(()));
+        assert_eq!(limiter.wait(&"a", 10_400), 600);
+        assert_eq!(limiter.check("a", 10_400), Err(600));
+        assert_eq!(limiter.wait(&"b", 10_400), 0);
+        // other keys have their own bucket
+        assert_eq!(limiter.check("b", 10_400), Ok(()));
+        // one token back after an interval, then wait a whole interval for the next
+        assert_eq!(limiter.check("a", 11_000), Ok(()));
+        assert_eq!(limiter.check("a", 11_500), Err(500));
+        // never more than the burst saved up
+        assert_eq!(limiter.check("a", 20_000), Ok(()));
+        assert_eq!(limiter.check("a", 20_000), Ok(()));
+        assert_eq!(limiter.check("a", 20_000), Err(1_000));
+
+        limiter.sweep(20_500);
+        assert_eq!(limiter.buckets.len(), 1);
+        limiter.sweep(22_000);
+        assert!(limiter.buckets.is_empty());
+    }
+}
@@ -0,0 +1,141 @@
+use serde::{Deserialize, Serialize};
+
+use super::messages::Message;
+
+/// Limits on how long a chat's messages are kept. A limit that isn't set doesn't apply.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    /// Oldest a message may get, in milliseconds since the server received it
+    #[serde(rename = "maxAge", default, skip_serializing_if = "Option::is_none")]
+    pub max_age: Option<u64>,
+    /// Most messages kept - the oldest go first. Notices such as the chat being created aren't
+    /// counted, but bot replies are.
+    #[serde(rename = "maxCount", default, skip_serializing_if = "Option::is_none")]
+    pub max_count: Option<usize>,
+}
+
+impl RetentionPolicy {
+    /// Limits of zero would empty a chat as soon as it was swept, so aren't allowed
+    pub fn is_valid(&self) -> bool {
+        self.max_age != Some(0) && self.max_count != Some(0)
+    }
+
+    /// Combines two policies, keeping the tighter of each limit
+    pub fn stricter(&self, other: &RetentionPolicy) -> RetentionPolicy {
+        fn min<T: Ord>(a: Option<T>, b: Option<T>) -> Option<T> {
+            match (a, b) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            }
+        }
+        RetentionPolicy {
+            max_age: min(self.max_age, other.max_age),
+            max_count: min(self.max_count, other.max_count),
+        }
+    }
+
+    /// Ids of messages that should be removed from a log ordered by timestamp - those past
+    /// their own expiry time, older than `max_age`, or beyond the newest `max_count` that
+    /// aren't notices
+    pub fn expired<'a>(&self, log: &'a [Message], now: u64) -> Vec<&'a str> {
+        let cutoff = self.max_age.map(|max_age| now.saturating_sub(max_age));
+        // notices would otherwise push out what people and bots wrote
+        let mut excess = match self.max_count {
+            Some(max_count) => log
+                .iter()
+                .filter(|m| !m.kind.is_notice())
+                .count()
+                .saturating_sub(max_count),
+            None => 0,
+        };
+        log.iter()
+            .filter(|m| {
+                let over_count = excess > 0 && !m.kind.is_notice();
+                if over_count {
+                    excess -= 1;
+                }
+                over_count
+                    || cutoff.is_some_and(|cutoff| m.timestamp < cutoff)
+                    || m.expires_at.is_some_and(|expires_at| expires_at <= now)
+            })
+            .map(|m| m.id.as_str())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::messages::MessageKind;
+
+    fn message(id: &str, timestamp: u64, expires_at: Option<u64>) -> Message {
+        Message {
+            id: id.to_owned(),
+            timestamp,
+            expires_at,
+            ..Message::default()
+        }
+    }
+
+    #[test]
+    fn test_stricter() {
+        let global = RetentionPolicy {
+            max_age: Some(1_000),
+            max_count: None,
+        };
+        let chat = RetentionPolicy {
+            max_age: Some(5_000),
+            max_count: Some(10),
+        };
+        assert_eq!(
+            global.stricter(&chat),
+            RetentionPolicy {
+                max_age: Some(1_000),
+                max_count: Some(10),
+            }
+        );
+        assert_eq!(
+            RetentionPolicy::default().stricter(&RetentionPolicy::default()),
+            RetentionPolicy::default()
+        );
+        assert!(!RetentionPolicy {
+            max_age: None,
+            max_count: Some(0),
+        }
+        .is_valid());
+    }
+
+    #[test]
+    fn test_expired() {
+        let created = Message {
+            kind: MessageKind::ChatCreated { title: None },
+            ..message("created", 50, None)
+        };
+        let log = vec![
+            created,
+            message("a", 100, None),
+            message("b", 200, Some(10_000)),
+            message("c", 300, Some(500)),
+            message("d", 400, None),
+            Message {
+                kind: MessageKind::BotReply {
+                    bot: "echo".to_owned(),
+                },
+                ..message("e", 500, None)
+            },
+        ];
+        assert_eq!(RetentionPolicy::default().expired(&log, 1_000), vec!["c"]);
+        let by_age = RetentionPolicy {
+            max_age: Some(850),
+            max_count: None,
+        };
+        assert_eq!(by_age.expired(&log, 1_000), vec!["created", "a", "c"]);
+        let by_count = RetentionPolicy {
+            max_age: None,
+            max_count: Some(3),
+        };
+        // the notice is older, but isn't counted, while the bot reply is
+        assert_eq!(by_count.expired(&log, 0), vec!["a", "b"]);
+    }
+}
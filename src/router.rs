@@ -1,18 +1,21 @@
 use path_tree::PathTree;
+use std::cmp;
 use std::collections::HashMap;
+use std::net::IpAddr;
 use std::sync::Arc;
 
 use qstring::QString;
 
-use super::chat_service::ChatService;
+use super::chat_service::{self, ChatService};
+use super::rate_limit::{RateLimit, RateLimiter};
 
 pub type HttpHandler = Box<
     dyn Fn(
             &mut ChatService,
             HashMap<&str, &str>,
             Option<QString>,
-            http::Request<&str>,
-        ) -> http::Response<String>
+            http::Request<&[u8]>,
+        ) -> http::Response<Vec<u8>>
         + Send
         + Sync
         + 'static,
@@ -24,8 +27,8 @@ where
             &mut ChatService,
             HashMap<&str, &str>,
             Option<QString>,
-            http::Request<&str>,
-        ) -> http::Response<String>
+            http::Request<&[u8]>,
+        ) -> http::Response<Vec<u8>>
         + Send
         + Sync
         + 'static,
@@ -35,17 +38,65 @@ where
 
 pub struct Route {
     pub method: http::Method,
+    /// The path the route was registered with, such as `/chats/:chatId`
+    pub path: String,
     pub handler: HttpHandler,
 }
 
 impl Route {
-    pub fn new(method: http::Method, handler: HttpHandler) -> Self {
-        Route { method, handler }
+    pub fn new(method: http::Method, path: &str, handler: HttpHandler) -> Self {
+        Route {
+            method,
+            path: path.to_owned(),
+            handler,
+        }
+    }
+}
+
+/// The address a request came from, which the server adds to each request's extensions
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RemoteAddr(pub IpAddr);
+
+/// Finds the user making a request, for rate limits kept per user
+pub type UserKey = fn(&http::Request<&[u8]>) -> Option<u64>;
+
+/// Rate limits on one route, kept per remote address and per user
+struct RouteLimiter {
+    addresses: RateLimiter<IpAddr>,
+    users: RateLimiter<u64>,
+    user_id: UserKey,
+}
+
+impl RouteLimiter {
+    /// Takes a token for the request's address and user, or returns how many milliseconds until
+    /// it may be retried. Neither is taken unless both are there, so a refused request costs
+    /// nothing.
+    fn check(&mut self, req: &http::Request<&[u8]>, now: u64) -> Result<(), u64> {
+        let address = req
+            .extensions()
+            .get::<RemoteAddr>()
+            .map(|RemoteAddr(address)| *address);
+        let user_id = (self.user_id)(req);
+        let wait = cmp::max(
+            address.map_or(0, |address| self.addresses.wait(&address, now)),
+            user_id.map_or(0, |user_id| self.users.wait(&user_id, now)),
+        );
+        if wait > 0 {
+            return Err(wait);
+        }
+        if let Some(address) = address {
+            self.addresses.check(address, now)?;
+        }
+        if let Some(user_id) = user_id {
+            self.users.check(user_id, now)?;
+        }
+        Ok(())
     }
 }
 
 pub struct RouterBuilder {
     trees: HashMap<http::Method, PathTree<Route>>,
+    limits: HashMap<(http::Method, String), RouteLimiter>,
     service: ChatService,
 }
 
@@ -57,16 +108,34 @@ impl RouterBuilder {
                 &mut ChatService,
                 HashMap<&str, &str>,
                 Option<QString>,
-                http::Request<&str>,
-            ) -> http::Response<String>
+                http::Request<&[u8]>,
+            ) -> http::Response<Vec<u8>>
             + Send
             + Sync
             + 'static,
     {
         self.trees
-            .get_mut(&method)
-            .unwrap()
-            .insert(route, Route::new(method, handler_fn(handler)));
+            .entry(method.clone())
+            .or_default()
+            .insert(route, Route::new(method, route, handler_fn(handler)));
+        self
+    }
+
+    /// Limits how often a route may be called from each remote address, and by each user that
+    /// `user_id` finds. Requests over the limit are answered with 429 Too Many Requests.
+    pub fn rate_limit(
+        mut self,
+        route: &str,
+        method: http::Method,
+        limit: RateLimit,
+        user_id: UserKey,
+    ) -> Self {
+        let limiter = RouteLimiter {
+            addresses: RateLimiter::new(limit),
+            users: RateLimiter::new(limit),
+            user_id,
+        };
+        self.limits.insert((method, route.to_owned()), limiter);
         self
     }
 
@@ -74,6 +143,7 @@ impl RouterBuilder {
     pub fn build(self) -> Router {
         Router {
             trees: Arc::new(self.trees),
+            limits: self.limits,
             service: self.service,
         }
     }
@@ -81,27 +151,44 @@ impl RouterBuilder {
 
 pub struct Router {
     trees: Arc<HashMap<http::Method, PathTree<Route>>>,
+    limits: HashMap<(http::Method, String), RouteLimiter>,
     service: ChatService,
 }
 
 impl Router {
     pub fn builder(service: ChatService) -> RouterBuilder {
-        let trees: HashMap<http::Method, PathTree<Route>> = {
-            let mut tree = HashMap::new();
-            tree.insert(http::Method::POST, PathTree::new());
-            tree.insert(http::Method::GET, PathTree::new());
-            tree
-        };
-        RouterBuilder { trees, service }
+        RouterBuilder {
+            trees: HashMap::new(),
+            limits: HashMap::new(),
+            service,
+        }
     }
 
-    pub fn route(&mut self, req: http::Request<&str>) -> http::Response<String> {
+    pub(crate) fn service_mut(&mut self) -> &mut ChatService {
+        &mut self.service
+    }
+
+    /// Forgets rate limit buckets that have filled back up
+    pub(crate) fn sweep_rate_limits(&mut self, now: u64) {
+        for limiter in self.limits.values_mut() {
+            limiter.addresses.sweep(now);
+            limiter.users.sweep(now);
+        }
+    }
+
+    pub fn route(&mut self, req: http::Request<&[u8]>) -> http::Response<Vec<u8>> {
         let trees = self.trees.clone();
         let path = req.uri().path().to_owned();
         let query = req.uri().query().to_owned();
         let query = query.map(QString::from);
-        match trees.get(req.method()).unwrap().find(&path) {
+        match trees.get(req.method()).and_then(|tree| tree.find(&path)) {
             Some((route, params)) => {
+                let key = (route.method.clone(), route.path.clone());
+                if let Some(limiter) = self.limits.get_mut(&key) {
+                    if let Err(retry_after) = limiter.check(&req, chat_service::timestamp()) {
+                        return too_many_requests(retry_after);
+                    }
+                }
                 let handler = &route.handler;
                 let res = handler(
                     &mut self.service,
@@ -109,8 +196,14 @@ impl Router {
                     query,
                     req,
                 );
-                if res.status() != http::StatusCode::OK {
-                    println!("response: {:?}", res);
+                if !res.status().is_success() {
+                    println!(
+                        "{} {} response: {} {}",
+                        route.method,
+                        path,
+                        res.status(),
+                        String::from_utf8_lossy(res.body())
+                    );
                 }
                 res
             }
@@ -122,19 +215,36 @@ impl Router {
     }
 }
 
-pub fn not_found() -> http::Response<String> {
+pub fn not_found() -> http::Response<Vec<u8>> {
     status_code_msg(http::StatusCode::NOT_FOUND, "Not found.", "text/plain")
 }
 
-pub fn status_ok() -> http::Response<String> {
-    status_code_msg(http::StatusCode::OK, String::new(), "text/plain")
+pub fn status_ok() -> http::Response<Vec<u8>> {
+    status_code_msg(http::StatusCode::OK, Vec::new(), "text/plain")
 }
 
-pub fn ok_json<T: Into<String>>(body: T) -> http::Response<String> {
+pub fn ok_json<T: Into<Vec<u8>>>(body: T) -> http::Response<Vec<u8>> {
     status_code_msg(http::StatusCode::OK, body, "application/json")
 }
 
-pub fn error500(error_msg: &str) -> http::Response<String> {
+pub fn bad_request<T: Into<Vec<u8>>>(msg: T) -> http::Response<Vec<u8>> {
+    status_code_msg(http::StatusCode::BAD_REQUEST, msg, "text/plain")
+}
+
+/// 429 Too Many Requests, with `Retry-After` rounded up to whole seconds
+pub fn too_many_requests(retry_after: u64) -> http::Response<Vec<u8>> {
+    let seconds = retry_after.div_ceil(1000);
+    let mut res = status_code_msg(
+        http::StatusCode::TOO_MANY_REQUESTS,
+        format!("Too many requests, retry in {} seconds.", seconds),
+        "text/plain",
+    );
+    res.headers_mut()
+        .insert(http::header::RETRY_AFTER, http::HeaderValue::from(seconds));
+    res
+}
+
+pub fn error500(error_msg: &str) -> http::Response<Vec<u8>> {
     eprintln!("ERROR 500 : {}", error_msg);
     super::router::status_code_msg(
         http::StatusCode::INTERNAL_SERVER_ERROR,
@@ -143,11 +253,11 @@ pub fn error500(error_msg: &str) -> http::Response<String> {
     )
 }
 
-pub fn status_code_msg<T: Into<String>>(
+pub fn status_code_msg<T: Into<Vec<u8>>>(
     code: http::StatusCode,
     msg: T,
     content_type: &str,
-) -> http::Response<String> {
+) -> http::Response<Vec<u8>> {
     http::Response::builder()
         .status(code)
         .header("Content-Type", content_type)
@@ -171,7 +281,7 @@ mod tests {
                     assert_eq!(params.len(), 2, "Unexpected params len");
                     assert_eq!(params["id"], "42");
                     assert_eq!(params["answer"], "everything");
-                    assert_eq!(*req.body(), "req body");
+                    assert_eq!(*req.body(), b"req body");
                     status_code_msg(http::StatusCode::OK, "body here", "text/plain")
                 },
             )
@@ -179,11 +289,62 @@ mod tests {
 
         let mut req = http::Request::builder();
         req.uri("/home/42/everything");
-        let res = router.route(req.body("req body".into()).unwrap());
-        assert_eq!(res.body(), "body here");
+        let res = router.route(req.body(&b"req body"[..]).unwrap());
+        assert_eq!(res.body(), b"body here");
         assert_eq!(
             res.headers().get("Content-type"),
             Some(&http::HeaderValue::from_str("text/plain").unwrap())
         );
     }
+
+    #[test]
+    fn test_rate_limit() {
+        let limit = RateLimit {
+            burst: 2,
+            interval: 60_000,
+        };
+        let mut router = Router::builder(ChatService::default())
+            .register("/ping/:userId", http::Method::POST, |_, _, _, _| {
+                status_ok()
+            })
+            .register("/ping/:userId", http::Method::GET, |_, _, _, _| status_ok())
+            .rate_limit("/ping/:userId", http::Method::POST, limit, |req| {
+                req.uri().path().rsplit('/').next()?.parse().ok()
+            })
+            .build();
+        let mut ping = |method: http::Method, path: &str, address: [u8; 4]| {
+            let mut req = http::Request::builder();
+            req.method(method).uri(path);
+            req.extension(RemoteAddr(IpAddr::from(address)));
+            router.route(req.body(&b""[..]).unwrap())
+        };
+
+        let home = [10, 0, 0, 1];
+        assert!(ping(http::Method::POST, "/ping/1", home)
+            .status()
+            .is_success());
+        assert!(ping(http::Method::POST, "/ping/2", home)
+            .status()
+            .is_success());
+        // the address has used up its burst, though user 3 hasn't
+        let res = ping(http::Method::POST, "/ping/3", home);
+        assert_eq!(res.status(), http::StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers()[http::header::RETRY_AFTER], "60");
+        // as has user 1, from any address
+        assert!(ping(http::Method::POST, "/ping/1", [10, 0, 0, 2])
+            .status()
+            .is_success());
+        let res = ping(http::Method::POST, "/ping/1", [10, 0, 0, 3]);
+        assert_eq!(res.status(), http::StatusCode::TOO_MANY_REQUESTS);
+        // a refused request doesn't use up its address's allowance
+        for path in &["/ping/4", "/ping/5"] {
+            assert!(ping(http::Method::POST, path, [10, 0, 0, 3])
+                .status()
+                .is_success());
+        }
+        // routes without a limit are left alone
+        assert!(ping(http::Method::GET, "/ping/1", home)
+            .status()
+            .is_success());
+    }
 }
@@ -0,0 +1,345 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// Number of words kept either side of a match when building a snippet
+const CONTEXT_WORDS: usize = 6;
+
+/// Identifies an indexed message by chat id and message id
+pub type DocKey = (u64, String);
+
+/// A word of a message, with the byte range it came from
+struct Token {
+    start: usize,
+    end: usize,
+    term: String,
+}
+
+/// Splits text into lowercased alphanumeric words
+fn tokenize(text: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+    for (i, c) in text.char_indices() {
+        match (c.is_alphanumeric(), start) {
+            (true, None) => start = Some(i),
+            (false, Some(s)) => {
+                tokens.push(Token {
+                    start: s,
+                    end: i,
+                    term: text[s..i].to_lowercase(),
+                });
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(s) = start {
+        tokens.push(Token {
+            start: s,
+            end: text.len(),
+            term: text[s..].to_lowercase(),
+        });
+    }
+    tokens
+}
+
+#[derive(Debug, PartialEq)]
+enum Clause {
+    Term(String),
+    /// `word*` - any term starting with the given text
+    Prefix(String),
+    /// `"some words"` - the terms in order, next to each other
+    Phrase(Vec<String>),
+}
+
+/// A parsed search query - every clause must match for a message to be a hit
+#[derive(Debug, PartialEq)]
+pub struct Query {
+    clauses: Vec<Clause>,
+}
+
+impl Query {
+    pub fn parse(query: &str) -> Self {
+        let mut clauses = Vec::new();
+        for (i, part) in query.split('"').enumerate() {
+            if i % 2 == 1 {
+                let terms = tokenize(part)
+                    .into_iter()
+                    .map(|t| t.term)
+                    .collect::<Vec<_>>();
+                match terms.len() {
+                    0 => {}
+                    1 => clauses.push(Clause::Term(terms.into_iter().next().unwrap())),
+                    _ => clauses.push(Clause::Phrase(terms)),
+                }
+                continue;
+            }
+            for word in part.split_whitespace() {
+                let prefix = word.ends_with('*');
+                for token in tokenize(word) {
+                    clauses.push(if prefix {
+                        Clause::Prefix(token.term)
+                    } else {
+                        Clause::Term(token.term)
+                    });
+                }
+            }
+        }
+        Query { clauses }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clauses.is_empty()
+    }
+}
+
+#[derive(Debug)]
+pub struct SearchHit {
+    pub key: DocKey,
+    pub score: f64,
+    /// Word positions of every match in the message, in order
+    pub positions: Vec<usize>,
+}
+
+/// Inverted index from terms to the messages and word positions they appear at
+#[derive(Default)]
+pub struct SearchIndex {
+    postings: BTreeMap<String, HashMap<DocKey, Vec<usize>>>,
+    documents: HashMap<DocKey, Vec<String>>,
+}
+
+impl SearchIndex {
+    /// Adds a message to the index, replacing anything previously indexed under the same key
+    pub fn index(&mut self, key: DocKey, text: &str) {
+        self.remove(&key);
+        let terms = tokenize(text)
+            .into_iter()
+            .map(|t| t.term)
+            .collect::<Vec<_>>();
+        for (position, term) in terms.iter().enumerate() {
+            self.postings
+                .entry(term.clone())
+                .or_default()
+                .entry(key.clone())
+                .or_default()
+                .push(position);
+        }
+        self.documents.insert(key, terms);
+    }
+
+    pub fn remove(&mut self, key: &DocKey) {
+        let terms = match self.documents.remove(key) {
+            Some(terms) => terms,
+            None => return,
+        };
+        for term in terms.iter().collect::<HashSet<_>>() {
+            if let Some(docs) = self.postings.get_mut(term) {
+                docs.remove(key);
+                if docs.is_empty() {
+                    self.postings.remove(term);
+                }
+            }
+        }
+    }
+
+    /// Finds messages matching every clause of the query, best match first. Only messages
+    /// accepted by `visible` are returned, though all messages count towards term rarity.
+    pub fn search<F>(&self, query: &Query, visible: F) -> Vec<SearchHit>
+    where
+        F: Fn(&DocKey) -> bool,
+    {
+        let mut hits: Option<HashMap<&DocKey, (f64, Vec<usize>)>> = None;
+        for clause in &query.clauses {
+            let matches = self.clause_matches(clause);
+            // rarer clauses are worth more
+            let idf = 1.0 + (self.documents.len() as f64 / matches.len().max(1) as f64).ln();
+            let mut next = HashMap::new();
+            for (key, positions) in matches {
+                let previous = match &hits {
+                    Some(hits) => match hits.get(key) {
+                        Some(previous) => previous.clone(),
+                        None => continue,
+                    },
+                    None => (0.0, Vec::new()),
+                };
+                let (score, mut all_positions) = previous;
+                let score = score + idf * positions.len() as f64;
+                all_positions.extend(positions);
+                next.insert(key, (score, all_positions));
+            }
+            hits = Some(next);
+        }
+
+        let mut hits = hits
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|(key, _)| visible(key))
+            .map(|(key, (score, mut positions))| {
+                positions.sort_unstable();
+                positions.dedup();
+                SearchHit {
+                    key: key.clone(),
+                    score,
+                    positions,
+                }
+            })
+            .collect::<Vec<_>>();
+        hits.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
+        hits
+    }
+
+    /// Word positions that match a clause, per message
+    fn clause_matches(&self, clause: &Clause) -> HashMap<&DocKey, Vec<usize>> {
+        let mut matches: HashMap<&DocKey, Vec<usize>> = HashMap::new();
+        match clause {
+            Clause::Term(term) => {
+                if let Some(docs) = self.postings.get(term) {
+                    for (key, positions) in docs {
+                        matches.insert(key, positions.clone());
+                    }
+                }
+            }
+            Clause::Prefix(prefix) => {
+                let terms = self
+                    .postings
+                    .range(prefix.clone()..)
+                    .take_while(|(term, _)| term.starts_with(prefix.as_str()));
+                for (_, docs) in terms {
+                    for (key, positions) in docs {
+                        matches.entry(key).or_default().extend(positions);
+                    }
+                }
+            }
+            Clause::Phrase(terms) => {
+                let first = match self.postings.get(&terms[0]) {
+                    Some(docs) => docs,
+                    None => return matches,
+                };
+                for (key, starts) in first {
+                    let words = &self.documents[key];
+                    let positions = starts
+                        .iter()
+                        .filter(|start| {
+                            words.len() >= **start + terms.len()
+                                && words[**start..**start + terms.len()] == terms[..]
+                        })
+                        .flat_map(|start| *start..*start + terms.len())
+                        .collect::<Vec<_>>();
+                    if !positions.is_empty() {
+                        matches.insert(key, positions);
+                    }
+                }
+            }
+        }
+        matches
+    }
+}
+
+/// An excerpt of `text` around the first matched word, with a few words either side
+pub fn snippet(text: &str, positions: &[usize]) -> String {
+    let tokens = tokenize(text);
+    let first = match positions.first() {
+        Some(first) if *first < tokens.len() => *first,
+        _ => return text.to_owned(),
+    };
+    let last = positions
+        .iter()
+        .take_while(|p| **p <= first + CONTEXT_WORDS)
+        .last()
+        .cloned()
+        .unwrap_or(first)
+        .min(tokens.len() - 1);
+    let from = first.saturating_sub(CONTEXT_WORDS);
+    let to = (last + CONTEXT_WORDS).min(tokens.len() - 1);
+    let start = if from == 0 { 0 } else { tokens[from].start };
+    let end = if to == tokens.len() - 1 {
+        text.len()
+    } else {
+        tokens[to].end
+    };
+    format!(
+        "{}{}{}",
+        if start > 0 { "…" } else { "" },
+        &text[start..end],
+        if end < text.len() { "…" } else { "" }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn key(id: &str) -> DocKey {
+        (1, id.to_owned())
+    }
+
+    fn index() -> SearchIndex {
+        let mut index = SearchIndex::default();
+        index.index(key("a"), "Lunch at the Thai place tomorrow?");
+        index.index(key("b"), "The place was closed, lunch was late");
+        index.index(key("c"), "Placing the order now");
+        index
+    }
+
+    fn ids(hits: Vec<SearchHit>) -> Vec<String> {
+        hits.into_iter().map(|hit| hit.key.1).collect()
+    }
+
+    #[test]
+    fn test_query_parse() {
+        let query = Query::parse("lunch \"thai place\" plac*");
+        assert_eq!(
+            query.clauses,
+            vec![
+                Clause::Term("lunch".to_owned()),
+                Clause::Phrase(vec!["thai".to_owned(), "place".to_owned()]),
+                Clause::Prefix("plac".to_owned()),
+            ]
+        );
+        assert!(Query::parse(" \"\" ").is_empty());
+    }
+
+    #[test]
+    fn test_terms_phrases_and_prefixes() {
+        let index = index();
+        let mut hits = ids(index.search(&Query::parse("lunch place"), |_| true));
+        hits.sort();
+        assert_eq!(hits, vec!["a", "b"]);
+        assert_eq!(
+            ids(index.search(&Query::parse("\"thai place\""), |_| true)),
+            vec!["a"]
+        );
+        assert!(index
+            .search(&Query::parse("\"place thai\""), |_| true)
+            .is_empty());
+        assert_eq!(ids(index.search(&Query::parse("plac*"), |_| true)).len(), 3);
+        assert_eq!(
+            ids(index.search(&Query::parse("plac*"), |key| key.1 != "c")).len(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_ranking_and_removal() {
+        let mut index = index();
+        index.index(key("d"), "lunch lunch lunch");
+        assert_eq!(ids(index.search(&Query::parse("lunch"), |_| true))[0], "d");
+
+        index.remove(&key("d"));
+        index.index(key("a"), "dinner instead");
+        assert_eq!(
+            ids(index.search(&Query::parse("lunch"), |_| true)),
+            vec!["b"]
+        );
+    }
+
+    #[test]
+    fn test_snippet() {
+        let text = "one two three four five six seven eight nine ten eleven twelve thirteen";
+        assert_eq!(snippet(text, &[0]), "one two three four five six seven…");
+        assert_eq!(
+            snippet(text, &[9]),
+            "…four five six seven eight nine ten eleven twelve thirteen"
+        );
+        assert_eq!(snippet("short", &[]), "short");
+    }
+}
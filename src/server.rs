@@ -1,15 +1,36 @@
 use std::collections::HashMap;
 use std::error::Error;
 use std::io::{Read, Write};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
 
 use mio::net::{TcpListener, TcpStream};
+use mio::unix::EventedFd;
 use mio::{Events, Poll, PollOpt, Ready, Token};
+use qstring::QString;
+use serde::{Deserialize, Serialize};
 
-use super::chat_service::ChatService;
-use super::messages::Message;
-use super::router::{error500, not_found, ok_json, status_code_msg, status_ok, Router};
+use super::attachments::{self, ByteRange};
+use super::chat_service::{self, ChatError, ChatListFilter, ChatService};
+use super::config::Config;
+use super::messages::{
+    BlockRequest, ChatSettingsUpdate, ChatUpdate, DeliveryAck, Draft, Message, MessageEdit,
+    PinRequest, ReactionRequest, ReadReceipt, RetentionRequest, TypingRequest,
+};
+use super::moderation::ReviewDecision;
+use super::quotas::{self, Quota};
+use super::router::{
+    bad_request, error500, not_found, ok_json, status_code_msg, status_ok, RemoteAddr, Router,
+};
+use super::shutdown::Signals;
+use super::webhooks::{self, SubscriptionRequest, Target, WebhookConnection};
 
 const MAX_BUF_SIZE: usize = 8192;
+const DEFAULT_SEARCH_LIMIT: usize = 20;
+const DEFAULT_MENTIONS_LIMIT: usize = 50;
+/// How long to stop accepting connections after accepting fails, such as when the process has
+/// run out of file descriptors
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
 
 pub struct Client<T>
 where
@@ -17,6 +38,21 @@ where
 {
     socket: T,
     buffer: [u8; MAX_BUF_SIZE],
+    /// Bytes received that don't yet make up a whole request
+    pending: Vec<u8>,
+    /// Length of the line and headers at the start of `pending`, once they have all arrived
+    head_len: Option<usize>,
+    /// Response bytes the socket wasn't ready to take yet
+    outgoing: Vec<u8>,
+    interest: Ready,
+    /// User the connection belongs to, taken from the `userId` of its first request that has one
+    user_id: Option<u64>,
+    /// Where the connection came from, passed on to the router with each request
+    address: Option<IpAddr>,
+    /// When the connection last sent or took any bytes
+    last_active: Instant,
+    /// When the first bytes of the request still being received arrived
+    request_started: Option<Instant>,
 }
 
 impl<T> Client<T>
@@ -27,66 +63,307 @@ where
         Client {
             socket,
             buffer: [0; MAX_BUF_SIZE],
+            pending: Vec::new(),
+            head_len: None,
+            outgoing: Vec::new(),
+            interest: Ready::readable(),
+            user_id: None,
+            address: None,
+            last_active: Instant::now(),
+            request_started: None,
         }
     }
 
     pub fn read(&mut self) -> std::io::Result<usize> {
-        self.socket.read(&mut self.buffer)
+        let bytes_read = self.socket.read(&mut self.buffer)?;
+        let before = self.pending.len();
+        self.pending.extend_from_slice(&self.buffer[..bytes_read]);
+        if bytes_read > 0 {
+            self.last_active = Instant::now();
+            // the end of the head may straddle the bytes already there
+            self.find_head(before.saturating_sub(3));
+        }
+        Ok(bytes_read)
+    }
+
+    /// Takes the first `len` bytes off `pending`, and looks for the head of the request after
+    /// them
+    fn consume(&mut self, len: usize) {
+        if len == 0 {
+            return;
+        }
+        self.pending.drain(..len);
+        self.head_len = None;
+        self.find_head(0);
+    }
+
+    /// Records where the request's line and headers end, searching `pending` from `from`
+    fn find_head(&mut self, from: usize) {
+        if self.head_len.is_some() {
+            return;
+        }
+        self.head_len = self.pending[from..]
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .map(|i| from + i + 4);
+    }
+
+    /// Writes as much of the queued response data as the socket will take
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        while !self.outgoing.is_empty() {
+            match self.socket.write(&self.outgoing) {
+                Ok(0) => return Err(std::io::ErrorKind::WriteZero.into()),
+                Ok(written) => {
+                    self.outgoing.drain(..written);
+                    self.last_active = Instant::now();
+                }
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// When the connection is closed unless something happens first. A request that is coming
+    /// in must finish its headers within the header timeout, and from then on keep up the
+    /// minimum transfer rate. Otherwise the connection may sit idle for the idle timeout.
+    fn deadline(&self, limits: &ConnectionLimits) -> Instant {
+        let idle = self.last_active + limits.idle_timeout;
+        let started = match self.request_started {
+            Some(started) => started,
+            None => return idle,
+        };
+        if self.head_len.is_none() {
+            return idle.min(started + limits.header_timeout);
+        }
+        // when the request's average rate would fall below the minimum
+        match (self.pending.len() as u64 * 1000).checked_div(limits.min_transfer_rate) {
+            Some(allowed) => {
+                let allowed = limits.header_timeout.max(Duration::from_millis(allowed));
+                idle.min(started + allowed)
+            }
+            None => idle,
+        }
+    }
+}
+
+/// How long connections may sit idle or take over a request, and how many may be open at once
+#[derive(Clone, Copy, Debug)]
+struct ConnectionLimits {
+    idle_timeout: Duration,
+    header_timeout: Duration,
+    /// Bytes per second a request must average once it has taken longer than the header
+    /// timeout, 0 for no minimum
+    min_transfer_rate: u64,
+    max_connections: usize,
+}
+
+impl ConnectionLimits {
+    fn new(config: &Config) -> Self {
+        ConnectionLimits {
+            idle_timeout: Duration::from_millis(config.idle_timeout),
+            header_timeout: Duration::from_millis(config.header_timeout),
+            min_transfer_rate: config.min_transfer_rate,
+            max_connections: config.max_connections,
+        }
     }
 }
 
 pub struct Server {
     token: mio::Token,
     next_token: u64,
-    listener: TcpListener,
+    /// Closed once the server starts shutting down
+    listener: Option<TcpListener>,
     events: Events,
     connections: HashMap<mio::Token, Client<TcpStream>>,
     poll: Poll,
     router: Router,
+    max_request_size: usize,
+    limits: ConnectionLimits,
+    sweep_interval: Duration,
+    /// When expired messages are next removed - `poll` wakes up for it even with no socket events
+    next_sweep: Instant,
+    /// Outgoing connections delivering webhooks, which share tokens with `connections`
+    webhook_connections: HashMap<mio::Token, WebhookConnection<TcpStream>>,
+    /// SIGTERM and SIGINT, once `handle_signals` has been called
+    signals: Option<(mio::Token, Signals)>,
+    /// How long in-flight requests get to finish once shutdown starts
+    grace_period: Duration,
+    /// When the server gives up waiting for connections to finish, once shutdown has started
+    shutdown_deadline: Option<Instant>,
+    /// When to try accepting connections again, after accepting failed
+    accept_paused_until: Option<Instant>,
 }
 
-fn response_to_string(res: http::Response<String>) -> String {
-    let body = res.body();
-    let headers = res.headers();
-    let headers = headers
+fn response_to_bytes(res: http::Response<Vec<u8>>) -> Vec<u8> {
+    let headers = res
+        .headers()
         .iter()
         .map(|(k, v)| format!("{}: {}", k, v.to_str().unwrap()))
         .collect::<Vec<_>>()
         .join("\r\n");
 
-    format!(
-        "HTTP/1.1 {}\r\n{}\r\nContent-Length: {}\r\n\r\n{}",
+    let mut bytes = format!(
+        "HTTP/1.1 {}\r\n{}\r\nContent-Length: {}\r\n\r\n",
         res.status(),
         headers,
-        body.len(),
-        body
+        res.body().len()
     )
+    .into_bytes();
+    bytes.extend_from_slice(res.body());
+    bytes
+}
+
+/// Parses a numeric path parameter such as `:chatId`
+fn id_param(params: &HashMap<&str, &str>, name: &str) -> Result<u64, String> {
+    let id = params.get(name).cloned().unwrap_or_default();
+    id.parse::<u64>()
+        .map_err(|e| format!("unable to parse {}: {:?}", name, e))
+}
+
+/// Parses a numeric query string parameter such as `?userId=`
+fn id_query(query: &Option<QString>, name: &str) -> Result<u64, String> {
+    match query.as_ref().and_then(|query| query.get(name)) {
+        Some(id) => id
+            .parse::<u64>()
+            .map_err(|e| format!("unable to parse {}: {:?}", name, e)),
+        None => Err(format!("missing query parameter {}", name)),
+    }
+}
+
+/// The `userId` query string parameter of a request, if it has a valid one
+fn request_user_id(req: &crate::parse::Request<'_>) -> Option<u64> {
+    let query = QString::from(req.uri().query()?);
+    query.get("userId")?.parse::<u64>().ok()
+}
+
+/// The sender of a message being posted, for rate limits kept per user
+fn message_sender(req: &crate::parse::Request<'_>) -> Option<u64> {
+    #[derive(Deserialize)]
+    struct Sender {
+        #[serde(rename = "sourceUserId")]
+        source_user_id: u64,
+    }
+    let sender = serde_json::from_slice::<Sender>(req.body()).ok()?;
+    Some(sender.source_user_id)
+}
+
+/// Turns away requests to `/admin` routes that don't carry the admin bearer token
+fn check_admin(
+    req: &crate::parse::Request<'_>,
+    admin_token: Option<&str>,
+) -> Option<http::Response<Vec<u8>>> {
+    let admin_token = match admin_token {
+        Some(admin_token) => admin_token,
+        None => {
+            return Some(status_code_msg(
+                http::StatusCode::FORBIDDEN,
+                "Admin routes are disabled.",
+                "text/plain",
+            ))
+        }
+    };
+    let authorized = req
+        .headers()
+        .get(http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|token| token == admin_token);
+    if authorized {
+        None
+    } else {
+        Some(status_code_msg(
+            http::StatusCode::UNAUTHORIZED,
+            "Admin token required.",
+            "text/plain",
+        ))
+    }
+}
+
+fn to_json<T: Serialize>(value: &T) -> http::Response<Vec<u8>> {
+    match serde_json::to_string(value) {
+        Ok(json) => ok_json(json),
+        Err(e) => error500(&format!("unable to serialize json: {:?}", e)),
+    }
+}
+
+fn chat_error(e: ChatError) -> http::Response<Vec<u8>> {
+    let code = match e {
+        ChatError::ChatNotFound(_)
+        | ChatError::MessageNotFound(_)
+        | ChatError::UserNotFound(_)
+        | ChatError::FlagNotFound(_) => http::StatusCode::NOT_FOUND,
+        ChatError::ChatExists(_)
+        | ChatError::MessageExists(_)
+        | ChatError::ScheduledMessageExists(_) => http::StatusCode::CONFLICT,
+        ChatError::NotContacts { .. } | ChatError::Blocked { .. } => http::StatusCode::FORBIDDEN,
+        ChatError::CannotBlockSelf(_) => http::StatusCode::BAD_REQUEST,
+        ChatError::NotSender { .. } | ChatError::NotParticipant { .. } => {
+            http::StatusCode::FORBIDDEN
+        }
+        ChatError::MessageDeleted(_) => http::StatusCode::GONE,
+        ChatError::ParentNotFound(_)
+        | ChatError::InvalidMessageId(_)
+        | ChatError::InvalidMention { .. }
+        | ChatError::InvalidWebhook(_)
+        | ChatError::InvalidReaction(_)
+        | ChatError::InvalidRetention(_)
+        | ChatError::InvalidChatMetadata(_) => http::StatusCode::BAD_REQUEST,
+        ChatError::TooManyReactions(_)
+        | ChatError::TooManyPins(_)
+        | ChatError::MessageRejected(_) => http::StatusCode::UNPROCESSABLE_ENTITY,
+        ChatError::AttachmentNotFound(_) => http::StatusCode::NOT_FOUND,
+        ChatError::AttachmentTooLarge { .. } => http::StatusCode::PAYLOAD_TOO_LARGE,
+        ChatError::AttachmentQuotaExceeded { .. } => http::StatusCode::FORBIDDEN,
+        // the daily message quota frees up again at midnight, the others only once the user
+        // removes something
+        ChatError::QuotaExceeded {
+            quota: Quota::MessagesPerDay(_),
+            ..
+        } => {
+            let now = chat_service::timestamp();
+            let mut res = status_code_msg(
+                http::StatusCode::TOO_MANY_REQUESTS,
+                e.to_string(),
+                "text/plain",
+            );
+            let retry_after = (quotas::DAY - now % quotas::DAY).div_ceil(1000);
+            res.headers_mut().insert(
+                http::header::RETRY_AFTER,
+                http::HeaderValue::from(retry_after),
+            );
+            return res;
+        }
+        ChatError::QuotaExceeded { .. } => http::StatusCode::FORBIDDEN,
+        ChatError::Storage(_) => http::StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    status_code_msg(code, e.to_string(), "text/plain")
 }
 
 impl Server {
     pub fn new(listener: TcpListener) -> Result<Self, Box<dyn Error>> {
+        Server::with_config(listener, Config::default())
+    }
+
+    pub fn with_config(listener: TcpListener, config: Config) -> Result<Self, Box<dyn Error>> {
         let events = Events::with_capacity(64);
         let connections = HashMap::new();
         let token = Token(0);
         let next_token = 1;
         let poll = Poll::new()?;
-        let chat_service = ChatService::default();
+        let chat_service = ChatService::new(&config)?;
 
         let router = Router::builder(chat_service)
             // Creates a chat between users
             .register("/chats", http::Method::POST, |svc, _, _, req| {
-                println!("POST /chats {}", req.body());
-                let chat = match serde_json::from_str::<super::messages::Chat>(req.body()) {
+                println!("POST /chats {}", String::from_utf8_lossy(req.body()));
+                let chat = match serde_json::from_slice::<super::messages::Chat>(req.body()) {
                     Ok(chat) => chat,
                     Err(e) => return error500(&format!("unable to parse json: {:?}", e)),
                 };
                 match svc.add_chat(chat) {
                     Ok(()) => status_ok(),
-                    Err(e) => status_code_msg(
-                        http::StatusCode::BAD_REQUEST,
-                        format!("unable to add chat {:?}", e),
-                        "text/plain",
-                    ), //error500(&format!("unable to add chat: {:?}", e)),
+                    Err(e) => chat_error(e),
                 }
             })
             // Adds a message to a chat
@@ -94,23 +371,37 @@ impl Server {
                 "/chats/:chatId/messages",
                 http::Method::POST,
                 |svc, params, _, req| {
-                    println!("POST, /chats/:chatId/messages {}", req.body());
+                    println!(
+                        "POST, /chats/:chatId/messages {}",
+                        String::from_utf8_lossy(req.body())
+                    );
                     let chat_id = params.get("chatId").unwrap();
                     let chat_id = match chat_id.parse::<u64>() {
                         Ok(chat_id) => chat_id,
                         Err(e) => return error500(&format!("unable to parse chat id: {:?}", e)),
                     };
-                    let message = match serde_json::from_str::<Message>(req.body()) {
+                    let message = match serde_json::from_slice::<Message>(req.body()) {
                         Ok(message) => message,
                         Err(e) => return error500(&format!("unable to parse json: {:?}", e)),
                     };
+                    // a sendAt in the future holds the message back, returning it with its id
+                    let scheduled = message
+                        .send_at
+                        .is_some_and(|send_at| send_at > chat_service::timestamp());
+                    if scheduled {
+                        return match svc.schedule_message(chat_id, message) {
+                            Ok(scheduled) => to_json(&scheduled),
+                            Err(e) => chat_error(e),
+                        };
+                    }
                     match svc.send_message(chat_id, message) {
                         Ok(()) => status_ok(),
-                        Err(_) => not_found(),
+                        Err(e) => chat_error(e),
                     }
                 },
             )
-            // Lists a user's current chats (query param userId required)
+            // Lists a user's current chats (query param userId required). Archived chats are only
+            // listed with archived=true, and pinned=only leaves out chats that aren't pinned.
             .register("/chats", http::Method::GET, |svc, _, query, _| {
                 println!("GET /chats");
                 let query = match query {
@@ -129,7 +420,25 @@ impl Server {
                     },
                     None => return not_found(),
                 };
-                let chats = svc.get_user_chats(user_id);
+                let archived = match query.get("archived") {
+                    Some(archived) => match archived.parse::<bool>() {
+                        Ok(archived) => archived,
+                        Err(e) => return bad_request(format!("unable to parse archived: {:?}", e)),
+                    },
+                    None => false,
+                };
+                let pinned_only = match query.get("pinned") {
+                    Some("only") => true,
+                    Some(pinned) => {
+                        return bad_request(format!("invalid pinned filter {:?}", pinned))
+                    }
+                    None => false,
+                };
+                let filter = ChatListFilter {
+                    archived,
+                    pinned_only,
+                };
+                let chats = svc.get_user_chats(user_id, filter);
                 match serde_json::to_string(&chats) {
                     Ok(json) => ok_json(json),
                     Err(e) => error500(&format!("unable to parse json: {:?}", e)),
@@ -156,77 +465,1179 @@ impl Server {
                     }
                 },
             )
-            .build();
+            // Edits the text of a message (sender only)
+            .register(
+                "/chats/:chatId/messages/:messageId",
+                http::Method::PATCH,
+                |svc, params, _, req| {
+                    let chat_id = match id_param(&params, "chatId") {
+                        Ok(chat_id) => chat_id,
+                        Err(e) => return bad_request(e),
+                    };
+                    let edit = match serde_json::from_slice::<MessageEdit>(req.body()) {
+                        Ok(edit) => edit,
+                        Err(e) => return bad_request(format!("unable to parse json: {:?}", e)),
+                    };
+                    match svc.edit_message(chat_id, params["messageId"], edit.user_id, edit.message)
+                    {
+                        Ok(message) => to_json(&message),
+                        Err(e) => chat_error(e),
+                    }
+                },
+            )
+            // Deletes a message, leaving a tombstone (sender only, query param userId required)
+            .register(
+                "/chats/:chatId/messages/:messageId",
+                http::Method::DELETE,
+                |svc, params, query, _| {
+                    let chat_id = match id_param(&params, "chatId") {
+                        Ok(chat_id) => chat_id,
+                        Err(e) => return bad_request(e),
+                    };
+                    let user_id = match id_query(&query, "userId") {
+                        Ok(user_id) => user_id,
+                        Err(e) => return bad_request(e),
+                    };
+                    match svc.delete_message(chat_id, params["messageId"], user_id) {
+                        Ok(message) => to_json(&message),
+                        Err(e) => chat_error(e),
+                    }
+                },
+            )
+            // Adds a reaction to a message
+            .register(
+                "/chats/:chatId/messages/:messageId/reactions",
+                http::Method::POST,
+                |svc, params, _, req| {
+                    let chat_id = match id_param(&params, "chatId") {
+                        Ok(chat_id) => chat_id,
+                        Err(e) => return bad_request(e),
+                    };
+                    let reaction = match serde_json::from_slice::<ReactionRequest>(req.body()) {
+                        Ok(reaction) => reaction,
+                        Err(e) => return bad_request(format!("unable to parse json: {:?}", e)),
+                    };
+                    match svc.add_reaction(
+                        chat_id,
+                        params["messageId"],
+                        reaction.user_id,
+                        &reaction.emoji,
+                    ) {
+                        Ok(message) => to_json(&message),
+                        Err(e) => chat_error(e),
+                    }
+                },
+            )
+            // Removes a reaction from a message (query params userId and emoji required)
+            .register(
+                "/chats/:chatId/messages/:messageId/reactions",
+                http::Method::DELETE,
+                |svc, params, query, _| {
+                    let chat_id = match id_param(&params, "chatId") {
+                        Ok(chat_id) => chat_id,
+                        Err(e) => return bad_request(e),
+                    };
+                    let user_id = match id_query(&query, "userId") {
+                        Ok(user_id) => user_id,
+                        Err(e) => return bad_request(e),
+                    };
+                    let emoji = match query.as_ref().and_then(|query| query.get("emoji")) {
+                        Some(emoji) => emoji,
+                        None => return bad_request("missing query parameter emoji"),
+                    };
+                    match svc.remove_reaction(chat_id, params["messageId"], user_id, emoji) {
+                        Ok(message) => to_json(&message),
+                        Err(e) => chat_error(e),
+                    }
+                },
+            )
+            // Pins a message to the top of the chat
+            .register(
+                "/chats/:chatId/messages/:messageId/pin",
+                http::Method::POST,
+                |svc, params, _, req| {
+                    let chat_id = match id_param(&params, "chatId") {
+                        Ok(chat_id) => chat_id,
+                        Err(e) => return bad_request(e),
+                    };
+                    let pin = match serde_json::from_slice::<PinRequest>(req.body()) {
+                        Ok(pin) => pin,
+                        Err(e) => return bad_request(format!("unable to parse json: {:?}", e)),
+                    };
+                    match svc.pin_message(chat_id, params["messageId"], pin.user_id) {
+                        Ok(pin) => to_json(&pin),
+                        Err(e) => chat_error(e),
+                    }
+                },
+            )
+            // Unpins a message (query param userId required)
+            .register(
+                "/chats/:chatId/messages/:messageId/pin",
+                http::Method::DELETE,
+                |svc, params, query, _| {
+                    let chat_id = match id_param(&params, "chatId") {
+                        Ok(chat_id) => chat_id,
+                        Err(e) => return bad_request(e),
+                    };
+                    let user_id = match id_query(&query, "userId") {
+                        Ok(user_id) => user_id,
+                        Err(e) => return bad_request(e),
+                    };
+                    match svc.unpin_message(chat_id, params["messageId"], user_id) {
+                        Ok(()) => status_ok(),
+                        Err(e) => chat_error(e),
+                    }
+                },
+            )
+            // Saves the user's unsent text for a chat (query param userId required)
+            .register(
+                "/chats/:chatId/draft",
+                http::Method::PUT,
+                |svc, params, query, req| {
+                    let chat_id = match id_param(&params, "chatId") {
+                        Ok(chat_id) => chat_id,
+                        Err(e) => return bad_request(e),
+                    };
+                    let user_id = match id_query(&query, "userId") {
+                        Ok(user_id) => user_id,
+                        Err(e) => return bad_request(e),
+                    };
+                    let draft = match serde_json::from_slice::<Draft>(req.body()) {
+                        Ok(draft) => draft,
+                        Err(e) => return bad_request(format!("unable to parse json: {:?}", e)),
+                    };
+                    match svc.set_draft(chat_id, user_id, draft) {
+                        Ok(draft) => to_json(&draft),
+                        Err(e) => chat_error(e),
+                    }
+                },
+            )
+            // Gets the user's draft for a chat (query param userId required)
+            .register(
+                "/chats/:chatId/draft",
+                http::Method::GET,
+                |svc, params, query, _| {
+                    let chat_id = match id_param(&params, "chatId") {
+                        Ok(chat_id) => chat_id,
+                        Err(e) => return bad_request(e),
+                    };
+                    let user_id = match id_query(&query, "userId") {
+                        Ok(user_id) => user_id,
+                        Err(e) => return bad_request(e),
+                    };
+                    match svc.get_draft(chat_id, user_id) {
+                        Ok(Some(draft)) => to_json(draft),
+                        Ok(None) => not_found(),
+                        Err(e) => chat_error(e),
+                    }
+                },
+            )
+            // Discards the user's draft for a chat (query param userId required)
+            .register(
+                "/chats/:chatId/draft",
+                http::Method::DELETE,
+                |svc, params, query, _| {
+                    let chat_id = match id_param(&params, "chatId") {
+                        Ok(chat_id) => chat_id,
+                        Err(e) => return bad_request(e),
+                    };
+                    let user_id = match id_query(&query, "userId") {
+                        Ok(user_id) => user_id,
+                        Err(e) => return bad_request(e),
+                    };
+                    match svc.delete_draft(chat_id, user_id) {
+                        Ok(true) => status_ok(),
+                        Ok(false) => not_found(),
+                        Err(e) => chat_error(e),
+                    }
+                },
+            )
+            // Lists a chat's pinned messages
+            .register(
+                "/chats/:chatId/pins",
+                http::Method::GET,
+                |svc, params, _, _| {
+                    let chat_id = match id_param(&params, "chatId") {
+                        Ok(chat_id) => chat_id,
+                        Err(e) => return bad_request(e),
+                    };
+                    match svc.get_pins(chat_id) {
+                        Ok(pins) => to_json(&pins),
+                        Err(e) => chat_error(e),
+                    }
+                },
+            )
+            // Lists a message and every reply under it
+            .register(
+                "/chats/:chatId/messages/:messageId/thread",
+                http::Method::GET,
+                |svc, params, _, _| {
+                    let chat_id = match id_param(&params, "chatId") {
+                        Ok(chat_id) => chat_id,
+                        Err(e) => return bad_request(e),
+                    };
+                    match svc.get_thread(chat_id, params["messageId"]) {
+                        Ok(thread) => to_json(&thread),
+                        Err(e) => chat_error(e),
+                    }
+                },
+            )
+            // Acknowledges that a message reached the recipient's device
+            .register(
+                "/chats/:chatId/messages/:messageId/ack",
+                http::Method::POST,
+                |svc, params, _, req| {
+                    let chat_id = match id_param(&params, "chatId") {
+                        Ok(chat_id) => chat_id,
+                        Err(e) => return bad_request(e),
+                    };
+                    let ack = match serde_json::from_slice::<DeliveryAck>(req.body()) {
+                        Ok(ack) => ack,
+                        Err(e) => return bad_request(format!("unable to parse json: {:?}", e)),
+                    };
+                    match svc.ack_delivery(chat_id, params["messageId"], ack.user_id) {
+                        Ok(message) => to_json(&message),
+                        Err(e) => chat_error(e),
+                    }
+                },
+            )
+            // Advances a participant's read cursor
+            .register(
+                "/chats/:chatId/read",
+                http::Method::POST,
+                |svc, params, _, req| {
+                    let chat_id = match id_param(&params, "chatId") {
+                        Ok(chat_id) => chat_id,
+                        Err(e) => return bad_request(e),
+                    };
+                    let receipt = match serde_json::from_slice::<ReadReceipt>(req.body()) {
+                        Ok(receipt) => receipt,
+                        Err(e) => return bad_request(format!("unable to parse json: {:?}", e)),
+                    };
+                    match svc.mark_read(chat_id, receipt.user_id, receipt.message_id.as_deref()) {
+                        Ok(_) => status_ok(),
+                        Err(e) => chat_error(e),
+                    }
+                },
+            )
+            // Lists each participant's read cursor
+            .register(
+                "/chats/:chatId/read",
+                http::Method::GET,
+                |svc, params, _, _| {
+                    let chat_id = match id_param(&params, "chatId") {
+                        Ok(chat_id) => chat_id,
+                        Err(e) => return bad_request(e),
+                    };
+                    match svc.get_read_cursors(chat_id) {
+                        Ok(cursors) => to_json(&cursors),
+                        Err(e) => chat_error(e),
+                    }
+                },
+            )
+            // Searches the messages of a user's chats (query params userId and q required)
+            .register("/search", http::Method::GET, |svc, _, query, _| {
+                let user_id = match id_query(&query, "userId") {
+                    Ok(user_id) => user_id,
+                    Err(e) => return bad_request(e),
+                };
+                let limit = match query.as_ref().and_then(|query| query.get("limit")) {
+                    Some(limit) => match limit.parse::<usize>() {
+                        Ok(limit) => limit,
+                        Err(e) => return bad_request(format!("unable to parse limit: {:?}", e)),
+                    },
+                    None => DEFAULT_SEARCH_LIMIT,
+                };
+                match query.as_ref().and_then(|query| query.get("q")) {
+                    Some(q) => to_json(&svc.search(user_id, q, limit)),
+                    None => bad_request("missing query parameter q"),
+                }
+            })
+            // Uploads a file from the raw request body (query param userId required)
+            .register("/attachments", http::Method::POST, |svc, _, query, req| {
+                let user_id = match id_query(&query, "userId") {
+                    Ok(user_id) => user_id,
+                    Err(e) => return bad_request(e),
+                };
+                let mime_type = req
+                    .headers()
+                    .get(http::header::CONTENT_TYPE)
+                    .and_then(|value| value.to_str().ok())
+                    .unwrap_or("application/octet-stream");
+                match svc.upload_attachment(user_id, mime_type, req.body()) {
+                    Ok(attachment) => to_json(&attachment),
+                    Err(e) => chat_error(e),
+                }
+            })
+            // Downloads a file, or the part of it given by a Range header. The user (query param
+            // userId required) must have uploaded it or share a chat it was sent in.
+            .register(
+                "/attachments/:sha256",
+                http::Method::GET,
+                |svc, params, query, req| {
+                    let user_id = match id_query(&query, "userId") {
+                        Ok(user_id) => user_id,
+                        Err(e) => return bad_request(e),
+                    };
+                    let (attachment, data) = match svc.get_attachment(user_id, params["sha256"]) {
+                        Ok(found) => found,
+                        Err(e) => return chat_error(e),
+                    };
+                    let len = data.len() as u64;
+                    let range = match req.headers().get(http::header::RANGE) {
+                        Some(range) => attachments::parse_range(range.to_str().unwrap_or(""), len),
+                        None => ByteRange::Full,
+                    };
+                    let mut res = http::Response::builder();
+                    res.header(http::header::CONTENT_TYPE, attachment.mime_type.as_str())
+                        .header(http::header::ACCEPT_RANGES, "bytes")
+                        .header(http::header::ETAG, format!("\"{}\"", attachment.sha256));
+                    let body = match range {
+                        ByteRange::Full => data,
+                        ByteRange::Partial(start, end) => {
+                            res.status(http::StatusCode::PARTIAL_CONTENT).header(
+                                http::header::CONTENT_RANGE,
+                                format!("bytes {}-{}/{}", start, end, len),
+                            );
+                            data[start as usize..=end as usize].to_vec()
+                        }
+                        ByteRange::Unsatisfiable => {
+                            res.status(http::StatusCode::RANGE_NOT_SATISFIABLE)
+                                .header(http::header::CONTENT_RANGE, format!("bytes */{}", len));
+                            Vec::new()
+                        }
+                    };
+                    res.body(body).expect("unable to create response")
+                },
+            )
+            // Lists a chat's events after the sequence number given by `since` (default 0)
+            .register(
+                "/chats/:chatId/events",
+                http::Method::GET,
+                |svc, params, query, _| {
+                    let chat_id = match id_param(&params, "chatId") {
+                        Ok(chat_id) => chat_id,
+                        Err(e) => return bad_request(e),
+                    };
+                    let since = match query.as_ref().and_then(|query| query.get("since")) {
+                        Some(since) => match since.parse::<u64>() {
+                            Ok(since) => since,
+                            Err(e) => {
+                                return bad_request(format!("unable to parse since: {:?}", e))
+                            }
+                        },
+                        None => 0,
+                    };
+                    match svc.get_events(chat_id, since) {
+                        Ok(events) => to_json(&events),
+                        Err(e) => chat_error(e),
+                    }
+                },
+            )
+            // Shows or clears a participant's typing indicator
+            .register(
+                "/chats/:chatId/typing",
+                http::Method::POST,
+                |svc, params, _, req| {
+                    let chat_id = match id_param(&params, "chatId") {
+                        Ok(chat_id) => chat_id,
+                        Err(e) => return bad_request(e),
+                    };
+                    let typing = match serde_json::from_slice::<TypingRequest>(req.body()) {
+                        Ok(typing) => typing,
+                        Err(e) => return bad_request(format!("unable to parse json: {:?}", e)),
+                    };
+                    match svc.set_typing(chat_id, typing.user_id, typing.typing.unwrap_or(true)) {
+                        Ok(()) => status_ok(),
+                        Err(e) => chat_error(e),
+                    }
+                },
+            )
+            // Shows whether a user is online, when they were last seen and where they're typing
+            .register(
+                "/users/:userId/presence",
+                http::Method::GET,
+                |svc, params, _, _| match id_param(&params, "userId") {
+                    Ok(user_id) => to_json(&svc.get_presence(user_id)),
+                    Err(e) => bad_request(e),
+                },
+            )
+            // Sets a chat's retention policy (maxAge in milliseconds, maxCount)
+            .register(
+                "/chats/:chatId/retention",
+                http::Method::POST,
+                |svc, params, _, req| {
+                    let chat_id = match id_param(&params, "chatId") {
+                        Ok(chat_id) => chat_id,
+                        Err(e) => return bad_request(e),
+                    };
+                    let request = match serde_json::from_slice::<RetentionRequest>(req.body()) {
+                        Ok(request) => request,
+                        Err(e) => return bad_request(format!("unable to parse json: {:?}", e)),
+                    };
+                    match svc.set_retention(chat_id, request.user_id, request.policy) {
+                        Ok(policy) => to_json(&policy),
+                        Err(e) => chat_error(e),
+                    }
+                },
+            )
+            // Shows a chat's own retention policy
+            .register(
+                "/chats/:chatId/retention",
+                http::Method::GET,
+                |svc, params, _, _| {
+                    let chat_id = match id_param(&params, "chatId") {
+                        Ok(chat_id) => chat_id,
+                        Err(e) => return bad_request(e),
+                    };
+                    match svc.get_retention(chat_id) {
+                        Ok(policy) => to_json(&policy),
+                        Err(e) => chat_error(e),
+                    }
+                },
+            )
+            // Changes a chat's title, description or avatar
+            .register(
+                "/chats/:chatId",
+                http::Method::PATCH,
+                |svc, params, _, req| {
+                    let chat_id = match id_param(&params, "chatId") {
+                        Ok(chat_id) => chat_id,
+                        Err(e) => return bad_request(e),
+                    };
+                    let update = match serde_json::from_slice::<ChatUpdate>(req.body()) {
+                        Ok(update) => update,
+                        Err(e) => return bad_request(format!("unable to parse json: {:?}", e)),
+                    };
+                    match svc.update_chat(chat_id, update) {
+                        Ok(chat) => to_json(&chat),
+                        Err(e) => chat_error(e),
+                    }
+                },
+            )
+            // Mutes, archives or pins a chat for one participant
+            .register(
+                "/chats/:chatId/settings",
+                http::Method::PATCH,
+                |svc, params, _, req| {
+                    let chat_id = match id_param(&params, "chatId") {
+                        Ok(chat_id) => chat_id,
+                        Err(e) => return bad_request(e),
+                    };
+                    let update = match serde_json::from_slice::<ChatSettingsUpdate>(req.body()) {
+                        Ok(update) => update,
+                        Err(e) => return bad_request(format!("unable to parse json: {:?}", e)),
+                    };
+                    match svc.update_chat_settings(chat_id, update) {
+                        Ok(settings) => to_json(&settings),
+                        Err(e) => chat_error(e),
+                    }
+                },
+            )
+            // Blocks a user (body {"blockedUserId": ..})
+            .register(
+                "/users/:userId/blocks",
+                http::Method::POST,
+                |svc, params, _, req| {
+                    let user_id = match id_param(&params, "userId") {
+                        Ok(user_id) => user_id,
+                        Err(e) => return bad_request(e),
+                    };
+                    let block = match serde_json::from_slice::<BlockRequest>(req.body()) {
+                        Ok(block) => block,
+                        Err(e) => return bad_request(format!("unable to parse json: {:?}", e)),
+                    };
+                    match svc.block_user(user_id, block.blocked_user_id) {
+                        Ok(_) => to_json(&svc.get_blocked(user_id)),
+                        Err(e) => chat_error(e),
+                    }
+                },
+            )
+            // Lists the users a user has blocked
+            .register(
+                "/users/:userId/blocks",
+                http::Method::GET,
+                |svc, params, _, _| match id_param(&params, "userId") {
+                    Ok(user_id) => to_json(&svc.get_blocked(user_id)),
+                    Err(e) => bad_request(e),
+                },
+            )
+            // Unblocks a user
+            .register(
+                "/users/:userId/blocks/:blockedUserId",
+                http::Method::DELETE,
+                |svc, params, _, _| {
+                    let user_id = match id_param(&params, "userId") {
+                        Ok(user_id) => user_id,
+                        Err(e) => return bad_request(e),
+                    };
+                    let blocked_user_id = match id_param(&params, "blockedUserId") {
+                        Ok(blocked_user_id) => blocked_user_id,
+                        Err(e) => return bad_request(e),
+                    };
+                    if svc.unblock_user(user_id, blocked_user_id) {
+                        to_json(&svc.get_blocked(user_id))
+                    } else {
+                        not_found()
+                    }
+                },
+            )
+            // Lists messages mentioning a user, newest first (optional query param limit)
+            .register(
+                "/users/:userId/mentions",
+                http::Method::GET,
+                |svc, params, query, _| {
+                    let user_id = match id_param(&params, "userId") {
+                        Ok(user_id) => user_id,
+                        Err(e) => return bad_request(e),
+                    };
+                    let limit = match query.as_ref().and_then(|query| query.get("limit")) {
+                        Some(limit) => match limit.parse::<usize>() {
+                            Ok(limit) => limit,
+                            Err(e) => {
+                                return bad_request(format!("unable to parse limit: {:?}", e))
+                            }
+                        },
+                        None => DEFAULT_MENTIONS_LIMIT,
+                    };
+                    to_json(&svc.get_mentions(user_id, limit))
+                },
+            )
+            // Reports how much of each quota a user has used
+            .register(
+                "/users/:userId/usage",
+                http::Method::GET,
+                |svc, params, _, _| match id_param(&params, "userId") {
+                    Ok(user_id) => to_json(&svc.get_usage(user_id)),
+                    Err(e) => bad_request(e),
+                },
+            )
+            // Lists a user's messages waiting for their sendAt time
+            .register(
+                "/users/:userId/scheduled",
+                http::Method::GET,
+                |svc, params, _, _| match id_param(&params, "userId") {
+                    Ok(user_id) => to_json(&svc.get_scheduled(user_id)),
+                    Err(e) => bad_request(e),
+                },
+            )
+            // Cancels a scheduled message, returning it
+            .register(
+                "/users/:userId/scheduled/:messageId",
+                http::Method::DELETE,
+                |svc, params, _, _| {
+                    let user_id = match id_param(&params, "userId") {
+                        Ok(user_id) => user_id,
+                        Err(e) => return bad_request(e),
+                    };
+                    match svc.cancel_scheduled(user_id, params["messageId"]) {
+                        Ok(scheduled) => to_json(&scheduled),
+                        Err(e) => chat_error(e),
+                    }
+                },
+            )
+            // Exports everything held about a user (admin only)
+            .register("/admin/users/:userId/export", http::Method::GET, {
+                let admin_token = config.admin_token.clone();
+                move |svc, params, _, req| {
+                    if let Some(res) = check_admin(&req, admin_token.as_deref()) {
+                        return res;
+                    }
+                    match id_param(&params, "userId") {
+                        Ok(user_id) => to_json(&svc.export_user(user_id)),
+                        Err(e) => bad_request(e),
+                    }
+                }
+            })
+            // Erases a user's messages, contacts and uploads (admin only)
+            .register("/admin/users/:userId/erase", http::Method::POST, {
+                let admin_token = config.admin_token.clone();
+                move |svc, params, _, req| {
+                    if let Some(res) = check_admin(&req, admin_token.as_deref()) {
+                        return res;
+                    }
+                    match id_param(&params, "userId") {
+                        Ok(user_id) => to_json(&svc.erase_user(user_id)),
+                        Err(e) => bad_request(e),
+                    }
+                }
+            })
+            // Subscribes an endpoint to webhooks (admin only, body {"url", "events", "secret"})
+            .register("/admin/webhooks", http::Method::POST, {
+                let admin_token = config.admin_token.clone();
+                move |svc, _, _, req| {
+                    if let Some(res) = check_admin(&req, admin_token.as_deref()) {
+                        return res;
+                    }
+                    let request = match serde_json::from_slice::<SubscriptionRequest>(req.body()) {
+                        Ok(request) => request,
+                        Err(e) => return bad_request(format!("unable to parse json: {:?}", e)),
+                    };
+                    match svc.subscribe_webhook(request) {
+                        Ok(subscription) => to_json(&subscription),
+                        Err(e) => chat_error(e),
+                    }
+                }
+            })
+            // Lists webhook subscriptions (admin only)
+            .register("/admin/webhooks", http::Method::GET, {
+                let admin_token = config.admin_token.clone();
+                move |svc, _, _, req| match check_admin(&req, admin_token.as_deref()) {
+                    Some(res) => res,
+                    None => to_json(&svc.get_webhooks()),
+                }
+            })
+            // Removes a webhook subscription and its pending deliveries (admin only)
+            .register("/admin/webhooks/:webhookId", http::Method::DELETE, {
+                let admin_token = config.admin_token.clone();
+                move |svc, params, _, req| {
+                    if let Some(res) = check_admin(&req, admin_token.as_deref()) {
+                        return res;
+                    }
+                    match id_param(&params, "webhookId") {
+                        Ok(webhook_id) if svc.unsubscribe_webhook(webhook_id) => status_ok(),
+                        Ok(_) => not_found(),
+                        Err(e) => bad_request(e),
+                    }
+                }
+            })
+            // Lists webhook deliveries that failed every attempt (admin only)
+            .register("/admin/webhooks/dead-letters", http::Method::GET, {
+                let admin_token = config.admin_token.clone();
+                move |svc, _, _, req| match check_admin(&req, admin_token.as_deref()) {
+                    Some(res) => res,
+                    None => to_json(&svc.get_dead_letters()),
+                }
+            })
+            // Retries a dead-lettered webhook delivery (admin only)
+            .register(
+                "/admin/webhooks/dead-letters/:deliveryId/retry",
+                http::Method::POST,
+                {
+                    let admin_token = config.admin_token.clone();
+                    move |svc, params, _, req| {
+                        if let Some(res) = check_admin(&req, admin_token.as_deref()) {
+                            return res;
+                        }
+                        match id_param(&params, "deliveryId") {
+                            Ok(delivery_id) if svc.redeliver_webhook(delivery_id) => status_ok(),
+                            Ok(_) => not_found(),
+                            Err(e) => bad_request(e),
+                        }
+                    }
+                },
+            )
+            // Lists messages flagged by the message filters, oldest first (admin only)
+            .register("/admin/flagged", http::Method::GET, {
+                let admin_token = config.admin_token.clone();
+                move |svc, _, _, req| match check_admin(&req, admin_token.as_deref()) {
+                    Some(res) => res,
+                    None => to_json(&svc.get_flagged()),
+                }
+            })
+            // Approves or removes a flagged message (admin only, body {"action": "approve" or
+            // "remove"})
+            .register("/admin/flagged/:flagId", http::Method::POST, {
+                let admin_token = config.admin_token.clone();
+                move |svc, params, _, req| {
+                    if let Some(res) = check_admin(&req, admin_token.as_deref()) {
+                        return res;
+                    }
+                    let flag_id = match id_param(&params, "flagId") {
+                        Ok(flag_id) => flag_id,
+                        Err(e) => return bad_request(e),
+                    };
+                    let decision = match serde_json::from_slice::<ReviewDecision>(req.body()) {
+                        Ok(decision) => decision,
+                        Err(e) => return bad_request(format!("unable to parse json: {:?}", e)),
+                    };
+                    match svc.resolve_flag(flag_id, decision.action) {
+                        Ok(flag) => to_json(&flag),
+                        Err(e) => chat_error(e),
+                    }
+                }
+            })
+            // Lists every export and erasure (admin only)
+            .register("/admin/audit", http::Method::GET, {
+                let admin_token = config.admin_token.clone();
+                move |svc, _, _, req| match check_admin(&req, admin_token.as_deref()) {
+                    Some(res) => res,
+                    None => to_json(&svc.get_audit_log()),
+                }
+            });
+        let router = match config.message_rate_limit {
+            Some(limit) => router.rate_limit(
+                "/chats/:chatId/messages",
+                http::Method::POST,
+                limit,
+                message_sender,
+            ),
+            None => router,
+        }
+        .build();
 
         poll.register(&listener, token, Ready::readable(), PollOpt::edge())?;
         Ok(Server {
             token,
             next_token,
-            listener,
+            listener: Some(listener),
             events,
             connections,
             poll,
             router,
+            max_request_size: config.max_request_size(),
+            limits: ConnectionLimits::new(&config),
+            sweep_interval: Duration::from_millis(config.retention_sweep_interval),
+            next_sweep: Instant::now(),
+            webhook_connections: HashMap::new(),
+            signals: None,
+            grace_period: Duration::from_millis(config.shutdown_grace_period),
+            shutdown_deadline: None,
+            accept_paused_until: None,
         })
     }
 
+    /// Starts shutting down when the process gets SIGTERM or SIGINT
+    pub fn handle_signals(&mut self) -> std::io::Result<()> {
+        let signals = Signals::install()?;
+        let token = Token(self.next_token as usize);
+        self.next_token += 1;
+        self.poll.register(
+            &EventedFd(&signals.fd()),
+            token,
+            Ready::readable(),
+            PollOpt::edge(),
+        )?;
+        self.signals = Some((token, signals));
+        Ok(())
+    }
+
+    /// Stops accepting connections and lets the open ones finish what they are doing, up to the
+    /// grace period. Keep-alive connections are closed once they have no request in flight, and
+    /// no new webhook deliveries are started. Attachments and chats are written to disk as they
+    /// change, so there is nothing left to flush.
+    pub fn shutdown(&mut self) {
+        if self.shutdown_deadline.is_some() {
+            return;
+        }
+        println!(
+            "shutting down, waiting up to {:?} for {} connections",
+            self.grace_period,
+            self.connections.len() + self.webhook_connections.len()
+        );
+        self.listener = None;
+        self.shutdown_deadline = Some(Instant::now() + self.grace_period);
+        self.close_idle_connections();
+    }
+
+    /// Whether the server has shut down - every connection has finished or the grace period is
+    /// over
+    pub fn is_finished(&self) -> bool {
+        match self.shutdown_deadline {
+            Some(deadline) => {
+                (self.connections.is_empty() && self.webhook_connections.is_empty())
+                    || Instant::now() >= deadline
+            }
+            None => false,
+        }
+    }
+
+    /// Closes connections with no request part way in or response still going out
+    fn close_idle_connections(&mut self) {
+        let idle = self
+            .connections
+            .iter()
+            .filter(|(_, client)| client.request_started.is_none() && client.outgoing.is_empty())
+            .map(|(token, _)| *token)
+            .collect::<Vec<_>>();
+        for client_token in idle {
+            self.close_client(client_token);
+        }
+    }
+
+    /// How long `poll` can wait for socket events before there's other work to do - the next
+    /// sweep, connection timeout, end of the shutdown grace period, retry of accepting
+    /// connections, scheduled message, webhook attempt or webhook timeout
+    fn timeout(&mut self) -> Duration {
+        let now = chat_service::timestamp();
+        let limits = self.limits;
+        let next_sweep = self
+            .connections
+            .values()
+            .map(|client| client.deadline(&limits))
+            .chain(self.shutdown_deadline)
+            .chain(self.accept_paused_until)
+            .fold(self.next_sweep, Instant::min);
+        let shutting_down = self.shutdown_deadline.is_some();
+        let service = self.router.service_mut();
+        // deliveries aren't started once shutting down, so a due one mustn't wake the loop
+        let deadlines = [
+            service.next_scheduled(),
+            service
+                .webhooks_mut()
+                .next_attempt()
+                .filter(|_| !shutting_down),
+            self.webhook_connections.values().map(|c| c.deadline).min(),
+        ];
+        deadlines
+            .iter()
+            .flatten()
+            .map(|deadline| Duration::from_millis(deadline.saturating_sub(now)))
+            .fold(
+                next_sweep.saturating_duration_since(Instant::now()),
+                Duration::min,
+            )
+    }
+
     pub fn poll(&mut self) -> Result<(), Box<dyn Error>> {
-        self.poll.poll(&mut self.events, None)?;
-        for event in self.events.iter() {
-            match event.token() {
-                token if token == self.token => loop {
-                    match self.listener.accept() {
-                        Ok((socket, _)) => {
-                            let client_token = Token(self.next_token as usize);
-                            self.next_token += 1;
-                            self.poll.register(
-                                &socket,
-                                client_token,
-                                Ready::readable(),
-                                PollOpt::edge(),
-                            )?;
-                            self.connections.insert(client_token, Client::new(socket));
-                        }
-                        Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                            break;
+        let timeout = self.timeout();
+        self.poll.poll(&mut self.events, Some(timeout))?;
+        let sent = self
+            .router
+            .service_mut()
+            .send_scheduled(chat_service::timestamp());
+        if sent > 0 {
+            println!("sent {} scheduled messages", sent);
+        }
+        if Instant::now() >= self.next_sweep {
+            let removed = self
+                .router
+                .service_mut()
+                .sweep_expired(chat_service::timestamp());
+            if removed > 0 {
+                println!("removed {} expired messages", removed);
+            }
+            self.router.sweep_rate_limits(chat_service::timestamp());
+            self.next_sweep = Instant::now() + self.sweep_interval;
+        }
+        self.router.service_mut().save();
+        let events = self
+            .events
+            .iter()
+            .map(|event| (event.token(), event.readiness()))
+            .collect::<Vec<_>>();
+        for (event_token, readiness) in events {
+            match event_token {
+                token if token == self.token => {
+                    if self.accept_paused_until.is_none() {
+                        self.accept_connections();
+                    }
+                }
+                token if self.signals.as_ref().is_some_and(|(t, _)| *t == token) => {
+                    let received = self
+                        .signals
+                        .as_ref()
+                        .is_some_and(|(_, signals)| signals.drain());
+                    if received {
+                        self.shutdown();
+                    }
+                }
+                token if self.webhook_connections.contains_key(&token) => {
+                    self.handle_webhook(token);
+                }
+                client_token => {
+                    if !self.handle_client(client_token, readiness) {
+                        self.close_client(client_token);
+                    }
+                }
+            }
+        }
+        // the listener is edge triggered, so connections left waiting while paused won't raise
+        // another event
+        if self
+            .accept_paused_until
+            .is_some_and(|until| Instant::now() >= until)
+        {
+            self.accept_paused_until = None;
+            self.accept_connections();
+        }
+        self.expire_connections();
+        self.expire_webhooks();
+        if self.shutdown_deadline.is_some() {
+            self.close_idle_connections();
+        } else {
+            self.start_webhooks();
+        }
+        Ok(())
+    }
+
+    /// Accepts every connection waiting on the listener. When accepting fails, such as when
+    /// the process is out of file descriptors, it pauses for `ACCEPT_BACKOFF` rather than
+    /// retrying straight away.
+    fn accept_connections(&mut self) {
+        while let Some(listener) = &self.listener {
+            let (mut socket, address) = match listener.accept() {
+                Ok(accepted) => accepted,
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                // the client gave up before it was accepted
+                Err(ref e) if e.kind() == std::io::ErrorKind::ConnectionAborted => continue,
+                Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    eprintln!("unable to accept connections, pausing: {}", e);
+                    self.accept_paused_until = Some(Instant::now() + ACCEPT_BACKOFF);
+                    break;
+                }
+            };
+            if self.connections.len() >= self.limits.max_connections {
+                eprintln!(
+                    "turning away {}, {} connections are open",
+                    address,
+                    self.connections.len()
+                );
+                let response = status_code_msg(
+                    http::StatusCode::SERVICE_UNAVAILABLE,
+                    "Too many connections.",
+                    "text/plain",
+                );
+                let _ = socket.write(&response_to_bytes(response));
+                continue;
+            }
+            let client_token = Token(self.next_token as usize);
+            self.next_token += 1;
+            let registered =
+                self.poll
+                    .register(&socket, client_token, Ready::readable(), PollOpt::edge());
+            if let Err(e) = registered {
+                eprintln!("unable to register connection from {}: {}", address, e);
+                continue;
+            }
+            let mut client = Client::new(socket);
+            client.address = Some(address.ip());
+            self.connections.insert(client_token, client);
+        }
+    }
+
+    fn close_client(&mut self, client_token: Token) {
+        let user_id = self
+            .connections
+            .remove(&client_token)
+            .and_then(|client| client.user_id);
+        if let Some(user_id) = user_id {
+            self.router.service_mut().user_disconnected(user_id);
+        }
+    }
+
+    /// Closes connections that have sat idle too long, or are too slow sending a request. Those
+    /// part way through a request are told it timed out.
+    fn expire_connections(&mut self) {
+        let now = Instant::now();
+        let expired = self
+            .connections
+            .iter()
+            .filter(|(_, client)| client.deadline(&self.limits) <= now)
+            .map(|(token, _)| *token)
+            .collect::<Vec<_>>();
+        for client_token in expired {
+            let client = self.connections.get_mut(&client_token).unwrap();
+            if client.request_started.is_some() {
+                eprintln!("request timed out on client {:?}", client_token);
+                let response = status_code_msg(
+                    http::StatusCode::REQUEST_TIMEOUT,
+                    "Request timed out.",
+                    "text/plain",
+                );
+                client.outgoing.extend(response_to_bytes(response));
+                let _ = client.flush();
+            }
+            self.close_client(client_token);
+        }
+    }
+
+    /// Opens a connection for each webhook delivery that is due. A connection that can't be
+    /// opened counts as a failed attempt.
+    fn start_webhooks(&mut self) {
+        let now = chat_service::timestamp();
+        let due = self.router.service_mut().webhooks_mut().take_due(now);
+        for (delivery, subscription) in due {
+            let connected = Target::parse(&subscription.url).and_then(|target| {
+                let socket =
+                    TcpStream::connect(&subscription.address).map_err(|e| e.to_string())?;
+                Ok((target, socket))
+            });
+            let (target, socket) = match connected {
+                Ok(connected) => connected,
+                Err(e) => {
+                    self.webhook_failed(delivery, e);
+                    continue;
+                }
+            };
+            let token = Token(self.next_token as usize);
+            self.next_token += 1;
+            let registered = self.poll.register(
+                &socket,
+                token,
+                Ready::readable() | Ready::writable(),
+                PollOpt::edge(),
+            );
+            if let Err(e) = registered {
+                self.webhook_failed(delivery, e.to_string());
+                continue;
+            }
+            let request = webhooks::request(&target, &subscription, &delivery);
+            let connection = WebhookConnection::new(socket, delivery, request, now);
+            self.webhook_connections.insert(token, connection);
+        }
+    }
+
+    /// Moves a webhook delivery along, and once it has a response or has failed closes the
+    /// connection and reports how it went
+    fn handle_webhook(&mut self, token: Token) {
+        let connection = match self.webhook_connections.get_mut(&token) {
+            Some(connection) => connection,
+            None => return,
+        };
+        let result = match connection.advance() {
+            Ok(None) => return,
+            Ok(Some(status)) if (200..300).contains(&status) => Ok(()),
+            Ok(Some(status)) => Err(format!("endpoint responded with status {}", status)),
+            Err(e) => Err(e),
+        };
+        let connection = self.webhook_connections.remove(&token).unwrap();
+        match result {
+            Ok(()) => {
+                println!(
+                    "delivered {} webhook {}",
+                    connection.delivery.event, connection.delivery.id
+                );
+                self.router
+                    .service_mut()
+                    .webhooks_mut()
+                    .delivered(connection.delivery.id);
+            }
+            Err(e) => self.webhook_failed(connection.delivery, e),
+        }
+    }
+
+    /// Gives up on webhook connections that have gone too long without a response
+    fn expire_webhooks(&mut self) {
+        let now = chat_service::timestamp();
+        let expired = self
+            .webhook_connections
+            .iter()
+            .filter(|(_, connection)| connection.deadline <= now)
+            .map(|(token, _)| *token)
+            .collect::<Vec<_>>();
+        for token in expired {
+            let connection = self.webhook_connections.remove(&token).unwrap();
+            self.webhook_failed(connection.delivery, "timed out".to_owned());
+        }
+    }
+
+    fn webhook_failed(&mut self, delivery: webhooks::Delivery, error: String) {
+        eprintln!(
+            "webhook delivery {} attempt {} failed: {}",
+            delivery.id,
+            delivery.attempts + 1,
+            error
+        );
+        self.router
+            .service_mut()
+            .webhooks_mut()
+            .failed(delivery, error, chat_service::timestamp());
+    }
+
+    /// Reads and answers whatever a client has sent, then writes as much of the response as the
+    /// socket will take. Returns false once the connection should be closed.
+    fn handle_client(&mut self, client_token: Token, readiness: Ready) -> bool {
+        let client = match self.connections.get_mut(&client_token) {
+            Some(client) => client,
+            None => return false,
+        };
+        let mut open = true;
+        if readiness.is_readable() {
+            loop {
+                match client.read() {
+                    Ok(0) => {
+                        // socket closed
+                        eprintln!("client socket closed {:?}", client_token);
+                        open = false;
+                        break;
+                    }
+                    Ok(_) => {}
+                    Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                    Err(e) => {
+                        eprintln!("error reading from client {:?}: {}", client_token, e);
+                        return false;
+                    }
+                }
+                if client.pending.len() > self.max_request_size {
+                    let response = status_code_msg(
+                        http::StatusCode::PAYLOAD_TOO_LARGE,
+                        "Request too large.",
+                        "text/plain",
+                    );
+                    client.outgoing.extend(response_to_bytes(response));
+                    let _ = client.flush();
+                    return false;
+                }
+            }
+            let consumed = match crate::parse::parse_pipelined(&client.pending) {
+                Ok((requests, consumed)) => {
+                    for mut request in requests {
+                        if let Some(address) = client.address {
+                            request.extensions_mut().insert(RemoteAddr(address));
                         }
-                        _ => unreachable!(),
-                    }
-                },
-                client_token => loop {
-                    let client = self.connections.get_mut(&client_token).unwrap();
-                    match client.read() {
-                        Ok(bytes_read) => {
-                            if bytes_read == 0 {
-                                // socket closed
-                                eprintln!("client socket closed {:?}", client_token);
-                                self.connections.remove(&client_token);
-                                break;
-                            }
-                            let requests = {
-                                match crate::parse::parse_buffer(&client.buffer[0..bytes_read]) {
-                                    Ok(requests) => requests,
-                                    Err(e) => {
-                                        eprintln!("error parsing buffer {:?}", e);
-                                        break;
-                                    }
+                        let user_id = request_user_id(&request);
+                        let response = self.router.route(request);
+                        // a connection stands for the first user a request of its succeeds for,
+                        // and only their later requests count as them being active
+                        if let Some(user_id) = user_id.filter(|_| response.status().is_success()) {
+                            let service = self.router.service_mut();
+                            match client.user_id {
+                                None => {
+                                    client.user_id = Some(user_id);
+                                    service.user_connected(user_id);
                                 }
-                            };
-                            for request in requests {
-                                let response = self.router.route(request);
-                                let response = response_to_string(response);
-                                client.socket.write_all(&response[..].as_bytes()).unwrap();
+                                Some(bound) if bound == user_id => service.user_seen(user_id),
+                                Some(_) => {}
                             }
                         }
-                        Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                            break;
-                        }
-                        _ => unreachable!(),
+                        // whatever the request changed is saved before the client is told
+                        self.router.service_mut().save();
+                        client.outgoing.extend(response_to_bytes(response));
                     }
-                },
+                    consumed
+                }
+                Err(e) => {
+                    eprintln!("error parsing buffer {:?}", e);
+                    let response = bad_request("Unable to parse request.");
+                    client.outgoing.extend(response_to_bytes(response));
+                    open = false;
+                    client.pending.len()
+                }
+            };
+            client.consume(consumed);
+            // a request's deadlines run from when its first bytes arrive
+            if client.pending.is_empty() {
+                client.request_started = None;
+            } else if consumed > 0 || client.request_started.is_none() {
+                client.request_started = Some(Instant::now());
             }
         }
-        Ok(())
+        if let Err(e) = client.flush() {
+            eprintln!("error writing to client {:?}: {}", client_token, e);
+            return false;
+        }
+        if !open {
+            return false;
+        }
+        // only ask to hear about writability while there's a response waiting to go out
+        let interest = if client.outgoing.is_empty() {
+            Ready::readable()
+        } else {
+            Ready::readable() | Ready::writable()
+        };
+        if interest != client.interest {
+            let reregistered =
+                self.poll
+                    .reregister(&client.socket, client_token, interest, PollOpt::edge());
+            if let Err(e) = reregistered {
+                eprintln!("error reregistering client {:?}: {}", client_token, e);
+                return false;
+            }
+            client.interest = interest;
+        }
+        true
     }
 }
 
@@ -239,7 +1650,492 @@ mod tests {
         let addr = "127.0.0.1:8080".parse().unwrap();
         let listener = TcpListener::bind(&addr).unwrap();
         let mut server = Server::new(listener).unwrap();
-        let sock = TcpStream::connect(&addr).unwrap();
+        let _sock = TcpStream::connect(&addr).unwrap();
+        server.poll().unwrap();
+    }
+
+    /// Sends a request down a connection to the server, returning the status and body of the
+    /// response
+    fn exchange(
+        server: &mut Server,
+        stream: &mut std::net::TcpStream,
+        request: &[u8],
+    ) -> (u16, Vec<u8>) {
+        stream.set_nonblocking(true).unwrap();
+        stream.write_all(request).unwrap();
+        let mut received = Vec::new();
+        for _ in 0..100 {
+            server.poll().unwrap();
+            let mut buffer = [0; 4096];
+            if let Ok(read) = stream.read(&mut buffer) {
+                received.extend_from_slice(&buffer[..read]);
+            }
+            let head_len = match received.windows(4).position(|w| w == b"\r\n\r\n") {
+                Some(end) => end + 4,
+                None => continue,
+            };
+            let head = String::from_utf8_lossy(&received[..head_len]).into_owned();
+            let len = head
+                .lines()
+                .find_map(|line| line.strip_prefix("Content-Length: "))
+                .map_or(0, |len| len.parse::<usize>().unwrap());
+            if received.len() >= head_len + len {
+                let status = head[9..12].parse().unwrap();
+                return (status, received[head_len..head_len + len].to_vec());
+            }
+        }
+        panic!("no response to {}", String::from_utf8_lossy(request));
+    }
+
+    #[test]
+    fn upload_and_download_attachments() {
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = Config {
+            blob_dir: std::env::temp_dir().join(format!("chat-mio-server-{}", std::process::id())),
+            ..Config::default()
+        };
+        let blob_dir = config.blob_dir.clone();
+        let _ = std::fs::remove_dir_all(&blob_dir);
+        let mut server = Server::with_config(listener, config).unwrap();
+        let mut stream = std::net::TcpStream::connect(addr).unwrap();
+
+        let upload = b"POST /attachments?userId=58534 HTTP/1.1\r\nContent-Type: image/png\r\nContent-Length: 6\r\n\r\n\x89PNG\x00\xff";
+        let (status, body) = exchange(&mut server, &mut stream, upload);
+        assert_eq!(status, 200);
+        let attachment = serde_json::from_slice::<crate::messages::Attachment>(&body).unwrap();
+        assert_eq!(
+            (attachment.size, attachment.mime_type.as_str()),
+            (6, "image/png")
+        );
+
+        let download = |user_id: &str, range: &str| {
+            format!(
+                "GET /attachments/{}{} HTTP/1.1\r\n{}\r\n",
+                attachment.sha256, user_id, range
+            )
+            .into_bytes()
+        };
+        let (status, body) = exchange(&mut server, &mut stream, &download("?userId=58534", ""));
+        assert_eq!((status, body.as_slice()), (200, &b"\x89PNG\x00\xff"[..]));
+        let partial = download("?userId=58534", "Range: bytes=1-3\r\n");
+        let (status, body) = exchange(&mut server, &mut stream, &partial);
+        assert_eq!((status, body.as_slice()), (206, &b"PNG"[..]));
+        assert_eq!(exchange(&mut server, &mut stream, &download("", "")).0, 400);
+
+        // other users can only download it once it's sent in a chat they're in
+        assert_eq!(
+            exchange(&mut server, &mut stream, &download("?userId=74827", "")).0,
+            404
+        );
+        let service = server.router.service_mut();
+        let chat = crate::messages::Chat {
+            id: 11872,
+            participant_ids: [58534, 74827],
+            ..Default::default()
+        };
+        service.add_chat(chat).unwrap();
+        // nor can they get at it by attaching it themselves, or making it the chat's avatar
+        let stolen = Message {
+            id: "stolen".to_owned(),
+            source_user_id: 74827,
+            destination_user_id: 58534,
+            attachments: vec![attachment.clone()],
+            ..Message::default()
+        };
+        assert_eq!(
+            service.send_message(11872, stolen),
+            Err(ChatError::AttachmentNotFound(attachment.sha256.clone()))
+        );
+        let update = crate::messages::ChatUpdate {
+            user_id: 74827,
+            title: None,
+            description: None,
+            avatar: Some(Some(attachment.sha256.clone())),
+        };
+        assert_eq!(
+            service.update_chat(11872, update),
+            Err(ChatError::AttachmentNotFound(attachment.sha256.clone()))
+        );
+        let message = Message {
+            id: "m1".to_owned(),
+            source_user_id: 58534,
+            destination_user_id: 74827,
+            attachments: vec![attachment.clone()],
+            ..Message::default()
+        };
+        service.send_message(11872, message).unwrap();
+        assert_eq!(
+            exchange(&mut server, &mut stream, &download("?userId=74827", "")).0,
+            200
+        );
+        assert_eq!(
+            exchange(&mut server, &mut stream, &download("?userId=68694", "")).0,
+            404
+        );
+        std::fs::remove_dir_all(&blob_dir).unwrap();
+    }
+
+    #[test]
+    fn bind_connections_to_users() {
+        use crate::messages::PresenceStatus;
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut server = Server::new(listener).unwrap();
+        let mut stream = std::net::TcpStream::connect(addr).unwrap();
+        let status =
+            |server: &mut Server, user_id| server.router.service_mut().get_presence(user_id).status;
+
+        // a request that fails doesn't bind the connection
+        let failed = b"GET /chats/1/events?userId=58534 HTTP/1.1\r\n\r\n";
+        assert_eq!(exchange(&mut server, &mut stream, failed).0, 404);
+        assert_eq!(status(&mut server, 58534), PresenceStatus::Offline);
+        let listed = b"GET /chats?userId=58534 HTTP/1.1\r\n\r\n";
+        assert_eq!(exchange(&mut server, &mut stream, listed).0, 200);
+        assert_eq!(status(&mut server, 58534), PresenceStatus::Online);
+
+        // naming someone else on the same connection doesn't bring them online
+        let other = b"GET /chats?userId=74827 HTTP/1.1\r\n\r\n";
+        assert_eq!(exchange(&mut server, &mut stream, other).0, 200);
+        assert_eq!(status(&mut server, 74827), PresenceStatus::Offline);
+        assert_eq!(
+            server.router.service_mut().get_presence(74827).last_seen,
+            None
+        );
+
+        drop(stream);
+        for _ in 0..100 {
+            if server.connections.is_empty() {
+                break;
+            }
+            server.poll().unwrap();
+        }
+        assert_eq!(status(&mut server, 58534), PresenceStatus::Offline);
+    }
+
+    #[test]
+    fn deliver_webhooks() {
+        let endpoint = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        endpoint.set_nonblocking(true).unwrap();
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let closed_url = format!("http://{}/", closed.local_addr().unwrap());
+        drop(closed);
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let config = Config {
+            retention_sweep_interval: 10,
+            ..Config::default()
+        };
+        let mut server = Server::with_config(listener, config).unwrap();
+        let service = server.router.service_mut();
+        let subscribe = |service: &mut ChatService, url: String, event: &str| {
+            let request = SubscriptionRequest {
+                url,
+                events: vec![event.to_owned()],
+                secret: "s3cret".to_owned(),
+            };
+            service.subscribe_webhook(request).unwrap();
+        };
+        let url = format!("http://{}/hooks", endpoint.local_addr().unwrap());
+        subscribe(service, url, "chatCreated");
+        subscribe(service, closed_url, "messageSent");
+        let chat = crate::messages::Chat {
+            id: 11872,
+            participant_ids: [58534, 74827],
+            ..Default::default()
+        };
+        service.add_chat(chat).unwrap();
+
+        // the endpoint answers once the whole request has arrived
+        let mut stream = None;
+        let mut received = Vec::new();
+        for _ in 0..100 {
+            server.poll().unwrap();
+            if stream.is_none() {
+                stream = endpoint.accept().ok().map(|(stream, _)| stream);
+            }
+            if let Some(stream) = stream.as_mut() {
+                stream.set_nonblocking(true).unwrap();
+                let mut buffer = [0; 4096];
+                if let Ok(read) = stream.read(&mut buffer) {
+                    received.extend_from_slice(&buffer[..read]);
+                }
+                if matches!(crate::parse::parse_pipelined(&received), Ok((requests, _)) if !requests.is_empty())
+                {
+                    break;
+                }
+            }
+        }
+        let (requests, _) = crate::parse::parse_pipelined(&received).unwrap();
+        let request = &requests[0];
+        let header = |name| request.headers()[name].to_str().unwrap();
+        assert_eq!(request.uri().path(), "/hooks");
+        assert_eq!(header("X-Chat-Event"), "chatCreated");
+        assert_eq!(
+            header("X-Chat-Signature"),
+            format!("sha256={}", webhooks::sign("s3cret", request.body()))
+        );
+        let payload = serde_json::from_slice::<serde_json::Value>(request.body()).unwrap();
+        assert_eq!(payload["data"]["id"], 11872);
+        stream
+            .unwrap()
+            .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+            .unwrap();
+        for _ in 0..100 {
+            if server.webhook_connections.is_empty() {
+                break;
+            }
+            server.poll().unwrap();
+        }
+        assert!(server.webhook_connections.is_empty());
+        let service = server.router.service_mut();
+        assert_eq!(service.webhooks_mut().next_attempt(), None);
+
+        // a delivery that can't connect is retried later
+        let message = Message {
+            id: "m1".to_owned(),
+            source_user_id: 58534,
+            destination_user_id: 74827,
+            ..Message::default()
+        };
+        service.send_message(11872, message).unwrap();
+        for _ in 0..100 {
+            server.poll().unwrap();
+            if server.webhook_connections.is_empty() {
+                break;
+            }
+        }
+        let service = server.router.service_mut();
+        let retry_at = service.webhooks_mut().next_attempt().unwrap();
+        assert!(retry_at > chat_service::timestamp());
+        assert!(service.get_dead_letters().is_empty());
+    }
+
+    #[test]
+    fn client_deadline() {
+        let limits = ConnectionLimits {
+            idle_timeout: Duration::from_secs(60),
+            header_timeout: Duration::from_secs(10),
+            min_transfer_rate: 1000,
+            max_connections: 1,
+        };
+        let mut client = Client::new(std::io::Cursor::new(Vec::new()));
+        let start = client.last_active;
+        assert_eq!(client.deadline(&limits), start + limits.idle_timeout);
+
+        let receive = |client: &mut Client<std::io::Cursor<Vec<u8>>>, bytes: &[u8]| {
+            client.socket.get_mut().extend_from_slice(bytes);
+            client.read().unwrap();
+        };
+        receive(&mut client, b"POST /chats HTTP/1.1\r\n");
+        let start = client.last_active;
+        client.request_started = Some(start);
+        assert_eq!(client.deadline(&limits), start + limits.header_timeout);
+        // the end of the headers is found even when it arrives split across reads
+        receive(&mut client, b"\r\n");
+        assert_eq!(client.head_len, Some(24));
+        // once the headers are in, each 1000 bytes buys another second
+        client.pending.resize(20_000, b'x');
+        assert_eq!(client.deadline(&limits), start + Duration::from_secs(20));
+        client.pending.resize(200_000, b'x');
+        assert_eq!(
+            client.deadline(&limits),
+            client.last_active + limits.idle_timeout
+        );
+        // the next request's headers are looked for once this one is taken off
+        client.consume(200_000);
+        assert_eq!(client.head_len, None);
+    }
+
+    #[test]
+    fn close_slow_connections() {
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = Config {
+            idle_timeout: 200,
+            header_timeout: 50,
+            max_connections: 2,
+            ..Config::default()
+        };
+        let mut server = Server::with_config(listener, config).unwrap();
+        let connect = |server: &mut Server| {
+            let stream = std::net::TcpStream::connect(addr).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let open = server.connections.len();
+            for _ in 0..100 {
+                server.poll().unwrap();
+                if server.connections.len() > open {
+                    break;
+                }
+            }
+            stream
+        };
+        let read = |mut stream: std::net::TcpStream| {
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        let mut slow = connect(&mut server);
+        slow.write_all(b"GET /chats?userId=1 HTTP/1.1\r\n").unwrap();
+        let idle = connect(&mut server);
+        assert_eq!(server.connections.len(), 2);
+        let turned_away = std::net::TcpStream::connect(addr).unwrap();
+        turned_away
+            .set_read_timeout(Some(Duration::from_millis(10)))
+            .unwrap();
+        for _ in 0..100 {
+            server.poll().unwrap();
+            if turned_away.peek(&mut [0]).is_ok() {
+                break;
+            }
+        }
+        assert!(read(turned_away).starts_with("HTTP/1.1 503"));
+
+        for _ in 0..100 {
+            if server.connections.is_empty() {
+                break;
+            }
+            server.poll().unwrap();
+        }
+        assert!(server.connections.is_empty());
+        assert!(read(slow).starts_with("HTTP/1.1 408"));
+        assert_eq!(read(idle), "");
+    }
+
+    #[test]
+    fn drop_client_that_cannot_reregister() {
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut server = Server::new(listener).unwrap();
+        let mut streams = Vec::new();
+        for _ in 0..2 {
+            streams.push(std::net::TcpStream::connect(addr).unwrap());
+        }
+        for _ in 0..100 {
+            if server.connections.len() == 2 {
+                break;
+            }
+            server.poll().unwrap();
+        }
+        let mut tokens = server.connections.keys().cloned().collect::<Vec<_>>();
+        tokens.sort();
+
+        // changing what the first client waits for fails once it has left the poll
+        let broken = server.connections.get_mut(&tokens[0]).unwrap();
+        server.poll.deregister(&broken.socket).unwrap();
+        broken.interest = Ready::writable();
+        assert!(!server.handle_client(tokens[0], Ready::empty()));
+        server.close_client(tokens[0]);
+
+        // only that connection is lost
+        let (status, _) = exchange(
+            &mut server,
+            &mut streams[1],
+            b"GET /users/1/usage HTTP/1.1\r\n\r\n",
+        );
+        assert_eq!(status, 200);
+        assert_eq!(server.connections.len(), 1);
+    }
+
+    #[test]
+    fn drain_on_shutdown() {
+        let start = |grace_period| {
+            let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+            let addr = listener.local_addr().unwrap();
+            let config = Config {
+                shutdown_grace_period: grace_period,
+                ..Config::default()
+            };
+            (Server::with_config(listener, config).unwrap(), addr)
+        };
+        // connects, and waits until the server has read what was sent
+        let connect = |server: &mut Server, addr, sent: &[u8]| {
+            let open = server.connections.len();
+            let mut stream = std::net::TcpStream::connect(addr).unwrap();
+            stream.write_all(sent).unwrap();
+            for _ in 0..100 {
+                server.poll().unwrap();
+                let received = sent.is_empty()
+                    || server
+                        .connections
+                        .values()
+                        .any(|client| client.request_started.is_some());
+                if server.connections.len() > open && received {
+                    break;
+                }
+            }
+            stream
+        };
+
+        let (mut server, addr) = start(5_000);
+        let mut busy = connect(&mut server, addr, b"GET /users/1/usage HTTP/1.1\r\n");
+        let mut idle = connect(&mut server, addr, b"");
+        assert_eq!(server.connections.len(), 2);
+        server.shutdown();
+        assert_eq!(server.connections.len(), 1);
+        assert!(!server.is_finished());
+        assert_eq!(idle.read(&mut [0; 16]).unwrap(), 0);
+        assert!(std::net::TcpStream::connect(addr).is_err());
+
+        // the request in flight is answered before the connection closes
+        busy.write_all(b"\r\n").unwrap();
+        for _ in 0..100 {
+            if server.is_finished() {
+                break;
+            }
+            server.poll().unwrap();
+        }
+        assert!(server.connections.is_empty());
+        let mut response = String::new();
+        busy.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"));
+
+        // a request that never finishes is given up on after the grace period
+        let (mut server, addr) = start(50);
+        let _stuck = connect(&mut server, addr, b"GET / HTTP/1.1\r\n");
+        server.shutdown();
+        let started = Instant::now();
+        while !server.is_finished() {
+            server.poll().unwrap();
+        }
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert_eq!(server.connections.len(), 1);
+    }
+
+    #[test]
+    fn hold_webhooks_on_shutdown() {
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let config = Config {
+            shutdown_grace_period: 5_000,
+            retention_sweep_interval: 60_000,
+            ..Config::default()
+        };
+        let mut server = Server::with_config(listener, config).unwrap();
+        let service = server.router.service_mut();
+        let request = SubscriptionRequest {
+            url: "http://127.0.0.1:9/hooks".to_owned(),
+            events: vec!["chatCreated".to_owned()],
+            secret: "s3cret".to_owned(),
+        };
+        service.subscribe_webhook(request).unwrap();
+        let chat = crate::messages::Chat {
+            id: 11872,
+            participant_ids: [58534, 74827],
+            ..Default::default()
+        };
+        service.add_chat(chat).unwrap();
+        let due = service.webhooks_mut().next_attempt().unwrap();
+        assert!(due <= chat_service::timestamp());
+
+        // the delivery is left due, and the loop sleeps until the grace period is over
+        server.shutdown();
         server.poll().unwrap();
+        assert!(server.webhook_connections.is_empty());
+        assert!(server.timeout() > Duration::from_secs(1));
+        let service = server.router.service_mut();
+        assert_eq!(service.webhooks_mut().next_attempt(), Some(due));
     }
 }
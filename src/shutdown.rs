@@ -0,0 +1,189 @@
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::ptr;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+/// Write end of the pipe the signal handler wakes the server through, -1 while no handler is
+/// installed
+static WRITE_FD: AtomicI32 = AtomicI32::new(-1);
+
+const SIGNALS: [libc::c_int; 2] = [libc::SIGTERM, libc::SIGINT];
+
+/// Where the calling thread's `errno` is kept, on the platforms where it's known how to find it
+#[cfg(any(target_os = "linux", target_os = "dragonfly"))]
+unsafe fn errno() -> Option<*mut libc::c_int> {
+    Some(libc::__errno_location())
+}
+
+#[cfg(any(target_os = "android", target_os = "netbsd", target_os = "openbsd"))]
+unsafe fn errno() -> Option<*mut libc::c_int> {
+    Some(libc::__errno())
+}
+
+#[cfg(any(target_vendor = "apple", target_os = "freebsd"))]
+unsafe fn errno() -> Option<*mut libc::c_int> {
+    Some(libc::__error())
+}
+
+#[cfg(any(target_os = "solaris", target_os = "illumos"))]
+unsafe fn errno() -> Option<*mut libc::c_int> {
+    Some(libc::___errno())
+}
+
+/// Anywhere else `errno` is left as the write leaves it, which only changes if the write fails
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "dragonfly",
+    target_os = "android",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_vendor = "apple",
+    target_os = "freebsd",
+    target_os = "solaris",
+    target_os = "illumos"
+)))]
+unsafe fn errno() -> Option<*mut libc::c_int> {
+    None
+}
+
+extern "C" fn on_signal(_: libc::c_int) {
+    // only async-signal-safe calls here - a full pipe already has a wakeup waiting in it
+    let fd = WRITE_FD.load(Ordering::Relaxed);
+    if fd >= 0 {
+        unsafe {
+            // the handler may run between a failed call and its caller reading errno
+            let errno = errno();
+            let saved = errno.map(|errno| *errno);
+            libc::write(fd, b"x".as_ptr() as *const libc::c_void, 1);
+            if let (Some(errno), Some(saved)) = (errno, saved) {
+                *errno = saved;
+            }
+        }
+    }
+}
+
+fn check(result: libc::c_int) -> io::Result<libc::c_int> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+/// Makes a pipe end non-blocking, and closed in child processes
+fn set_flags(fd: RawFd) -> io::Result<()> {
+    unsafe {
+        let flags = check(libc::fcntl(fd, libc::F_GETFL))?;
+        check(libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK))?;
+        check(libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC))?;
+    }
+    Ok(())
+}
+
+/// Turns SIGTERM and SIGINT into readiness on a pipe that `mio` can poll, using the self-pipe
+/// trick. Only one can be installed at a time; dropping it puts the default handlers back.
+pub struct Signals {
+    read: RawFd,
+    write: RawFd,
+}
+
+impl Signals {
+    pub fn install() -> io::Result<Self> {
+        let mut fds = [0; 2];
+        unsafe {
+            check(libc::pipe(fds.as_mut_ptr()))?;
+        }
+        let claimed = fds.iter().try_for_each(|fd| set_flags(*fd)).and_then(|()| {
+            WRITE_FD
+                .compare_exchange(-1, fds[1], Ordering::SeqCst, Ordering::SeqCst)
+                .map(|_| ())
+                .map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        "signal handlers are already installed",
+                    )
+                })
+        });
+        if let Err(e) = claimed {
+            unsafe {
+                libc::close(fds[0]);
+                libc::close(fds[1]);
+            }
+            return Err(e);
+        }
+        let signals = Signals {
+            read: fds[0],
+            write: fds[1],
+        };
+        for signal in &SIGNALS {
+            unsafe {
+                let mut action: libc::sigaction = mem::zeroed();
+                action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as usize;
+                action.sa_flags = libc::SA_RESTART;
+                libc::sigemptyset(&mut action.sa_mask);
+                check(libc::sigaction(*signal, &action, ptr::null_mut()))?;
+            }
+        }
+        Ok(signals)
+    }
+
+    /// The end of the pipe to poll for readability
+    pub fn fd(&self) -> RawFd {
+        self.read
+    }
+
+    /// Empties the pipe, returning whether a signal had arrived
+    pub fn drain(&self) -> bool {
+        let mut buffer = [0u8; 64];
+        let mut received = false;
+        loop {
+            let read = unsafe {
+                libc::read(
+                    self.read,
+                    buffer.as_mut_ptr() as *mut libc::c_void,
+                    buffer.len(),
+                )
+            };
+            if read <= 0 {
+                return received;
+            }
+            received = true;
+        }
+    }
+}
+
+impl Drop for Signals {
+    fn drop(&mut self) {
+        unsafe {
+            for signal in &SIGNALS {
+                libc::signal(*signal, libc::SIG_DFL);
+            }
+        }
+        // only clear the handler's pipe if it is still this one
+        let _ = WRITE_FD.compare_exchange(self.write, -1, Ordering::SeqCst, Ordering::SeqCst);
+        unsafe {
+            libc::close(self.read);
+            libc::close(self.write);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_signals() {
+        let signals = Signals::install().unwrap();
+        assert!(Signals::install().is_err());
+        assert!(!signals.drain());
+        unsafe {
+            libc::raise(libc::SIGTERM);
+            libc::raise(libc::SIGINT);
+        }
+        assert!(signals.drain());
+        assert!(!signals.drain());
+    }
+}
@@ -0,0 +1,85 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Chats saved to a local directory so they outlast a restart, each as a `<chatId>.json` file
+/// holding the chat with its messages, edit history and events. A chat is written out whole
+/// each time it changes.
+pub struct ChatStore {
+    dir: PathBuf,
+}
+
+impl ChatStore {
+    pub fn new(dir: PathBuf) -> Self {
+        ChatStore { dir }
+    }
+
+    /// Reads every chat saved so far. A directory that doesn't exist yet holds none.
+    pub fn load<T: DeserializeOwned>(&self) -> io::Result<Vec<T>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut chats = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            // files left half written by a crash start with a dot, and are skipped
+            let saved = path.extension().is_some_and(|ext| ext == "json")
+                && path
+                    .file_name()
+                    .is_some_and(|name| !name.to_string_lossy().starts_with('.'));
+            if !saved {
+                continue;
+            }
+            let chat = serde_json::from_slice(&fs::read(&path)?).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}: {}", path.display(), e),
+                )
+            })?;
+            chats.push(chat);
+        }
+        Ok(chats)
+    }
+
+    pub fn save<T: Serialize>(&self, chat_id: u64, chat: &T) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        // write then rename, so a crash part way through leaves the last save in place
+        let tmp = self.dir.join(format!(".{}.json.tmp", chat_id));
+        let mut file = File::create(&tmp)?;
+        file.write_all(&serde_json::to_vec(chat)?)?;
+        file.sync_all()?;
+        fs::rename(&tmp, self.dir.join(format!("{}.json", chat_id)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = std::env::temp_dir().join(format!("chat-store-test-{}", std::process::id()));
+        let store = ChatStore::new(dir.clone());
+        assert!(store.load::<Vec<u64>>().unwrap().is_empty());
+
+        store.save(1, &vec![1, 2]).unwrap();
+        store.save(2, &vec![3]).unwrap();
+        // saving again replaces the earlier copy
+        store.save(1, &vec![1, 2, 4]).unwrap();
+        fs::write(dir.join(".3.json.tmp"), "[5, ").unwrap();
+        let mut chats = store.load::<Vec<u64>>().unwrap();
+        chats.sort();
+        assert_eq!(chats, vec![vec![1, 2, 4], vec![3]]);
+
+        // a file that can't be read back is an error rather than a chat quietly lost
+        fs::write(dir.join("4.json"), "[6, ").unwrap();
+        assert!(store.load::<Vec<u64>>().is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
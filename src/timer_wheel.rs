@@ -0,0 +1,179 @@
+/// Number of slots in a default wheel - with 100ms slots, one turn covers 25.6 seconds
+const DEFAULT_SLOTS: usize = 256;
+const DEFAULT_RESOLUTION: u64 = 100;
+
+/// A value due at a deadline, in milliseconds since the epoch
+#[derive(Debug, PartialEq)]
+pub struct Timer<T> {
+    pub deadline: u64,
+    pub value: T,
+}
+
+/// A hashed timer wheel. Timers go in the slot for their deadline's tick, so adding one takes
+/// constant time and finding the due ones only looks at the slots passed since the last call
+/// to `expire`. Deadlines more than one turn of the wheel away share slots with nearer ones and
+/// are left in place until their turn comes round.
+pub struct TimerWheel<T> {
+    slots: Vec<Vec<Timer<T>>>,
+    /// Milliseconds covered by each slot
+    resolution: u64,
+    /// Tick the wheel has expired timers up to - slots for earlier ticks have been emptied of
+    /// everything that was due
+    current: u64,
+    len: usize,
+}
+
+impl<T> Default for TimerWheel<T> {
+    fn default() -> Self {
+        TimerWheel::new(DEFAULT_SLOTS, DEFAULT_RESOLUTION)
+    }
+}
+
+impl<T> TimerWheel<T> {
+    pub fn new(slots: usize, resolution: u64) -> Self {
+        assert!(slots > 0 && resolution > 0, "timer wheel can't be empty");
+        TimerWheel {
+            slots: (0..slots).map(|_| Vec::new()).collect(),
+            resolution,
+            current: 0,
+            len: 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn slot(&self, tick: u64) -> usize {
+        (tick % self.slots.len() as u64) as usize
+    }
+
+    pub fn insert(&mut self, deadline: u64, value: T) {
+        // timers already due go in the slot `expire` looks at first
+        let tick = (deadline / self.resolution).max(self.current);
+        let slot = self.slot(tick);
+        self.slots[slot].push(Timer { deadline, value });
+        self.len += 1;
+    }
+
+    /// Removes and returns every timer due by `now`, earliest first
+    pub fn expire(&mut self, now: u64) -> Vec<Timer<T>> {
+        let end = now / self.resolution;
+        let mut expired = Vec::new();
+        if self.len > 0 && end >= self.current {
+            // once a whole turn has passed every slot has to be looked at
+            let ticks = (end - self.current).min(self.slots.len() as u64 - 1);
+            for tick in self.current..=self.current + ticks {
+                let slot = self.slot(tick);
+                let timers = std::mem::take(&mut self.slots[slot]);
+                let (due, pending): (Vec<_>, Vec<_>) =
+                    timers.into_iter().partition(|t| t.deadline <= now);
+                self.slots[slot] = pending;
+                expired.extend(due);
+            }
+        }
+        self.current = self.current.max(end);
+        self.len -= expired.len();
+        expired.sort_by_key(|t| t.deadline);
+        expired
+    }
+
+    /// The earliest deadline in the wheel, which is when `expire` next has something to return
+    pub fn next_deadline(&self) -> Option<u64> {
+        if self.is_empty() {
+            return None;
+        }
+        // the first slot from the current tick holding a timer for this turn has the earliest
+        for tick in self.current..self.current + self.slots.len() as u64 {
+            let earliest = self.slots[self.slot(tick)]
+                .iter()
+                .filter(|t| t.deadline / self.resolution <= tick)
+                .map(|t| t.deadline)
+                .min();
+            if earliest.is_some() {
+                return earliest;
+            }
+        }
+        self.iter().map(|t| t.deadline).min()
+    }
+
+    /// Removes the first timer whose value matches
+    pub fn remove<F: Fn(&T) -> bool>(&mut self, matches: F) -> Option<Timer<T>> {
+        for slot in self.slots.iter_mut() {
+            if let Some(i) = slot.iter().position(|t| matches(&t.value)) {
+                self.len -= 1;
+                return Some(slot.swap_remove(i));
+            }
+        }
+        None
+    }
+
+    /// Keeps only the timers whose value `keep` returns true for, returning how many were removed
+    pub fn retain<F: Fn(&T) -> bool>(&mut self, keep: F) -> usize {
+        let before = self.len;
+        for slot in self.slots.iter_mut() {
+            slot.retain(|t| keep(&t.value));
+        }
+        self.len = self.slots.iter().map(Vec::len).sum();
+        before - self.len
+    }
+
+    /// Every pending timer, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = &Timer<T>> {
+        self.slots.iter().flatten()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_expire_in_deadline_order() {
+        let mut wheel = TimerWheel::new(8, 10);
+        wheel.expire(1_000);
+        wheel.insert(1_055, "b");
+        wheel.insert(1_050, "a");
+        // a full turn away, sharing a slot with "a"
+        wheel.insert(1_130, "c");
+        wheel.insert(900, "overdue");
+        assert_eq!(wheel.iter().count(), 4);
+        assert_eq!(wheel.next_deadline(), Some(900));
+
+        let values = |timers: Vec<Timer<&'static str>>| {
+            timers.into_iter().map(|t| t.value).collect::<Vec<_>>()
+        };
+        assert_eq!(values(wheel.expire(1_000)), vec!["overdue"]);
+        assert_eq!(wheel.next_deadline(), Some(1_050));
+        assert_eq!(values(wheel.expire(1_052)), vec!["a"]);
+        assert_eq!(wheel.next_deadline(), Some(1_055));
+        assert_eq!(values(wheel.expire(1_060)), vec!["b"]);
+        assert_eq!(wheel.next_deadline(), Some(1_130));
+        // skipping more than a turn still finds it
+        assert_eq!(values(wheel.expire(5_000)), vec!["c"]);
+        assert!(wheel.is_empty());
+        assert_eq!(wheel.next_deadline(), None);
+    }
+
+    #[test]
+    fn test_remove_and_retain() {
+        let mut wheel = TimerWheel::new(4, 10);
+        for i in 0..10 {
+            wheel.insert(i * 7, i);
+        }
+        assert_eq!(
+            wheel.remove(|v| *v == 3),
+            Some(Timer {
+                deadline: 21,
+                value: 3
+            })
+        );
+        assert_eq!(wheel.remove(|v| *v == 3), None);
+        assert_eq!(wheel.retain(|v| v % 2 == 0), 4);
+        assert_eq!(wheel.iter().count(), 5);
+        let mut left = wheel.iter().map(|t| t.value).collect::<Vec<_>>();
+        left.sort();
+        assert_eq!(left, vec![0, 2, 4, 6, 8]);
+    }
+}
@@ -0,0 +1,520 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, ToSocketAddrs};
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use super::timer_wheel::TimerWheel;
+
+/// Events a webhook can subscribe to
+pub const EVENT_TYPES: &[&str] = &["chatCreated", "messageSent"];
+/// Attempts made at a delivery before it goes on the dead-letter list
+pub const MAX_ATTEMPTS: u32 = 5;
+/// Wait before the first retry in milliseconds, doubling after each further failure
+const RETRY_DELAY: u64 = 1_000;
+/// Longest a delivery's connection may take to get a response, in milliseconds
+pub const DELIVERY_TIMEOUT: u64 = 10_000;
+/// Dead letters kept, the oldest being dropped to make room for new ones
+pub const MAX_DEAD_LETTERS: usize = 1_000;
+
+/// Body of `POST /admin/webhooks`
+#[derive(Deserialize)]
+pub struct SubscriptionRequest {
+    pub url: String,
+    pub events: Vec<String>,
+    pub secret: String,
+}
+
+/// An endpoint that is sent the events it subscribed to. The secret is never shown again once
+/// the subscription is made. The host is looked up once, when subscribing, so deliveries never
+/// wait on DNS.
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct Subscription {
+    pub id: u64,
+    pub url: String,
+    pub events: Vec<String>,
+    #[serde(skip_serializing)]
+    pub secret: String,
+    #[serde(skip_serializing)]
+    pub address: SocketAddr,
+    #[serde(rename = "createdAt")]
+    pub created_at: u64,
+}
+
+/// One event on its way to one subscription
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Delivery {
+    pub id: u64,
+    #[serde(rename = "webhookId")]
+    pub subscription_id: u64,
+    pub event: String,
+    /// The JSON body sent to the endpoint
+    pub payload: serde_json::Value,
+    /// Attempts that have failed so far
+    pub attempts: u32,
+    #[serde(rename = "lastError", skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    /// Users the event is about, so it can be dropped if one of them is erased
+    #[serde(skip_serializing)]
+    pub user_ids: Vec<u64>,
+}
+
+/// A delivery that failed `MAX_ATTEMPTS` times
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct DeadLetter {
+    #[serde(flatten)]
+    pub delivery: Delivery,
+    #[serde(rename = "failedAt")]
+    pub failed_at: u64,
+}
+
+/// Webhook subscriptions and the deliveries waiting to be made for them. Sending is left to
+/// the server loop, which takes the due deliveries and reports back how each one went.
+#[derive(Default)]
+pub struct Webhooks {
+    subscriptions: BTreeMap<u64, Subscription>,
+    next_id: u64,
+    next_delivery_id: u64,
+    /// Deliveries waiting for their first attempt or a retry
+    pending: TimerWheel<Delivery>,
+    /// Deliveries that were given up on, oldest first
+    dead_letters: Vec<DeadLetter>,
+    /// Users each delivery taken by the server loop is about, until it reports back
+    in_flight: HashMap<u64, Vec<u64>>,
+}
+
+impl Webhooks {
+    pub fn subscribe(
+        &mut self,
+        request: SubscriptionRequest,
+        now: u64,
+    ) -> Result<Subscription, String> {
+        let target = Target::parse(&request.url)?;
+        if request.events.is_empty() {
+            return Err("a webhook needs at least one event".to_owned());
+        }
+        if let Some(event) = request
+            .events
+            .iter()
+            .find(|event| !EVENT_TYPES.contains(&event.as_str()))
+        {
+            return Err(format!("unknown event {:?}", event));
+        }
+        if request.secret.is_empty() {
+            return Err("a webhook needs a secret".to_owned());
+        }
+        let address = target.resolve()?;
+        self.next_id += 1;
+        let subscription = Subscription {
+            id: self.next_id,
+            url: request.url,
+            events: request.events,
+            secret: request.secret,
+            address,
+            created_at: now,
+        };
+        self.subscriptions
+            .insert(subscription.id, subscription.clone());
+        Ok(subscription)
+    }
+
+    /// Removes a subscription along with its pending deliveries. Returns false if there was no
+    /// such subscription.
+    pub fn unsubscribe(&mut self, subscription_id: u64) -> bool {
+        self.pending
+            .retain(|delivery| delivery.subscription_id != subscription_id);
+        self.subscriptions.remove(&subscription_id).is_some()
+    }
+
+    pub fn subscriptions(&self) -> Vec<&Subscription> {
+        self.subscriptions.values().collect()
+    }
+
+    /// Queues a delivery of the event to every subscription that wants it. `user_ids` are the
+    /// users the event is about.
+    pub fn publish<T: Serialize>(&mut self, event: &str, data: &T, user_ids: &[u64], now: u64) {
+        let subscription_ids = self
+            .subscriptions
+            .values()
+            .filter(|s| s.events.iter().any(|e| e == event))
+            .map(|s| s.id)
+            .collect::<Vec<_>>();
+        if subscription_ids.is_empty() {
+            return;
+        }
+        let data = match serde_json::to_value(data) {
+            Ok(data) => data,
+            Err(e) => {
+                eprintln!("unable to serialize {} webhook: {:?}", event, e);
+                return;
+            }
+        };
+        for subscription_id in subscription_ids {
+            self.next_delivery_id += 1;
+            let payload = serde_json::json!({
+                "id": self.next_delivery_id,
+                "type": event,
+                "timestamp": now,
+                "data": data,
+            });
+            let delivery = Delivery {
+                id: self.next_delivery_id,
+                subscription_id,
+                event: event.to_owned(),
+                payload,
+                attempts: 0,
+                last_error: None,
+                user_ids: user_ids.to_vec(),
+            };
+            self.pending.insert(now, delivery);
+        }
+    }
+
+    /// When the next delivery is due to be attempted
+    pub fn next_attempt(&self) -> Option<u64> {
+        self.pending.next_deadline()
+    }
+
+    /// Takes the deliveries due by `now`, each with the subscription it goes to. Each one is
+    /// in flight until it's reported as `delivered` or `failed`.
+    pub fn take_due(&mut self, now: u64) -> Vec<(Delivery, Subscription)> {
+        let subscriptions = &self.subscriptions;
+        let in_flight = &mut self.in_flight;
+        self.pending
+            .expire(now)
+            .into_iter()
+            .filter_map(|timer| {
+                let subscription = subscriptions.get(&timer.value.subscription_id)?;
+                in_flight.insert(timer.value.id, timer.value.user_ids.clone());
+                Some((timer.value, subscription.clone()))
+            })
+            .collect()
+    }
+
+    pub fn delivered(&mut self, delivery_id: u64) {
+        self.in_flight.remove(&delivery_id);
+    }
+
+    /// Schedules a retry of a failed delivery, backing off exponentially, or moves it to the
+    /// dead-letter list once it has used up its attempts. A delivery whose user was erased while
+    /// it was in flight is dropped.
+    pub fn failed(&mut self, mut delivery: Delivery, error: String, now: u64) {
+        if self.in_flight.remove(&delivery.id).is_none() {
+            return;
+        }
+        delivery.attempts += 1;
+        delivery.last_error = Some(error);
+        if delivery.attempts >= MAX_ATTEMPTS {
+            if self.dead_letters.len() >= MAX_DEAD_LETTERS {
+                self.dead_letters.remove(0);
+            }
+            self.dead_letters.push(DeadLetter {
+                delivery,
+                failed_at: now,
+            });
+        } else if self.subscriptions.contains_key(&delivery.subscription_id) {
+            let delay = RETRY_DELAY << (delivery.attempts - 1);
+            self.pending.insert(now + delay, delivery);
+        }
+    }
+
+    pub fn dead_letters(&self) -> &[DeadLetter] {
+        &self.dead_letters
+    }
+
+    /// Drops every delivery about the user, pending, in flight or dead, so that none of their
+    /// messages outlive them being erased
+    pub fn forget_user(&mut self, user_id: u64) {
+        self.pending
+            .retain(|delivery| !delivery.user_ids.contains(&user_id));
+        self.in_flight
+            .retain(|_, user_ids| !user_ids.contains(&user_id));
+        self.dead_letters
+            .retain(|dead| !dead.delivery.user_ids.contains(&user_id));
+    }
+
+    /// Takes a delivery off the dead-letter list and tries it again with a fresh set of
+    /// attempts. Returns false if it wasn't there or its subscription has gone.
+    pub fn redeliver(&mut self, delivery_id: u64, now: u64) -> bool {
+        let position = self.dead_letters.iter().position(|dead| {
+            dead.delivery.id == delivery_id
+                && self
+                    .subscriptions
+                    .contains_key(&dead.delivery.subscription_id)
+        });
+        match position {
+            Some(i) => {
+                let mut delivery = self.dead_letters.remove(i).delivery;
+                delivery.attempts = 0;
+                self.pending.insert(now, delivery);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Hex HMAC-SHA256 of a request body, keyed with the subscription's secret
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac takes keys of any size");
+    mac.update(body);
+    format!("{:x}", mac.finalize().into_bytes())
+}
+
+/// Where a webhook URL points. Only plain `http://` URLs are supported.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Target {
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl Target {
+    pub fn parse(url: &str) -> Result<Target, String> {
+        let uri = url
+            .parse::<http::Uri>()
+            .map_err(|e| format!("invalid webhook url {:?}: {}", url, e))?;
+        if uri.scheme_part().map(|s| s.as_str()) != Some("http") {
+            return Err(format!("webhook url {:?} must start with http://", url));
+        }
+        let host = match uri.host() {
+            Some(host) => host.to_owned(),
+            None => return Err(format!("webhook url {:?} has no host", url)),
+        };
+        let path = uri
+            .path_and_query()
+            .map_or("/", |path| path.as_str())
+            .to_owned();
+        Ok(Target {
+            host,
+            port: uri.port_part().map_or(80, |port| port.as_u16()),
+            path,
+        })
+    }
+
+    /// Looks up the host's address. Names other than IP addresses are resolved with a
+    /// blocking lookup, so this is only done when subscribing, never from the server loop.
+    pub fn resolve(&self) -> Result<SocketAddr, String> {
+        (self.host.as_str(), self.port)
+            .to_socket_addrs()
+            .map_err(|e| format!("unable to resolve {}: {}", self.host, e))?
+            .next()
+            .ok_or_else(|| format!("no address found for {}", self.host))
+    }
+}
+
+/// The signed HTTP/1.1 request that makes a delivery
+pub fn request(target: &Target, subscription: &Subscription, delivery: &Delivery) -> Vec<u8> {
+    let body = delivery.payload.to_string().into_bytes();
+    let mut bytes = format!(
+        "POST {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nX-Chat-Event: {}\r\nX-Chat-Delivery: {}\r\n\
+         X-Chat-Signature: sha256={}\r\nConnection: close\r\n\r\n",
+        target.path,
+        target.host,
+        target.port,
+        body.len(),
+        delivery.event,
+        delivery.id,
+        sign(&subscription.secret, &body)
+    )
+    .into_bytes();
+    bytes.extend_from_slice(&body);
+    bytes
+}
+
+/// A connection making one delivery: it writes the request, then reads until the status line
+/// of the response has arrived
+pub struct WebhookConnection<T>
+where
+    T: Read + Write,
+{
+    pub socket: T,
+    pub delivery: Delivery,
+    request: Vec<u8>,
+    written: usize,
+    response: Vec<u8>,
+    /// When the delivery counts as failed if there's still no response
+    pub deadline: u64,
+}
+
+impl<T> WebhookConnection<T>
+where
+    T: Read + Write,
+{
+    pub fn new(socket: T, delivery: Delivery, request: Vec<u8>, now: u64) -> Self {
+        WebhookConnection {
+            socket,
+            delivery,
+            request,
+            written: 0,
+            response: Vec::new(),
+            deadline: now + DELIVERY_TIMEOUT,
+        }
+    }
+
+    /// Writes what the socket will take of the request, then reads what has come back.
+    /// Returns the response status once it has arrived, or None while still waiting.
+    pub fn advance(&mut self) -> Result<Option<u16>, String> {
+        while self.written < self.request.len() {
+            match self.socket.write(&self.request[self.written..]) {
+                Ok(0) => return Err("connection closed while sending".to_owned()),
+                Ok(written) => self.written += written,
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(None),
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+        let mut buffer = [0; 1024];
+        loop {
+            match self.socket.read(&mut buffer) {
+                Ok(0) => {
+                    return match self.status()? {
+                        Some(status) => Ok(Some(status)),
+                        None => Err("connection closed without a response".to_owned()),
+                    }
+                }
+                Ok(read) => {
+                    self.response.extend_from_slice(&buffer[..read]);
+                    if let Some(status) = self.status()? {
+                        return Ok(Some(status));
+                    }
+                }
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(None),
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+    }
+
+    fn status(&self) -> Result<Option<u16>, String> {
+        let end = match self.response.windows(2).position(|w| w == b"\r\n") {
+            Some(end) => end,
+            None => return Ok(None),
+        };
+        let line = String::from_utf8_lossy(&self.response[..end]);
+        match line.split(' ').nth(1).map(str::parse::<u16>) {
+            Some(Ok(status)) if line.starts_with("HTTP/1.") => Ok(Some(status)),
+            _ => Err(format!("invalid response {:?}", line)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn subscribe(webhooks: &mut Webhooks, events: &[&str]) -> Result<Subscription, String> {
+        let request = SubscriptionRequest {
+            url: "http://127.0.0.1:9000/hooks?source=chat".to_owned(),
+            events: events.iter().map(|e| e.to_string()).collect(),
+            secret: "s3cret".to_owned(),
+        };
+        webhooks.subscribe(request, 0)
+    }
+
+    #[test]
+    fn test_target() {
+        assert_eq!(
+            Target::parse("http://127.0.0.1:9000/hooks?source=chat"),
+            Ok(Target {
+                host: "127.0.0.1".to_owned(),
+                port: 9000,
+                path: "/hooks?source=chat".to_owned(),
+            })
+        );
+        assert_eq!(Target::parse("http://example.com").unwrap().port, 80);
+        assert!(Target::parse("https://example.com/").is_err());
+        assert!(Target::parse("/hooks").is_err());
+    }
+
+    #[test]
+    fn test_retries_and_dead_letters() {
+        let mut webhooks = Webhooks::default();
+        let subscription = subscribe(&mut webhooks, &["messageSent"]).unwrap();
+        assert!(subscribe(&mut webhooks, &["nope"]).is_err());
+        webhooks.publish("chatCreated", &"ignored", &[1, 2], 0);
+        webhooks.publish("messageSent", &"hello", &[1, 2], 0);
+        assert_eq!(webhooks.next_attempt(), Some(0));
+
+        let (mut delivery, target) = webhooks.take_due(0).pop().unwrap();
+        assert_eq!(target, subscription);
+        assert_eq!(delivery.payload["data"], "hello");
+        // each failure doubles the wait before the next attempt
+        let mut now = 0;
+        for attempt in 1..MAX_ATTEMPTS {
+            webhooks.failed(delivery, "refused".to_owned(), now);
+            let retry_at = now + (RETRY_DELAY << (attempt - 1));
+            assert_eq!(webhooks.next_attempt(), Some(retry_at));
+            assert!(webhooks.take_due(retry_at - 1).is_empty());
+            now = retry_at;
+            delivery = webhooks.take_due(now).pop().unwrap().0;
+            assert_eq!(delivery.attempts, attempt);
+        }
+        webhooks.failed(delivery, "refused".to_owned(), now);
+        assert_eq!(webhooks.next_attempt(), None);
+        let dead = &webhooks.dead_letters()[0];
+        assert_eq!(dead.delivery.attempts, MAX_ATTEMPTS);
+        assert_eq!(dead.delivery.last_error.as_deref(), Some("refused"));
+
+        let delivery_id = dead.delivery.id;
+        assert!(webhooks.redeliver(delivery_id, now));
+        assert!(!webhooks.redeliver(delivery_id, now));
+        assert!(webhooks.dead_letters().is_empty());
+        assert!(webhooks.unsubscribe(subscription.id));
+        assert!(webhooks.take_due(now).is_empty());
+    }
+
+    #[test]
+    fn test_dead_letter_limit() {
+        let mut webhooks = Webhooks::default();
+        subscribe(&mut webhooks, &["messageSent"]).unwrap();
+        for i in 0..MAX_DEAD_LETTERS + 1 {
+            webhooks.publish("messageSent", &i, &[1, 2], 0);
+        }
+        for (mut delivery, _) in webhooks.take_due(0) {
+            delivery.attempts = MAX_ATTEMPTS - 1;
+            webhooks.failed(delivery, "refused".to_owned(), 0);
+        }
+        // the oldest is dropped to make room
+        let dead_letters = webhooks.dead_letters();
+        assert_eq!(dead_letters.len(), MAX_DEAD_LETTERS);
+        assert_eq!(dead_letters[0].delivery.payload["data"], 1);
+    }
+
+    #[test]
+    fn test_forget_user() {
+        let mut webhooks = Webhooks::default();
+        subscribe(&mut webhooks, &["messageSent"]).unwrap();
+        for user_ids in &[[1, 2], [1, 3], [2, 3], [1, 4]] {
+            webhooks.publish("messageSent", &user_ids, user_ids, 0);
+        }
+        let mut due = webhooks.take_due(0);
+        let (mut dead, _) = due.remove(0);
+        dead.attempts = MAX_ATTEMPTS - 1;
+        webhooks.failed(dead, "refused".to_owned(), 0);
+        let (in_flight, _) = due.remove(0);
+        for (delivery, _) in due {
+            webhooks.failed(delivery, "refused".to_owned(), 0);
+        }
+
+        webhooks.forget_user(1);
+        assert!(webhooks.dead_letters().is_empty());
+        // a delivery that was being made when its user was erased isn't tried again
+        webhooks.failed(in_flight, "refused".to_owned(), 0);
+        let left = webhooks.take_due(RETRY_DELAY);
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0].0.user_ids, vec![2, 3]);
+    }
+
+    #[test]
+    fn test_sign() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+}